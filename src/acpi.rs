@@ -0,0 +1,368 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+
+/// Common header of every system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature : [u8; 4],
+    pub length : u32,
+    pub revision : u8,
+    pub checksum : u8,
+    pub oem_id : [u8; 6],
+    pub oem_table_id : [u8; 8],
+    pub oem_revision : u32,
+    pub creator_id : u32,
+    pub creator_revision : u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature : [u8; 8],
+    checksum : u8,
+    oem_id : [u8; 6],
+    revision : u8,
+    rsdt_address : u32,
+    // ACPI 2.0 and later
+    length : u32,
+    xsdt_address : u64,
+    extended_checksum : u8,
+    reserved : [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP, which the first checksum covers.
+const RSDP_V1_SIZE : usize = 20;
+
+fn virt(phys : PhysAddr) -> VirtAddr {
+    memory::physical_memory_offset() + phys.as_u64()
+}
+
+unsafe fn read<T : Copy>(phys : PhysAddr) -> T {
+    virt(phys).as_ptr::<T>().read_unaligned()
+}
+
+unsafe fn bytes(phys : PhysAddr, len : usize) -> &'static [u8] {
+    core::slice::from_raw_parts(virt(phys).as_ptr(), len)
+}
+
+fn u16_at(bytes : &[u8], at : usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes : &[u8], at : usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes : &[u8], at : usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn checksum_ok(bytes : &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Looks for the RSDP in the first KiB of the EBDA and in the BIOS area
+/// below 1 MiB, where the spec says it has to be.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe { read::<u16>(PhysAddr::new(0x40e)) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            let candidate = unsafe { bytes(addr, RSDP_V1_SIZE) };
+            if &candidate[..8] == b"RSD PTR " && checksum_ok(candidate) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Physical addresses of all tables the RSDT or XSDT lists.
+fn table_addresses() -> Vec<PhysAddr> {
+    let Some(rsdp_addr) = find_rsdp() else { return Vec::new() };
+    let rsdp : Rsdp = unsafe { read(rsdp_addr) };
+
+    let extended = rsdp.revision >= 2
+        && checksum_ok(unsafe { bytes(rsdp_addr, rsdp.length as usize) });
+    let (root, entry_size) = if extended {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+    let Some(header) = checked_header(root) else { return Vec::new() };
+
+    let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let first = root + size_of::<SdtHeader>() as u64;
+    (0..entries).map(|i| {
+        let entry = first + (i * entry_size) as u64;
+        let addr = if entry_size == 8 {
+            unsafe { read::<u64>(entry) }
+        } else {
+            unsafe { read::<u32>(entry) as u64 }
+        };
+        PhysAddr::new(addr)
+    }).collect()
+}
+
+fn checked_header(addr : PhysAddr) -> Option<SdtHeader> {
+    let header : SdtHeader = unsafe { read(addr) };
+    let valid = header.length as usize >= size_of::<SdtHeader>()
+        && checksum_ok(unsafe { bytes(addr, header.length as usize) });
+    valid.then_some(header)
+}
+
+/// Finds the table with `signature` and returns its address and header.
+/// Tables with a bad checksum are skipped.
+pub fn find_table(signature : &[u8; 4]) -> Option<(PhysAddr, SdtHeader)> {
+    table_addresses().into_iter().find_map(|addr| {
+        let header = checked_header(addr)?;
+        (&header.signature == signature).then_some((addr, header))
+    })
+}
+
+/// All bytes of the table with `signature`, header included.
+///
+/// Tables are read through the physical memory mapping, so everything here
+/// needs `memory::init`. Nothing is cached; each call scans them again.
+fn table(signature : &[u8; 4]) -> Option<&'static [u8]> {
+    let (addr, header) = find_table(signature)?;
+    Some(unsafe { bytes(addr, header.length as usize) })
+}
+
+/// Signatures of all tables present, in the order the RSDT/XSDT lists them.
+pub fn signatures() -> Vec<[u8; 4]> {
+    table_addresses().into_iter()
+        .filter_map(|addr| checked_header(addr).map(|header| header.signature))
+        .collect()
+}
+
+/// A register location in the ACPI "generic address structure" format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space : AddressSpace,
+    pub bit_width : u8,
+    pub bit_offset : u8,
+    pub address : u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    fn parse(bytes : &[u8]) -> GenericAddress {
+        let address_space = match bytes[0] {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        GenericAddress {
+            address_space,
+            bit_width : bytes[1],
+            bit_offset : bytes[2],
+            address : u64_at(bytes, 4),
+        }
+    }
+}
+
+/// A CPU the firmware knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id : u32,
+    pub apic_id : u32,
+    /// Usable now; otherwise it can at most be hot-plugged later.
+    pub enabled : bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id : u8,
+    pub address : PhysAddr,
+    /// First global system interrupt it handles.
+    pub gsi_base : u32,
+}
+
+/// An ISA IRQ that is wired to a different global system interrupt or
+/// with a different polarity or trigger mode than ISA normally uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq : u8,
+    pub gsi : u32,
+    pub active_low : bool,
+    pub level_triggered : bool,
+}
+
+/// What the MADT says about the interrupt controllers.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address : PhysAddr,
+    /// Whether there is a pair of 8259 PICs that has to be disabled.
+    pub has_8259 : bool,
+    pub processors : Vec<Processor>,
+    pub io_apics : Vec<IoApicInfo>,
+    pub overrides : Vec<InterruptOverride>,
+}
+
+const MADT_LOCAL_APIC : u8 = 0;
+const MADT_IO_APIC : u8 = 1;
+const MADT_INTERRUPT_OVERRIDE : u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE : u8 = 5;
+const MADT_LOCAL_X2APIC : u8 = 9;
+
+/// Parses the MADT ("APIC" table), or returns `None` if there is none.
+pub fn madt() -> Option<Madt> {
+    let body = &table(b"APIC")?[size_of::<SdtHeader>()..];
+    let mut madt = Madt {
+        local_apic_address : PhysAddr::new(u32_at(body, 0) as u64),
+        has_8259 : u32_at(body, 4) & 1 != 0,
+        processors : Vec::new(),
+        io_apics : Vec::new(),
+        overrides : Vec::new(),
+    };
+
+    let mut entries = &body[8..];
+    while entries.len() >= 2 {
+        let (kind, len) = (entries[0], entries[1] as usize);
+        if len < 2 || len > entries.len() {
+            break;
+        }
+        let entry = &entries[..len];
+        match kind {
+            MADT_LOCAL_APIC if len >= 8 => madt.processors.push(Processor {
+                processor_id : entry[2] as u32,
+                apic_id : entry[3] as u32,
+                enabled : u32_at(entry, 4) & 1 != 0,
+            }),
+            MADT_LOCAL_X2APIC if len >= 16 => madt.processors.push(Processor {
+                processor_id : u32_at(entry, 12),
+                apic_id : u32_at(entry, 4),
+                enabled : u32_at(entry, 8) & 1 != 0,
+            }),
+            MADT_IO_APIC if len >= 12 => madt.io_apics.push(IoApicInfo {
+                id : entry[2],
+                address : PhysAddr::new(u32_at(entry, 4) as u64),
+                gsi_base : u32_at(entry, 8),
+            }),
+            MADT_INTERRUPT_OVERRIDE if len >= 10 => {
+                let flags = u16_at(entry, 8);
+                madt.overrides.push(InterruptOverride {
+                    irq : entry[3],
+                    gsi : u32_at(entry, 4),
+                    active_low : flags & 0b11 == 0b11,
+                    level_triggered : (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE if len >= 12 => {
+                madt.local_apic_address = PhysAddr::new(u64_at(entry, 4));
+            }
+            _ => {}
+        }
+        entries = &entries[len..];
+    }
+    Some(madt)
+}
+
+/// The power management part of the FADT ("FACP" table). Ports that the
+/// machine does not have are 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// ISA IRQ of the system control interrupt.
+    pub sci_interrupt : u16,
+    /// Port that `acpi_enable` and `acpi_disable` are written to, to switch
+    /// ACPI mode on and off. 0 if the machine is always in ACPI mode.
+    pub smi_command_port : u32,
+    pub acpi_enable : u8,
+    pub acpi_disable : u8,
+    pub pm1a_event_block : u32,
+    pub pm1b_event_block : u32,
+    pub pm1a_control_block : u32,
+    pub pm1b_control_block : u32,
+    pub pm_timer_block : u32,
+    /// CMOS RTC register holding the century, if there is one.
+    pub century : u8,
+    /// Register and value that reset the machine, if supported.
+    pub reset : Option<(GenericAddress, u8)>,
+}
+
+const FADT_RESET_REGISTER_SUPPORTED : u32 = 1 << 10;
+
+/// Parses the FADT, or returns `None` if there is none.
+pub fn fadt() -> Option<Fadt> {
+    let fadt = table(b"FACP")?;
+    if fadt.len() < 116 {
+        return None;
+    }
+    let reset = (fadt.len() >= 129 && u32_at(fadt, 112) & FADT_RESET_REGISTER_SUPPORTED != 0)
+        .then(|| (GenericAddress::parse(&fadt[116..128]), fadt[128]));
+    Some(Fadt {
+        sci_interrupt : u16_at(fadt, 46),
+        smi_command_port : u32_at(fadt, 48),
+        acpi_enable : fadt[52],
+        acpi_disable : fadt[53],
+        pm1a_event_block : u32_at(fadt, 56),
+        pm1b_event_block : u32_at(fadt, 60),
+        pm1a_control_block : u32_at(fadt, 64),
+        pm1b_control_block : u32_at(fadt, 68),
+        pm_timer_block : u32_at(fadt, 76),
+        century : fadt[108],
+        reset,
+    })
+}
+
+/// Writes the FADT reset value to the reset register. Returns if there is
+/// no such register, it is in PCI configuration space, or the write did not
+/// reset the machine.
+pub fn reset() {
+    let Some((register, value)) = fadt().and_then(|fadt| fadt.reset) else { return };
+    match register.address_space {
+        AddressSpace::Io => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        AddressSpace::Memory => {
+            if let Some(addr) = memory::mmio::map(PhysAddr::new(register.address), 1) {
+                unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+            }
+        }
+        AddressSpace::PciConfig | AddressSpace::Other(_) => {}
+    }
+}
+
+/// What the HPET table says about the high precision event timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub address : GenericAddress,
+    pub hpet_number : u8,
+    pub comparators : u8,
+    pub counter_64bit : bool,
+    pub pci_vendor_id : u16,
+    /// Smallest periodic tick, in main counter ticks.
+    pub minimum_tick : u16,
+}
+
+/// Parses the HPET table, or returns `None` if there is none.
+pub fn hpet() -> Option<Hpet> {
+    let hpet = table(b"HPET")?;
+    if hpet.len() < 56 {
+        return None;
+    }
+    let block_id = u32_at(hpet, 36);
+    Some(Hpet {
+        address : GenericAddress::parse(&hpet[40..52]),
+        hpet_number : hpet[52],
+        comparators : ((block_id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit : block_id & (1 << 13) != 0,
+        pci_vendor_id : (block_id >> 16) as u16,
+        minimum_tick : u16_at(hpet, 53),
+    })
+}
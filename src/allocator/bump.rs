@@ -0,0 +1,65 @@
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+use super::{align_up, HeapDesign};
+
+/// Hands out memory by bumping a pointer; memory is only reclaimed once
+/// every allocation has been freed.
+pub struct BumpAllocator {
+    heap_start : usize,
+    heap_end : usize,
+    next : usize,
+    allocations : usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start : 0,
+            heap_end : 0,
+            next : 0,
+            allocations : 0,
+        }
+    }
+}
+
+impl HeapDesign for BumpAllocator {
+    unsafe fn init(&mut self, heap_start : usize, heap_size : usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    fn allocate(&mut self, layout : Layout) -> Option<NonNull<u8>> {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = alloc_start.checked_add(layout.size())?;
+        if alloc_end > self.heap_end {
+            return None;
+        }
+        self.next = alloc_end;
+        self.allocations += 1;
+        NonNull::new(alloc_start as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, _ptr : NonNull<u8>, _layout : Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    unsafe fn extend(&mut self, by : usize) {
+        self.heap_end += by;
+    }
+
+    fn top(&self) -> usize {
+        self.heap_end
+    }
+
+    fn size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+}
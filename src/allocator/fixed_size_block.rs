@@ -0,0 +1,133 @@
+use alloc::alloc::Layout;
+use core::mem;
+use core::ptr::NonNull;
+use super::{linked_list::LinkedListAllocator, HeapDesign};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Block sizes in use; each is also the block's alignment, so they must be
+/// powers of two.
+const BLOCK_SIZES : &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next : Option<&'static mut ListNode>,
+}
+
+/// Serves small allocations from per-size free lists of fixed-size blocks
+/// and everything larger from a linked-list allocator.
+///
+/// Freed blocks stay in their list, so repeated small allocations are O(1).
+pub struct FixedSizeBlockAllocator {
+    list_heads : [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback : LinkedListAllocator,
+    used : usize,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY : Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads : [EMPTY; BLOCK_SIZES.len()],
+            fallback : LinkedListAllocator::new(),
+            used : 0,
+        }
+    }
+
+    /// Index of the smallest block size that fits `layout`.
+    fn list_index(layout : &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    }
+}
+
+impl HeapDesign for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start : usize, heap_size : usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    fn allocate(&mut self, layout : Layout) -> Option<NonNull<u8>> {
+        match Self::list_index(&layout) {
+            Some(index) => {
+                let block_size = BLOCK_SIZES[index];
+                let ptr = match self.list_heads[index].take() {
+                    Some(node) => {
+                        self.list_heads[index] = node.next.take();
+                        NonNull::from(node).cast()
+                    }
+                    None => {
+                        // only works if all block sizes are a power of 2
+                        let block_layout = Layout::from_size_align(block_size, block_size)
+                            .unwrap();
+                        self.fallback.allocate(block_layout)?
+                    }
+                };
+                self.used += block_size;
+                Some(ptr)
+            }
+            None => {
+                let ptr = self.fallback.allocate(layout)?;
+                self.used += layout.size();
+                Some(ptr)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr : NonNull<u8>, layout : Layout) {
+        match Self::list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next : self.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr.as_ptr() as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+                self.used -= BLOCK_SIZES[index];
+            }
+            None => {
+                self.fallback.deallocate(ptr, layout);
+                self.used -= layout.size();
+            }
+        }
+    }
+
+    unsafe fn extend(&mut self, by : usize) {
+        self.fallback.extend(by);
+    }
+
+    fn top(&self) -> usize {
+        self.fallback.top()
+    }
+
+    fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    fn used(&self) -> usize {
+        self.used
+    }
+}
+
+#[test_case]
+fn test_blocks_are_reused() {
+    serial_print!("test_blocks_are_reused... ");
+
+    #[repr(align(4096))]
+    struct Arena([u8; 4096]);
+    static mut ARENA : Arena = Arena([0; 4096]);
+
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(core::ptr::addr_of_mut!(ARENA) as usize, 4096) };
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let first = allocator.allocate(layout).unwrap();
+    unsafe { allocator.deallocate(first, layout) };
+    let second = allocator.allocate(layout).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(allocator.used(), 32);
+
+    serial_println!("[ok]");
+}
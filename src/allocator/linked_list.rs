@@ -0,0 +1,196 @@
+use alloc::alloc::Layout;
+use core::mem;
+use core::ptr::{self, NonNull};
+use super::{align_up, HeapDesign};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Header written at the start of every free region.
+struct ListNode {
+    size : usize,
+    next : *mut ListNode,
+}
+
+const MIN_REGION_SIZE : usize = mem::size_of::<ListNode>();
+
+/// First-fit allocator over a free list kept sorted by address, so that
+/// neighbouring free regions are merged again on deallocation.
+pub struct LinkedListAllocator {
+    head : *mut ListNode,
+    heap_bottom : usize,
+    heap_top : usize,
+    used : usize,
+}
+
+// the free list only points into the heap region owned by the allocator
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head : ptr::null_mut(),
+            heap_bottom : 0,
+            heap_top : 0,
+            used : 0,
+        }
+    }
+
+    /// Adjusts a layout so the freed block can always hold a `ListNode`.
+    fn size_align(layout : Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        (layout.size().max(MIN_REGION_SIZE), layout.align())
+    }
+
+    /// Adds `[addr, addr + size)` to the free list, merging it with the
+    /// regions directly before and after it.
+    unsafe fn add_free_region(&mut self, addr : usize, size : usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= MIN_REGION_SIZE);
+
+        let mut prev : *mut ListNode = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = node;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        } else {
+            (*prev).next = node;
+        }
+    }
+
+    /// Returns the start address of an allocation of `size` bytes inside
+    /// `region`, if it fits and the leftovers can become free regions again.
+    fn alloc_from_region(region : &ListNode, size : usize, align : usize) -> Option<usize> {
+        let region_start = region as *const ListNode as usize;
+        let region_end = region_start + region.size;
+
+        let mut alloc_start = align_up(region_start, align);
+        if alloc_start != region_start && alloc_start - region_start < MIN_REGION_SIZE {
+            alloc_start = align_up(region_start + MIN_REGION_SIZE, align);
+        }
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region_end {
+            return None;
+        }
+
+        let excess = region_end - alloc_end;
+        if excess > 0 && excess < MIN_REGION_SIZE {
+            return None;
+        }
+        Some(alloc_start)
+    }
+}
+
+impl HeapDesign for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start : usize, heap_size : usize) {
+        self.heap_bottom = heap_start;
+        self.heap_top = heap_start;
+        self.extend(heap_size);
+    }
+
+    fn allocate(&mut self, layout : Layout) -> Option<NonNull<u8>> {
+        let (size, align) = Self::size_align(layout);
+
+        unsafe {
+            let mut prev : *mut ListNode = ptr::null_mut();
+            let mut current = self.head;
+            while !current.is_null() {
+                if let Some(alloc_start) = Self::alloc_from_region(&*current, size, align) {
+                    let region_start = current as usize;
+                    let region_end = region_start + (*current).size;
+                    let next = (*current).next;
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if alloc_start + size < region_end {
+                        self.add_free_region(alloc_start + size, region_end - alloc_start - size);
+                    }
+                    self.used += size;
+                    return NonNull::new(alloc_start as *mut u8);
+                }
+                prev = current;
+                current = (*current).next;
+            }
+        }
+        None
+    }
+
+    unsafe fn deallocate(&mut self, ptr : NonNull<u8>, layout : Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr.as_ptr() as usize, size);
+        self.used -= size;
+    }
+
+    unsafe fn extend(&mut self, by : usize) {
+        if by >= MIN_REGION_SIZE {
+            self.add_free_region(self.heap_top, by);
+            self.heap_top += by;
+        }
+    }
+
+    fn top(&self) -> usize {
+        self.heap_top
+    }
+
+    fn size(&self) -> usize {
+        self.heap_top - self.heap_bottom
+    }
+
+    fn used(&self) -> usize {
+        self.used
+    }
+}
+
+#[test_case]
+fn test_free_regions_merge() {
+    serial_print!("test_free_regions_merge... ");
+
+    #[repr(align(4096))]
+    struct Arena([u8; 4096]);
+    static mut ARENA : Arena = Arena([0; 4096]);
+
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(ptr::addr_of_mut!(ARENA) as usize, 4096) };
+
+    let quarter = Layout::from_size_align(1024, 8).unwrap();
+    let blocks = [
+        allocator.allocate(quarter).unwrap(),
+        allocator.allocate(quarter).unwrap(),
+        allocator.allocate(quarter).unwrap(),
+        allocator.allocate(quarter).unwrap(),
+    ];
+    assert!(allocator.allocate(quarter).is_none());
+
+    // free out of order; the regions must merge back into one
+    for &index in &[1, 3, 0, 2] {
+        unsafe { allocator.deallocate(blocks[index], quarter) };
+    }
+    assert_eq!(allocator.used(), 0);
+    let whole = Layout::from_size_align(4096, 8).unwrap();
+    assert!(allocator.allocate(whole).is_some());
+
+    serial_println!("[ok]");
+}
@@ -0,0 +1,209 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{OffsetPageTable, PageTableFlags},
+};
+use crate::memory::{self, Backing, BitmapFrameAllocator, MapError};
+
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+#[cfg(any(
+    all(feature = "bump-allocator", feature = "linked-list-allocator"),
+    all(feature = "bump-allocator", feature = "fixed-size-block-allocator"),
+    all(feature = "linked-list-allocator", feature = "fixed-size-block-allocator"),
+    not(any(feature = "bump-allocator", feature = "linked-list-allocator",
+        feature = "fixed-size-block-allocator")),
+))]
+compile_error!("enable exactly one of the `*-allocator` features");
+
+/// Heap design behind the global allocator, selected by cargo feature.
+#[cfg(feature = "bump-allocator")]
+pub type Design = bump::BumpAllocator;
+#[cfg(feature = "linked-list-allocator")]
+pub type Design = linked_list::LinkedListAllocator;
+#[cfg(feature = "fixed-size-block-allocator")]
+pub type Design = fixed_size_block::FixedSizeBlockAllocator;
+
+/// Interface `KernelHeap` needs from a heap design. Locking, interrupt
+/// safety and growth are handled by `KernelHeap`.
+pub trait HeapDesign {
+    /// Unsafe because the region must be mapped, unused and only ever
+    /// handed to one allocator.
+    unsafe fn init(&mut self, heap_start : usize, heap_size : usize);
+
+    fn allocate(&mut self, layout : Layout) -> Option<NonNull<u8>>;
+
+    unsafe fn deallocate(&mut self, ptr : NonNull<u8>, layout : Layout);
+
+    /// Adds the `by` bytes directly above `top()` to the heap.
+    unsafe fn extend(&mut self, by : usize);
+
+    /// End of the heap region.
+    fn top(&self) -> usize;
+
+    /// Bytes managed by the allocator.
+    fn size(&self) -> usize;
+
+    /// Bytes currently handed out.
+    fn used(&self) -> usize;
+}
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// Requires that `align` is a power of two.
+pub fn align_up(addr : usize, align : usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+
+pub const HEAP_START : usize = 0x_4444_4444_0000;
+/// Size mapped up front by `init_heap`.
+pub const HEAP_INITIAL_SIZE : usize = 100 * 1024;
+/// The heap never grows past this size.
+pub const HEAP_MAX_SIZE : usize = 64 * 1024 * 1024;
+/// The heap grows by at least this much at a time.
+pub const HEAP_GROW_STEP : usize = 64 * 1024;
+
+const PAGE_SIZE : usize = 4096;
+
+pub fn init_heap(mapper : &mut OffsetPageTable,
+    frame_allocator : &mut BitmapFrameAllocator)
+    -> Result<(), MapError>
+{
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    map_heap_pages(heap_start, HEAP_INITIAL_SIZE, mapper, frame_allocator)?;
+
+    super::ALLOCATOR.with_heap(|heap| unsafe {
+        heap.init(HEAP_START, HEAP_INITIAL_SIZE);
+    });
+
+    Ok(())
+}
+
+fn map_heap_pages(start : VirtAddr, size : usize,
+    mapper : &mut OffsetPageTable,
+    frame_allocator : &mut BitmapFrameAllocator)
+    -> Result<(), MapError>
+{
+    let size = align_up(size, PAGE_SIZE) as u64;
+    memory::map_range(mapper, frame_allocator, start, Backing::Allocate, size,
+                      PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently mapped for the heap.
+    pub size : usize,
+    pub used : usize,
+    pub free : usize,
+    /// Largest `used` value seen since boot.
+    pub high_water_mark : usize,
+    pub max_size : usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    super::ALLOCATOR.stats()
+}
+
+/// The kernel's global allocator: wraps the selected `Design` and maps more
+/// pages on demand, up to `HEAP_MAX_SIZE`.
+///
+/// Interrupts stay disabled while the heap lock is held. With preemptive
+/// threads a thread could otherwise be switched out while holding the lock,
+/// and anything that allocates with interrupts disabled (the scheduler,
+/// timer registration, ...) would spin on it forever.
+///
+/// Growing takes the kernel mapper and then the frame allocator with the
+/// heap locked, so neither may be held while allocating or freeing: that
+/// would deadlock against a CPU that is growing the heap, or against this
+/// one. Debug builds check it.
+pub struct KernelHeap {
+    heap : Mutex<Design>,
+    high_water_mark : AtomicUsize,
+}
+
+impl KernelHeap {
+    pub const fn empty() -> Self {
+        KernelHeap {
+            heap : Mutex::new(Design::new()),
+            high_water_mark : AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_heap<R>(&self, f : impl FnOnce(&mut Design) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.heap.lock()))
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.with_heap(|heap| HeapStats {
+            size : heap.size(),
+            used : heap.used(),
+            free : heap.size() - heap.used(),
+            high_water_mark : self.high_water_mark.load(Ordering::Relaxed),
+            max_size : HEAP_MAX_SIZE,
+        })
+    }
+
+    /// Maps enough new pages at the top of the heap to fit `layout`.
+    ///
+    /// Fails if the heap would exceed `HEAP_MAX_SIZE` or if `memory` has no
+    /// kernel mapper / frame allocator installed yet.
+    fn grow(heap : &mut Design, layout : Layout) -> bool {
+        // worst case the allocation needs `align` bytes of padding
+        let needed = layout.size() + layout.align();
+        let step = needed.max(HEAP_GROW_STEP);
+        let step = (step + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let step = step.min(HEAP_MAX_SIZE.saturating_sub(heap.size()));
+        if step < needed || heap.size() == 0 {
+            return false;
+        }
+
+        // map page by page so that whatever did get mapped before a failure
+        // still ends up in the heap instead of leaking
+        let top = VirtAddr::new(heap.top() as u64);
+        let mapped = memory::try_with_kernel_memory(|mapper, frame_allocator| {
+            let mut mapped = 0;
+            while mapped < step {
+                let page = top + mapped as u64;
+                if map_heap_pages(page, PAGE_SIZE, mapper, frame_allocator).is_err() {
+                    break;
+                }
+                mapped += PAGE_SIZE;
+            }
+            mapped
+        }).unwrap_or(0);
+
+        if mapped > 0 {
+            unsafe { heap.extend(mapped) };
+        }
+        mapped >= needed
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        debug_assert!(!memory::locked_here(), "allocating with kernel memory locked");
+        interrupts::without_interrupts(|| {
+            let mut heap = self.heap.lock();
+            let mut result = heap.allocate(layout);
+            if result.is_none() && Self::grow(&mut heap, layout) {
+                result = heap.allocate(layout);
+            }
+            self.high_water_mark.fetch_max(heap.used(), Ordering::Relaxed);
+            result.map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        debug_assert!(!memory::locked_here(), "freeing with kernel memory locked");
+        interrupts::without_interrupts(|| {
+            self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
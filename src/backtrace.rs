@@ -0,0 +1,134 @@
+use core::fmt;
+use core::ptr::addr_of;
+use x86_64::VirtAddr;
+use crate::memory;
+
+/// Deepest backtrace printed, in case the frame chain loops.
+const MAX_FRAMES : usize = 32;
+
+const SYMBOL_TABLE_SIZE : usize = 512 * 1024;
+
+/// Function names by start address, filled in after linking by
+/// `tools/embed-symbols.sh`: one `<16 hex digits> <name>` line per function,
+/// sorted by address, followed by zeros.
+///
+/// It is all zeros in a kernel that did not go through the script, and
+/// backtraces then show bare addresses.
+#[used]
+#[link_section = ".kernel_symbols"]
+static SYMBOL_TABLE : [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+fn symbol_table() -> &'static str {
+    // the compiler only ever sees the zeros, so hide the contents from it
+    let table : &[u8; SYMBOL_TABLE_SIZE] = core::hint::black_box(unsafe { &*addr_of!(SYMBOL_TABLE) });
+    let len = table.iter().position(|&byte| byte == 0).unwrap_or(table.len());
+    core::str::from_utf8(&table[..len]).unwrap_or("")
+}
+
+/// The function containing `addr` and how far into it `addr` is.
+pub fn symbolize(addr : u64) -> Option<(&'static str, u64)> {
+    lookup(symbol_table(), addr)
+}
+
+fn lookup(table : &str, addr : u64) -> Option<(&str, u64)> {
+    let mut found = None;
+    for line in table.lines() {
+        let Some((start, name)) = line.split_once(' ') else { continue };
+        let Ok(start) = u64::from_str_radix(start, 16) else { continue };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// Return addresses found by following the chain of saved frame pointers.
+///
+/// The kernel is built with frame pointers, so every frame starts with the
+/// caller's `rbp` followed by the return address. The walk stops at the
+/// first frame pointer that is not mapped, so a broken chain ends the
+/// backtrace instead of faulting again.
+pub struct Frames {
+    rbp : u64,
+    depth : usize,
+}
+
+impl Frames {
+    pub fn new(rbp : u64) -> Frames {
+        Frames { rbp, depth : 0 }
+    }
+
+    /// Starts at the caller of this function.
+    #[inline(always)]
+    pub fn here() -> Frames {
+        let rbp : u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        Frames::new(rbp)
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        if self.depth == MAX_FRAMES || rbp == 0 || rbp % 8 != 0
+            || !readable(rbp) || !readable(rbp + 8)
+        {
+            return None;
+        }
+        let (next, return_address) = unsafe {
+            (*(rbp as *const u64), *((rbp + 8) as *const u64))
+        };
+        if return_address == 0 {
+            return None;
+        }
+        self.rbp = next;
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+fn readable(addr : u64) -> bool {
+    VirtAddr::try_new(addr).map_or(false, memory::is_mapped)
+}
+
+/// `addr` as `<name+offset>` if it is in a known function.
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = symbolize(self.0) {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes one line per frame.
+pub fn write(out : &mut impl fmt::Write, frames : Frames) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    for (depth, return_address) in frames.enumerate() {
+        writeln!(out, "  {:2}: {}", depth, Symbolized(return_address))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_symbol_lookup() {
+    serial_print!("test_symbol_lookup... ");
+    let table = "0000000000201000 rustOS::init\n\
+                 0000000000201080 <T as core::fmt::Debug>::fmt\n\
+                 0000000000202000 rustOS::hlt_loop\n";
+    assert_eq!(lookup(table, 0x200fff), None);
+    assert_eq!(lookup(table, 0x201000), Some(("rustOS::init", 0)));
+    assert_eq!(lookup(table, 0x201234), Some(("<T as core::fmt::Debug>::fmt", 0x1b4)));
+    assert_eq!(lookup(table, 0x300000), Some(("rustOS::hlt_loop", 0xfe000)));
+    assert_eq!(lookup("", 0x201000), None);
+    serial_println!("[ok]");
+}
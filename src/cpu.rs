@@ -0,0 +1,124 @@
+use alloc::boxed::Box;
+use core::mem::offset_of;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+use crate::gdt::CpuTables;
+
+/// Upper bound on CPUs the kernel runs on; further ones stay halted.
+pub const MAX_CPUS : usize = 16;
+
+/// Data every CPU has a copy of, reached through the GS base.
+///
+/// While the CPU is in ring 0, GS points at its `PerCpu`. Entries from ring 3
+/// swap the user GS base out with `swapgs` and exits swap it back in.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this struct, so that `current` can load it from `gs:[0]`.
+    this : *mut PerCpu,
+    /// User stack pointer while `syscall_entry` switches stacks.
+    user_rsp_scratch : u64,
+    pub(crate) tables : CpuTables,
+    index : usize,
+    apic_id : AtomicU32,
+}
+
+/// Offsets into `PerCpu` for the syscall entry stub.
+pub(crate) const USER_RSP_SCRATCH_OFFSET : usize = offset_of!(PerCpu, user_rsp_scratch);
+pub(crate) const KERNEL_RSP_OFFSET : usize = offset_of!(PerCpu, tables) + CpuTables::RSP0_OFFSET;
+
+impl PerCpu {
+    const fn new(index : usize) -> PerCpu {
+        PerCpu {
+            this : core::ptr::null_mut(),
+            user_rsp_scratch : 0,
+            tables : CpuTables::new(),
+            index,
+            apic_id : AtomicU32::new(0),
+        }
+    }
+
+    /// 0 for the boot CPU, then counting up in the order the CPUs started.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_apic_id(&self, apic_id : u32) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+}
+
+/// The boot CPU's data has to exist before the heap does.
+static mut BOOT_CPU : PerCpu = PerCpu::new(0);
+
+static ONLINE : AtomicUsize = AtomicUsize::new(1);
+
+/// The boot CPU's `PerCpu`, to be activated by `gdt::init`.
+pub(crate) fn boot_cpu() -> &'static mut PerCpu {
+    unsafe { &mut *addr_of_mut!(BOOT_CPU) }
+}
+
+/// A `PerCpu` for the application processor with `index`. It is never freed.
+pub(crate) fn new_cpu(index : usize, apic_id : u32) -> &'static mut PerCpu {
+    assert!(index < MAX_CPUS, "CPU index {} out of range", index);
+    let cpu = Box::leak(Box::new(PerCpu::new(index)));
+    cpu.set_apic_id(apic_id);
+    cpu
+}
+
+/// Points the GS base of the running CPU at `cpu`.
+///
+/// Unsafe because `cpu` must belong to this CPU and no other.
+pub(crate) unsafe fn activate(cpu : &'static mut PerCpu) {
+    cpu.this = cpu as *mut PerCpu;
+    GsBase::write(VirtAddr::from_ptr(cpu.this));
+    // the user GS base, swapped in on the way to ring 3
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Raw pointer to the running CPU's data, for the fields it changes itself.
+pub(crate) fn current_ptr() -> *mut PerCpu {
+    let this : *mut PerCpu;
+    unsafe { core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags)) };
+    this
+}
+
+/// The running CPU's data.
+///
+/// A thread may move to another CPU whenever interrupts are enabled, so the
+/// result is only stable while they are disabled.
+pub fn current() -> &'static PerCpu {
+    unsafe { &*current_ptr() }
+}
+
+/// Index of the running CPU, see `PerCpu::index`.
+pub fn id() -> usize {
+    current().index
+}
+
+/// Like `id`, but `None` before `gdt::init` activated the boot CPU's data.
+pub fn try_id() -> Option<usize> {
+    match GsBase::read().is_null() {
+        true => None,
+        false => Some(id()),
+    }
+}
+
+pub fn is_boot_cpu() -> bool {
+    id() == 0
+}
+
+/// Number of CPUs that are up and scheduling threads.
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Called by an application processor once it takes part in scheduling.
+pub(crate) fn mark_online() {
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
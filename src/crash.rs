@@ -0,0 +1,58 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use crate::backtrace::{self, Frames};
+use crate::serial::SERIAL1;
+use crate::sync::{IrqSafeSpinlock, IrqSafeSpinlockGuard};
+use crate::vga_buffer::WRITER;
+
+/// Writes to both serial and VGA, for reports of fatal errors.
+///
+/// The crashed code may have held either lock; it is never going to release
+/// it, so a held lock is broken open instead of waited for.
+pub struct CrashWriter;
+
+impl fmt::Write for CrashWriter {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        lock_forced(&SERIAL1).write_str(s)?;
+        lock_forced(&WRITER).write_str(s)
+    }
+}
+
+/// Writes to VGA, for exception handlers that return to the code they
+/// interrupted, like the one for breakpoints.
+///
+/// That code may hold the lock on this CPU. It is paused until the handler
+/// returns, so the handler writes without the lock rather than wait.
+pub struct ExceptionWriter;
+
+impl fmt::Write for ExceptionWriter {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        match WRITER.lock_unless_held_here() {
+            Some(mut writer) => writer.write_str(s),
+            None => unsafe { WRITER.with_unchecked(|writer| writer.write_str(s)) },
+        }
+    }
+}
+
+/// Rounds another CPU gets to release a lock before it is broken open.
+const FORCE_AFTER : u64 = 1 << 24;
+
+fn lock_forced<T>(lock : &IrqSafeSpinlock<T>) -> IrqSafeSpinlockGuard<'_, T> {
+    for _ in 0..FORCE_AFTER {
+        if let Some(guard) = lock.try_lock() {
+            return guard;
+        }
+        if lock.is_held_here() {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { lock.force_unlock() };
+    lock.lock()
+}
+
+/// Writes the panic message and a backtrace of the panicking code.
+pub fn report_panic(info : &PanicInfo) {
+    let _ = writeln!(CrashWriter, "{}", info);
+    let _ = backtrace::write(&mut CrashWriter, Frames::here());
+}
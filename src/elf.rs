@@ -0,0 +1,385 @@
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+        mapper::{MapToError, TranslateResult},
+    },
+};
+use crate::memory::{self, AddressSpace};
+use crate::{thread, userspace};
+
+const ELF_MAGIC : [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64 : u8 = 2;
+const ELFDATA2LSB : u8 = 1;
+const EV_CURRENT : u8 = 1;
+const ET_EXEC : u16 = 2;
+const EM_X86_64 : u16 = 62;
+
+const ELF_HEADER_SIZE : usize = 64;
+const PROGRAM_HEADER_SIZE : usize = 56;
+
+pub const PT_LOAD : u32 = 1;
+pub const PF_X : u32 = 1 << 0;
+pub const PF_W : u32 = 1 << 1;
+pub const PF_R : u32 = 1 << 2;
+
+/// Top of the user stack; the page above it stays unmapped as a guard.
+pub const USER_STACK_TOP : u64 = userspace::USER_SPACE_END - 4096;
+pub const USER_STACK_SIZE : u64 = 16 * 4096;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The image is shorter than the headers it claims to have.
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit ELF file.
+    UnsupportedClass,
+    /// Not little endian.
+    UnsupportedEncoding,
+    UnsupportedVersion,
+    /// Only statically linked executables can be loaded, there is no
+    /// relocation support.
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaderSize,
+    /// A segment's file data lies outside the image.
+    SegmentOutOfBounds,
+    /// A segment's file size is larger than its memory size.
+    BadSegmentSize,
+    /// A segment is not inside `USER_SPACE_START..USER_SPACE_END`.
+    SegmentOutsideUserSpace,
+    /// Segments are not sorted by address or overlap each other.
+    OverlappingSegments,
+    /// The entry point is not inside an executable segment.
+    BadEntryPoint,
+    Map(MapToError<Size4KiB>),
+    /// Loaded, but there is no free slot for the thread to run it on.
+    TooManyThreads,
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(error : MapToError<Size4KiB>) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind : u32,
+    pub flags : u32,
+    pub offset : u64,
+    pub vaddr : u64,
+    pub file_size : u64,
+    pub mem_size : u64,
+    pub align : u64,
+}
+
+impl ProgramHeader {
+    /// Page flags for the segment, on top of `PRESENT | USER_ACCESSIBLE`.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A validated ELF64 executable.
+///
+/// `parse` checks everything `load` relies on, so loading only fails if
+/// memory runs out or a page is already mapped.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data : &'a [u8],
+    entry : u64,
+    ph_offset : usize,
+    ph_entry_size : usize,
+    ph_count : usize,
+}
+
+fn read_u16(data : &[u8], offset : usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data : &[u8], offset : usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data : &[u8], offset : usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data : &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedEncoding);
+        }
+        if data[6] != EV_CURRENT || read_u32(data, 20)? != EV_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if read_u16(data, 16)? != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let elf = ElfFile {
+            data,
+            entry : read_u64(data, 24)?,
+            ph_offset : read_u64(data, 32)? as usize,
+            ph_entry_size : read_u16(data, 54)? as usize,
+            ph_count : read_u16(data, 56)? as usize,
+        };
+        if elf.ph_count > 0 && elf.ph_entry_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize);
+        }
+        let table_end = elf.ph_entry_size.checked_mul(elf.ph_count)
+            .and_then(|size| size.checked_add(elf.ph_offset));
+        if table_end.map_or(true, |end| end > data.len()) {
+            return Err(ElfError::Truncated);
+        }
+
+        elf.check_segments()?;
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let elf = *self;
+        (0..self.ph_count).map(move |index| elf.program_header(index))
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers().filter(|header| header.kind == PT_LOAD)
+    }
+
+    /// Only called for indices whose header `parse` found inside the image.
+    fn program_header(&self, index : usize) -> ProgramHeader {
+        let base = self.ph_offset + index * self.ph_entry_size;
+        let data = self.data;
+        ProgramHeader {
+            kind : read_u32(data, base).unwrap(),
+            flags : read_u32(data, base + 4).unwrap(),
+            offset : read_u64(data, base + 8).unwrap(),
+            vaddr : read_u64(data, base + 16).unwrap(),
+            file_size : read_u64(data, base + 32).unwrap(),
+            mem_size : read_u64(data, base + 40).unwrap(),
+            align : read_u64(data, base + 48).unwrap(),
+        }
+    }
+
+    fn check_segments(&self) -> Result<(), ElfError> {
+        let mut previous_end = 0;
+        let mut entry_found = false;
+        for segment in self.load_segments() {
+            let file_end = segment.offset.checked_add(segment.file_size);
+            if file_end.map_or(true, |end| end > self.data.len() as u64) {
+                return Err(ElfError::SegmentOutOfBounds);
+            }
+            if segment.file_size > segment.mem_size {
+                return Err(ElfError::BadSegmentSize);
+            }
+            let start = VirtAddr::try_new(segment.vaddr)
+                .map_err(|_| ElfError::SegmentOutsideUserSpace)?;
+            if !userspace::is_user_range(start, segment.mem_size) {
+                return Err(ElfError::SegmentOutsideUserSpace);
+            }
+            if segment.vaddr < previous_end {
+                return Err(ElfError::OverlappingSegments);
+            }
+            previous_end = segment.vaddr + segment.mem_size;
+
+            if segment.flags & PF_X != 0
+                && (segment.vaddr..previous_end).contains(&self.entry)
+            {
+                entry_found = true;
+            }
+        }
+        if !entry_found {
+            return Err(ElfError::BadEntryPoint);
+        }
+        Ok(())
+    }
+}
+
+/// Where a loaded program starts running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedProgram {
+    pub entry : VirtAddr,
+    pub stack_top : VirtAddr,
+}
+
+/// Maps the `PT_LOAD` segments of `elf` and a user stack into `mapper`.
+///
+/// Segments get zeroed frames, so the part of a segment beyond its file data
+/// (`.bss`) reads as zero. A page shared by two segments gets the union of
+/// their permissions. If loading fails half way, the pages mapped so far stay
+/// mapped.
+pub fn load(elf : &ElfFile,
+            mapper : &mut OffsetPageTable,
+            frame_allocator : &mut impl FrameAllocator<Size4KiB>)
+    -> Result<LoadedProgram, ElfError>
+{
+    let mut last_page : Option<Page> = None;
+    for segment in elf.load_segments() {
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let start = VirtAddr::new(segment.vaddr);
+        let start_page : Page = Page::containing_address(start);
+        let end_page : Page = Page::containing_address(start + (segment.mem_size - 1));
+        let flags = segment.page_flags();
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            if Some(page) == last_page {
+                merge_flags(mapper, page, flags);
+            } else {
+                map_zeroed(mapper, frame_allocator, page, flags)?;
+            }
+        }
+        last_page = Some(end_page);
+
+        let file_start = segment.offset as usize;
+        let bytes = &elf.data[file_start..file_start + segment.file_size as usize];
+        copy_to_mapped(mapper, start, bytes);
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    let stack_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let stack_pages = Page::range(
+        Page::containing_address(stack_bottom),
+        Page::containing_address(VirtAddr::new(USER_STACK_TOP)),
+    );
+    for page in stack_pages {
+        map_zeroed(mapper, frame_allocator, page, stack_flags)?;
+    }
+
+    Ok(LoadedProgram {
+        entry : elf.entry(),
+        stack_top : VirtAddr::new(USER_STACK_TOP),
+    })
+}
+
+fn map_zeroed(mapper : &mut OffsetPageTable,
+              frame_allocator : &mut impl FrameAllocator<Size4KiB>,
+              page : Page,
+              flags : PageTableFlags)
+    -> Result<(), ElfError>
+{
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        let virt = mapper.phys_offset() + frame.start_address().as_u64();
+        core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    Ok(())
+}
+
+/// Widens the flags of a page mapped by the previous segment: writable if
+/// either segment is, executable if either segment is.
+fn merge_flags(mapper : &mut OffsetPageTable, page : Page, flags : PageTableFlags) {
+    let TranslateResult::Mapped { flags : old, .. } = mapper.translate(page.start_address())
+    else {
+        unreachable!("{:?} was mapped by the previous segment", page);
+    };
+    let mut merged = old | (flags & PageTableFlags::WRITABLE);
+    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+        merged.remove(PageTableFlags::NO_EXECUTE);
+    }
+    unsafe {
+        mapper.update_flags(page, merged).expect("page vanished while loading").flush();
+    }
+}
+
+/// Copies `bytes` to `start` through the physical memory mapping, since the
+/// target pages may be read-only.
+fn copy_to_mapped(mapper : &OffsetPageTable, start : VirtAddr, bytes : &[u8]) {
+    let mut copied = 0;
+    while copied < bytes.len() {
+        let addr = start + copied as u64;
+        let chunk = (4096 - u64::from(addr.page_offset()) as usize).min(bytes.len() - copied);
+        let phys = mapper.translate_addr(addr).expect("segment page not mapped");
+        let virt = mapper.phys_offset() + phys.as_u64();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[copied..].as_ptr(), virt.as_mut_ptr::<u8>(), chunk);
+        }
+        copied += chunk;
+    }
+}
+
+/// Loads `image` into a new address space and runs it in ring 3 on a new
+/// thread. The address space is freed with the thread.
+pub fn spawn(image : &[u8]) -> Result<thread::JoinHandle, ElfError> {
+    let elf = ElfFile::parse(image)?;
+    let mut address_space = AddressSpace::new()
+        .ok_or(ElfError::Map(MapToError::FrameAllocationFailed))?;
+    let program = memory::with_frame_allocator(|frame_allocator| {
+        load(&elf, &mut address_space.mapper(), frame_allocator)
+    })?;
+
+    // the address space handed back is freed right away
+    thread::try_spawn_in(address_space, move || unsafe {
+        userspace::enter_user_mode(program.entry, program.stack_top)
+    }).map_err(|_| ElfError::TooManyThreads)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+const HELLO : &[u8] = include_bytes!("../user/hello.elf");
+
+#[test_case]
+fn test_parse_hello() {
+    serial_print!("test_parse_hello... ");
+    let elf = ElfFile::parse(HELLO).expect("hello.elf does not parse");
+    assert_eq!(elf.entry().as_u64(), 0x1000_0040_0000);
+    let executable = elf.load_segments().filter(|s| s.flags & PF_X != 0).count();
+    assert_eq!(executable, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_rejects_malformed() {
+    serial_print!("test_parse_rejects_malformed... ");
+    assert!(matches!(ElfFile::parse(&HELLO[..40]), Err(ElfError::Truncated)));
+
+    let mut image = [0u8; 4096];
+    image.copy_from_slice(&HELLO[..4096]);
+    image[1] = b'F';
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::BadMagic)));
+    image[1] = b'E';
+    image[4] = 1;
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::UnsupportedClass)));
+    image[4] = ELFCLASS64;
+
+    // the program headers claim more bytes than there are
+    assert!(matches!(ElfFile::parse(&image[..100]), Err(ElfError::Truncated)));
+    // segment data now lies beyond the end of the image
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::SegmentOutOfBounds)));
+    serial_println!("[ok]");
+}
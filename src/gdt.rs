@@ -1,51 +1,175 @@
-use lazy_static::lazy_static;
-use x86_64::instructions::segmentation;
+use core::mem::offset_of;
+use core::ptr::{addr_of, addr_of_mut};
 use x86_64::registers::segmentation::Segment;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use crate::cpu;
+use crate::memory::KernelStack;
 
 pub const DOUBLE_FAULT_IST_INDEX : u16 = 0;
+pub const NMI_IST_INDEX : u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX : u16 = 2;
+pub const DEBUG_IST_INDEX : u16 = 3;
+/// `privilege_stack_table` slot used when an interrupt arrives in ring 3.
+pub const RING0_PRIVILEGE_STACK_INDEX : usize = 0;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector : SegmentSelector
-}
-
-lazy_static! {
-    static ref TSS : TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE : usize = 4096 * 5;
-            static mut STACK : [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
+const STACK_SIZE : usize = 4096 * 5;
+
+/// Exceptions that get a stack of their own, as they can arrive while the
+/// stack pointer is not a kernel stack: a double fault on an overflowed
+/// one, the others also right around `syscall` and `sysret`, where the
+/// kernel runs on the user's stack pointer.
+const IST_STACKS : [(u16, &str); 4] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault stack"),
+    (NMI_IST_INDEX, "NMI stack"),
+    (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+    (DEBUG_IST_INDEX, "debug stack"),
+];
+
+/// The order of the descriptors is fixed by `syscall`/`sysret`: kernel data
+/// must follow kernel code, and user code must follow user data.
+///
+/// Every CPU's GDT has the same layout, so the selectors are the same too.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code_selector : SegmentSelector,
+    pub kernel_data_selector : SegmentSelector,
+    pub user_data_selector : SegmentSelector,
+    pub user_code_selector : SegmentSelector,
+    pub tss_selector : SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT : (GlobalDescriptorTable, Selectors)= {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector : SegmentSelector = gdt.append(Descriptor::kernel_code_segment());
-        // gdt.add_entry(Descriptor::kernel_code_segment());
-        // let tss_selector : SegmentSelector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let tss_selector : SegmentSelector = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
-    };
+/// The GDT and TSS of one CPU, kept in its `cpu::PerCpu`.
+///
+/// The TSS is changed while the CPU uses it, since the ring-0 stack is
+/// switched with the running thread. The syscall entry stub reads the ring-0
+/// stack straight out of it.
+pub struct CpuTables {
+    tss : TaskStateSegment,
+    gdt : GlobalDescriptorTable,
+    selectors : Selectors,
+    /// Set by `init_stacks`; until then the boot CPU uses the stack below.
+    default_kernel_stack_top : u64,
+}
+
+impl CpuTables {
+    /// Where `privilege_stack_table[0]` is, which sits at offset 4 of the TSS.
+    pub(crate) const RSP0_OFFSET : usize = offset_of!(CpuTables, tss) + 4;
+
+    pub(crate) const fn new() -> CpuTables {
+        CpuTables {
+            tss : TaskStateSegment::new(),
+            gdt : GlobalDescriptorTable::new(),
+            selectors : Selectors {
+                kernel_code_selector : SegmentSelector::NULL,
+                kernel_data_selector : SegmentSelector::NULL,
+                user_data_selector : SegmentSelector::NULL,
+                user_code_selector : SegmentSelector::NULL,
+                tss_selector : SegmentSelector::NULL,
+            },
+            default_kernel_stack_top : 0,
+        }
+    }
+}
+
+fn current_tables() -> *mut CpuTables {
+    unsafe { addr_of_mut!((*cpu::current_ptr()).tables) }
+}
+
+pub fn selectors() -> &'static Selectors {
+    unsafe { &(*current_tables()).selectors }
+}
+
+fn boot_stack_top() -> VirtAddr {
+    static mut STACK : [u8; STACK_SIZE] = [0; STACK_SIZE];
+    VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE as u64
+}
+
+/// Top of the ring-0 stack used by threads that do not have their own.
+pub fn default_kernel_stack_top() -> VirtAddr {
+    match unsafe { (*current_tables()).default_kernel_stack_top } {
+        0 => boot_stack_top(),
+        top => VirtAddr::new(top),
+    }
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception
+/// arrives while running in ring 3.
+pub fn set_kernel_stack(stack_top : VirtAddr) {
+    unsafe {
+        (*current_tables()).tss.privilege_stack_table[RING0_PRIVILEGE_STACK_INDEX] = stack_top;
+    }
 }
 
+/// Makes the boot CPU's `PerCpu` current and loads its GDT and TSS.
+///
+/// Memory is not set up this early, so the TSS starts out with stacks in
+/// `.bss` that have no guard page. `init_stacks` replaces them.
 pub fn init() {
-    use x86_64::instructions::segmentation::CS;
-    use x86_64::instructions::tables::load_tss;
+    let boot_cpu = cpu::boot_cpu();
+    let tables = unsafe { &mut *addr_of_mut!(boot_cpu.tables) };
+    unsafe { cpu::activate(boot_cpu) };
 
-    GDT.0.load();
-    unsafe{
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+    static mut IST_BOOT_STACKS : [[u8; STACK_SIZE]; IST_STACKS.len()] = [[0; STACK_SIZE]; IST_STACKS.len()];
+    for (slot, (index, _)) in IST_STACKS.into_iter().enumerate() {
+        let bottom = VirtAddr::from_ptr(unsafe { addr_of!(IST_BOOT_STACKS[slot]) });
+        tables.tss.interrupt_stack_table[index as usize] = bottom + STACK_SIZE as u64;
     }
+    tables.tss.privilege_stack_table[RING0_PRIVILEGE_STACK_INDEX] = boot_stack_top();
+    unsafe { load(tables) };
 }
 
+/// Builds the GDT in `tables` and loads it together with the TSS.
+///
+/// Unsafe because `tables` must belong to the running CPU and stay in place.
+pub(crate) unsafe fn load(tables : &'static mut CpuTables) {
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS};
+    use x86_64::instructions::tables::load_tss;
 
+    let gdt = &mut tables.gdt;
+    let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    // the TSS lives as long as the tables, so the pointer stays valid
+    let tss_selector = gdt.append(Descriptor::tss_segment_unchecked(addr_of!(tables.tss)));
+    tables.selectors = Selectors {
+        kernel_code_selector,
+        kernel_data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    };
+
+    tables.gdt.load_unsafe();
+    CS::set_reg(kernel_code_selector);
+    SS::set_reg(kernel_data_selector);
+    DS::set_reg(SegmentSelector::NULL);
+    ES::set_reg(SegmentSelector::NULL);
+    load_tss(tss_selector);
+}
+
+/// Puts guarded kernel stacks into `tables` for the exceptions in
+/// `IST_STACKS` and for interrupts from ring 3. The stacks are never freed.
+///
+/// Needs `memory::install_kernel_mapper` and `memory::install_frame_allocator`.
+pub(crate) fn allocate_stacks(tables : &mut CpuTables) {
+    for (index, name) in IST_STACKS {
+        let stack = KernelStack::new(name, STACK_SIZE);
+        tables.tss.interrupt_stack_table[index as usize] = stack.top();
+        core::mem::forget(stack);
+    }
+    let privilege = KernelStack::new("ring 0 stack", STACK_SIZE);
+    tables.tss.privilege_stack_table[RING0_PRIVILEGE_STACK_INDEX] = privilege.top();
+    tables.default_kernel_stack_top = privilege.top().as_u64();
+    core::mem::forget(privilege);
+}
+
+/// Moves the boot CPU's exception and ring-0 stacks into guarded kernel
+/// stacks.
+pub fn init_stacks() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        allocate_stacks(unsafe { &mut *current_tables() });
+    });
+}
@@ -0,0 +1,282 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::{self, InterruptOverride, IoApicInfo};
+use crate::memory::mmio;
+
+/// Where the local APIC and the first IOAPIC sit unless the MADT says
+/// otherwise.
+pub const DEFAULT_LOCAL_APIC_ADDRESS : u64 = 0xfee0_0000;
+pub const DEFAULT_IO_APIC_ADDRESS : u64 = 0xfec0_0000;
+
+/// Vector for interrupts the local APIC raises without a source.
+pub const SPURIOUS_VECTOR : u8 = 0xff;
+
+const IA32_APIC_BASE : u32 = 0x1b;
+const APIC_BASE_ENABLE : u64 = 1 << 11;
+
+// local APIC registers, as offsets from its base
+const LAPIC_ID : usize = 0x20;
+const LAPIC_TASK_PRIORITY : usize = 0x80;
+const LAPIC_EOI : usize = 0xb0;
+const LAPIC_SPURIOUS : usize = 0xf0;
+const LAPIC_ICR_LOW : usize = 0x300;
+const LAPIC_ICR_HIGH : usize = 0x310;
+const LAPIC_LVT_TIMER : usize = 0x320;
+const LAPIC_LVT_LINT0 : usize = 0x350;
+const LAPIC_LVT_ERROR : usize = 0x370;
+const LAPIC_TIMER_INITIAL_COUNT : usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT : usize = 0x390;
+const LAPIC_TIMER_DIVIDE : usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE : u32 = 1 << 8;
+const LVT_MASKED : u32 = 1 << 16;
+const LVT_TIMER_PERIODIC : u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16 : u32 = 0b0011;
+
+const ICR_INIT : u32 = 0b101 << 8;
+const ICR_STARTUP : u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING : u32 = 1 << 12;
+const ICR_LEVEL_ASSERT : u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF : u32 = 0b11 << 18;
+
+// IOAPIC registers, selected through IOREGSEL
+const IOAPIC_VERSION : u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE : u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW : u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED : u64 = 1 << 15;
+const REDIRECTION_MASKED : u64 = 1 << 16;
+
+/// Virtual address of the local APIC registers, 0 until `LocalApic::init`.
+static LOCAL_APIC_BASE : AtomicU64 = AtomicU64::new(0);
+
+/// The local APIC of the CPU that runs the code. Every CPU sees its own at
+/// the same address.
+pub struct LocalApic;
+
+impl LocalApic {
+    /// Maps the registers at `address` and software-enables the local APIC
+    /// with its timer and error interrupts masked.
+    ///
+    /// LINT0 is left alone: in virtual wire mode the 8259 delivers through
+    /// it, and `time` needs the PIT until the APIC timer is calibrated.
+    pub fn init(address : PhysAddr) -> Option<LocalApic> {
+        let base = mmio::map(address, 4096)?;
+        LOCAL_APIC_BASE.store(base.as_u64(), Ordering::Relaxed);
+        Some(LocalApic::enable())
+    }
+
+    /// Enables the local APIC of the running CPU like `init` does, with the
+    /// registers already mapped. Every CPU's APIC sits at the same address.
+    pub fn enable() -> LocalApic {
+        unsafe {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_BASE_ENABLE);
+        }
+        let apic = LocalApic;
+        apic.write(LAPIC_TASK_PRIORITY, 0);
+        apic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        apic.write(LAPIC_LVT_ERROR, LVT_MASKED);
+        apic.write(LAPIC_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        apic
+    }
+
+    fn register(&self, offset : usize) -> *mut u32 {
+        let base = LOCAL_APIC_BASE.load(Ordering::Relaxed);
+        debug_assert!(base != 0, "local APIC not initialized");
+        (base as usize + offset) as *mut u32
+    }
+
+    fn read(&self, offset : usize) -> u32 {
+        unsafe { self.register(offset).read_volatile() }
+    }
+
+    fn write(&self, offset : usize, value : u32) {
+        unsafe { self.register(offset).write_volatile(value) }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.read(LAPIC_ID) >> 24
+    }
+
+    /// Stops interrupts from the 8259 arriving through LINT0.
+    pub fn mask_legacy_interrupts(&self) {
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    /// Sends an IPI and waits until the APIC has delivered it.
+    fn send_ipi(&self, apic_id : u32, command : u32) {
+        self.write(LAPIC_ICR_HIGH, apic_id << 24);
+        self.write(LAPIC_ICR_LOW, command);
+        while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the CPU with `apic_id` into its wait-for-SIPI state.
+    pub fn send_init(&self, apic_id : u32) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Starts the CPU with `apic_id` in real mode at `page * 4096`.
+    pub fn send_startup(&self, apic_id : u32, page : u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | page as u32);
+    }
+
+    /// Raises `vector` on every CPU but this one.
+    pub fn send_to_others(&self, vector : u8) {
+        self.send_ipi(0, ICR_ALL_EXCLUDING_SELF | vector as u32);
+    }
+
+    /// Number of timer counts in one tick of `time`, measured against the
+    /// PIT. `time` has to be ticking, with interrupts enabled.
+    pub fn calibrate_timer(&self, ticks : u64) -> u32 {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+        // start right at a tick edge
+        let start = crate::time::ticks();
+        while crate::time::ticks() == start {
+            x86_64::instructions::hlt();
+        }
+        self.write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+        let start = crate::time::ticks();
+        while crate::time::ticks() < start + ticks {
+            x86_64::instructions::hlt();
+        }
+        let elapsed = u32::MAX - self.read(LAPIC_TIMER_CURRENT_COUNT);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+        elapsed / ticks as u32
+    }
+
+    /// Fires `vector` every `count` timer counts.
+    pub fn start_periodic_timer(&self, vector : u8, count : u32) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+pub struct IoApic {
+    pub id : u8,
+    base : VirtAddr,
+    gsi_base : u32,
+    redirection_entries : u32,
+}
+
+impl IoApic {
+    /// Maps the IOAPIC's registers and masks all of its inputs.
+    pub fn init(info : IoApicInfo) -> Option<IoApic> {
+        let base = mmio::map(info.address, 0x20)?;
+        let mut io_apic = IoApic { id : info.id, base, gsi_base : info.gsi_base, redirection_entries : 0 };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for input in 0..io_apic.redirection_entries {
+            io_apic.write_entry(input, REDIRECTION_MASKED);
+        }
+        Some(io_apic)
+    }
+
+    fn read(&self, register : u32) -> u32 {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + 0x10u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, register : u32, value : u32) {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + 0x10u64).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn write_entry(&self, input : u32, entry : u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * input;
+        // masked while the halves disagree
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    pub fn handles(&self, gsi : u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.redirection_entries
+    }
+}
+
+struct IoApics {
+    io_apics : Vec<IoApic>,
+    overrides : Vec<InterruptOverride>,
+}
+
+static IO_APICS : Mutex<Option<IoApics>> = Mutex::new(None);
+
+/// Sets up the local APIC of the boot CPU and all IOAPICs, from the MADT if
+/// there is one and at the default addresses otherwise.
+pub fn init() -> Option<LocalApic> {
+    let madt = acpi::madt();
+    let (local_apic_address, io_apic_infos, overrides) = match madt {
+        Some(madt) => (madt.local_apic_address, madt.io_apics, madt.overrides),
+        None => {
+            let io_apic = IoApicInfo { id : 0, address : PhysAddr::new(DEFAULT_IO_APIC_ADDRESS), gsi_base : 0 };
+            (PhysAddr::new(DEFAULT_LOCAL_APIC_ADDRESS), alloc::vec![io_apic], Vec::new())
+        }
+    };
+
+    let local_apic = LocalApic::init(local_apic_address)?;
+    let io_apics = io_apic_infos.into_iter().filter_map(IoApic::init).collect::<Vec<_>>();
+    if io_apics.is_empty() {
+        return None;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *IO_APICS.lock() = Some(IoApics { io_apics, overrides });
+    });
+    Some(local_apic)
+}
+
+/// Delivers ISA `irq` as `vector` to the CPU with local APIC `apic_id`,
+/// honouring the MADT's interrupt source overrides. Returns `false` if no
+/// IOAPIC handles it.
+pub fn route_isa_irq(irq : u8, vector : u8, apic_id : u32) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let Some(io_apics) = io_apics.as_ref() else { return false };
+
+        // ISA interrupts are active high and edge triggered unless overridden
+        let (gsi, mut entry) = match io_apics.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => {
+                let mut entry = 0;
+                if o.active_low {
+                    entry |= REDIRECTION_ACTIVE_LOW;
+                }
+                if o.level_triggered {
+                    entry |= REDIRECTION_LEVEL_TRIGGERED;
+                }
+                (o.gsi, entry)
+            }
+            None => (irq as u32, 0),
+        };
+        entry |= vector as u64 | (apic_id as u64) << 56;
+
+        match io_apics.io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+            Some(io_apic) => {
+                io_apic.write_entry(gsi - io_apic.gsi_base, entry);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// The CPU supports an APIC, according to CPUID.
+pub fn is_supported() -> bool {
+    let features = core::arch::x86_64::__cpuid(1);
+    features.edx & (1 << 9) != 0
+}
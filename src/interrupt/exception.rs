@@ -0,0 +1,509 @@
+use core::arch::naked_asm;
+use core::fmt::{self, Write};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::backtrace::{self, Frames, Symbolized};
+use crate::crash::{CrashWriter, ExceptionWriter};
+use crate::{gdt, thread};
+
+const BREAKPOINT : u64 = 3;
+const DOUBLE_FAULT : u64 = 8;
+const INVALID_TSS : u64 = 10;
+const SEGMENT_NOT_PRESENT : u64 = 11;
+const STACK_SEGMENT_FAULT : u64 = 12;
+const GENERAL_PROTECTION_FAULT : u64 = 13;
+const PAGE_FAULT : u64 = 14;
+
+const IA32_GS_BASE : u32 = 0xc000_0101;
+
+/// Registers as saved by the exception entry stubs, lowest address first.
+/// Everything from `rip` on is pushed by the CPU.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15 : u64,
+    pub r14 : u64,
+    pub r13 : u64,
+    pub r12 : u64,
+    pub r11 : u64,
+    pub r10 : u64,
+    pub r9 : u64,
+    pub r8 : u64,
+    pub rbp : u64,
+    pub rdi : u64,
+    pub rsi : u64,
+    pub rdx : u64,
+    pub rcx : u64,
+    pub rbx : u64,
+    pub rax : u64,
+    pub vector : u64,
+    /// Zero for exceptions without one.
+    pub error_code : u64,
+    pub rip : u64,
+    pub cs : u64,
+    pub rflags : u64,
+    pub rsp : u64,
+    pub ss : u64,
+}
+
+impl ExceptionFrame {
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rip {:#018x} rsp {:#018x} rflags {:#x}", self.rip, self.rsp, self.rflags)?;
+        writeln!(f, "cs  {:#06x} ss {:#06x}", self.cs, self.ss)?;
+        let registers = [
+            ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx), ("rdx", self.rdx),
+            ("rsi", self.rsi), ("rdi", self.rdi), ("rbp", self.rbp), ("r8 ", self.r8),
+            ("r9 ", self.r9), ("r10", self.r10), ("r11", self.r11), ("r12", self.r12),
+            ("r13", self.r13), ("r14", self.r14), ("r15", self.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{} {:#018x}", name, value)?;
+            f.write_str(if i % 3 == 2 { "\n" } else { " " })?;
+        }
+        Ok(())
+    }
+}
+
+/// Name and mnemonic of an exception vector.
+pub fn describe(vector : u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("DIVIDE ERROR", "#DE"),
+        1 => ("DEBUG", "#DB"),
+        2 => ("NON-MASKABLE INTERRUPT", "NMI"),
+        3 => ("BREAKPOINT", "#BP"),
+        4 => ("OVERFLOW", "#OF"),
+        5 => ("BOUND RANGE EXCEEDED", "#BR"),
+        6 => ("INVALID OPCODE", "#UD"),
+        7 => ("DEVICE NOT AVAILABLE", "#NM"),
+        8 => ("DOUBLE FAULT", "#DF"),
+        9 => ("COPROCESSOR SEGMENT OVERRUN", "-"),
+        10 => ("INVALID TSS", "#TS"),
+        11 => ("SEGMENT NOT PRESENT", "#NP"),
+        12 => ("STACK-SEGMENT FAULT", "#SS"),
+        13 => ("GENERAL PROTECTION FAULT", "#GP"),
+        14 => ("PAGE FAULT", "#PF"),
+        16 => ("X87 FLOATING-POINT EXCEPTION", "#MF"),
+        17 => ("ALIGNMENT CHECK", "#AC"),
+        18 => ("MACHINE CHECK", "#MC"),
+        19 => ("SIMD FLOATING-POINT EXCEPTION", "#XM"),
+        20 => ("VIRTUALIZATION EXCEPTION", "#VE"),
+        21 => ("CONTROL PROTECTION EXCEPTION", "#CP"),
+        28 => ("HYPERVISOR INJECTION EXCEPTION", "#HV"),
+        29 => ("VMM COMMUNICATION EXCEPTION", "#VC"),
+        30 => ("SECURITY EXCEPTION", "#SX"),
+        _ => ("RESERVED EXCEPTION", "#??"),
+    }
+}
+
+fn has_error_code(vector : u64) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Human-readable form of an exception's error code.
+pub struct ErrorCode {
+    pub vector : u64,
+    pub code : u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self.vector {
+            PAGE_FAULT => write!(f, "{}", super::PageFaultReason(
+                PageFaultErrorCode::from_bits_truncate(self.code))),
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                if self.code == 0 {
+                    return write!(f, "not caused by a segment selector");
+                }
+                let table = match (self.code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{} entry {}", table, self.code >> 3)?;
+                if self.code & 1 != 0 {
+                    write!(f, ", during an external event")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{:#x}", self.code),
+        }
+    }
+}
+
+/// Entry stub for one vector. Makes the stack look the same for every
+/// exception, a zero error code where the CPU pushes none and the vector
+/// number, then continues in `exception_common`.
+macro_rules! exception_entry {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            )
+        }
+    };
+    ($name:ident, $vector:expr, paranoid) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym paranoid_common,
+            )
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            )
+        }
+    };
+}
+
+exception_entry!(divide_error_entry, 0);
+exception_entry!(debug_entry, 1, paranoid);
+exception_entry!(non_maskable_interrupt_entry, 2, paranoid);
+exception_entry!(breakpoint_entry, 3);
+exception_entry!(overflow_entry, 4);
+exception_entry!(bound_range_exceeded_entry, 5);
+exception_entry!(invalid_opcode_entry, 6);
+exception_entry!(device_not_available_entry, 7);
+exception_entry!(double_fault_entry, 8, error_code);
+exception_entry!(invalid_tss_entry, 10, error_code);
+exception_entry!(segment_not_present_entry, 11, error_code);
+exception_entry!(stack_segment_fault_entry, 12, error_code);
+exception_entry!(general_protection_fault_entry, 13, error_code);
+exception_entry!(page_fault_entry, 14, error_code);
+exception_entry!(x87_floating_point_entry, 16);
+exception_entry!(alignment_check_entry, 17, error_code);
+exception_entry!(machine_check_entry, 18, paranoid);
+exception_entry!(simd_floating_point_entry, 19);
+exception_entry!(virtualization_entry, 20);
+exception_entry!(cp_protection_entry, 21, error_code);
+exception_entry!(hv_injection_entry, 28);
+exception_entry!(vmm_communication_entry, 29, error_code);
+exception_entry!(security_entry, 30, error_code);
+
+// Hardware interrupts and IPIs take the same path, so that they switch GS
+// as well when they arrive in ring 3.
+exception_entry!(timer_entry, super::InterruptIndx::Timer as u8);
+exception_entry!(keyboard_entry, super::InterruptIndx::Keyboard as u8);
+exception_entry!(serial1_entry, super::InterruptIndx::Serial1 as u8);
+exception_entry!(pic1_spurious_entry, super::PIC_1_OFFSET + 7);
+exception_entry!(pic2_spurious_entry, super::PIC_2_OFFSET + 7);
+exception_entry!(tlb_shootdown_entry, super::TLB_SHOOTDOWN_VECTOR);
+exception_entry!(apic_spurious_entry, super::apic::SPURIOUS_VECTOR);
+
+const INTERRUPT_ENTRIES : [(u8, extern "C" fn()); 7] = [
+    (super::InterruptIndx::Timer as u8, timer_entry),
+    (super::InterruptIndx::Keyboard as u8, keyboard_entry),
+    (super::InterruptIndx::Serial1 as u8, serial1_entry),
+    (super::PIC_1_OFFSET + 7, pic1_spurious_entry),
+    (super::PIC_2_OFFSET + 7, pic2_spurious_entry),
+    (super::TLB_SHOOTDOWN_VECTOR, tlb_shootdown_entry),
+    (super::apic::SPURIOUS_VECTOR, apic_spurious_entry),
+];
+
+/// Saves the general purpose registers to complete an `ExceptionFrame`,
+/// calls `dispatch` and returns to the interrupted code if it comes back.
+///
+/// Coming from ring 3 it swaps in the kernel GS base, and swaps it out
+/// again on the way back. 22 words are on the stack by the call, so it
+/// stays 16 byte aligned.
+#[unsafe(naked)]
+extern "C" fn exception_common() {
+    naked_asm!(
+        // the CPU pushed cs above rip, the error code and the vector
+        "test byte ptr [rsp + 24], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // vector and error code
+        "add rsp, 16",
+        "test byte ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        dispatch = sym dispatch,
+    )
+}
+
+/// Like `exception_common`, for the exceptions that can arrive in ring 0
+/// with the user GS base still loaded, around `swapgs` in `syscall_entry`.
+/// They run on their own IST stack; see `gdt::IST_STACKS`.
+///
+/// CS cannot tell those apart, so it swaps GS if the GS base is 0: user
+/// programs cannot change their GS base, which stays 0, and the kernel's
+/// is its `PerCpu`. Until that is set up both are 0 and swapping does no
+/// harm. r12 remembers the swap across the call.
+#[unsafe(naked)]
+extern "C" fn paranoid_common() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov ecx, {gs_base}",
+        "rdmsr",
+        "xor r12d, r12d",
+        "or eax, edx",
+        "jnz 2f",
+        "swapgs",
+        "mov r12d, 1",
+        "2:",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "test r12d, r12d",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // vector and error code
+        "add rsp, 16",
+        "iretq",
+        gs_base = const IA32_GS_BASE,
+        dispatch = sym dispatch,
+    )
+}
+
+/// Points every exception vector of `idt`, and the interrupt vectors the
+/// kernel handles, at their entry stubs.
+pub(super) fn install(idt : &mut InterruptDescriptorTable) {
+    fn addr(entry : extern "C" fn()) -> VirtAddr {
+        VirtAddr::new(entry as usize as u64)
+    }
+
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error_entry));
+        idt.debug.set_handler_addr(addr(debug_entry))
+            .set_stack_index(gdt::DEBUG_IST_INDEX);
+        idt.non_maskable_interrupt.set_handler_addr(addr(non_maskable_interrupt_entry))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(addr(breakpoint_entry));
+        idt.overflow.set_handler_addr(addr(overflow_entry));
+        idt.bound_range_exceeded.set_handler_addr(addr(bound_range_exceeded_entry));
+        idt.invalid_opcode.set_handler_addr(addr(invalid_opcode_entry));
+        idt.device_not_available.set_handler_addr(addr(device_not_available_entry));
+        idt.double_fault.set_handler_addr(addr(double_fault_entry))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss_entry));
+        idt.segment_not_present.set_handler_addr(addr(segment_not_present_entry));
+        idt.stack_segment_fault.set_handler_addr(addr(stack_segment_fault_entry));
+        idt.general_protection_fault.set_handler_addr(addr(general_protection_fault_entry));
+        idt.page_fault.set_handler_addr(addr(page_fault_entry));
+        idt.x87_floating_point.set_handler_addr(addr(x87_floating_point_entry));
+        idt.alignment_check.set_handler_addr(addr(alignment_check_entry));
+        idt.machine_check.set_handler_addr(addr(machine_check_entry))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(addr(simd_floating_point_entry));
+        idt.virtualization.set_handler_addr(addr(virtualization_entry));
+        idt.cp_protection_exception.set_handler_addr(addr(cp_protection_entry));
+        idt.hv_injection_exception.set_handler_addr(addr(hv_injection_entry));
+        idt.vmm_communication_exception.set_handler_addr(addr(vmm_communication_entry));
+        idt.security_exception.set_handler_addr(addr(security_entry));
+        for (vector, entry) in INTERRUPT_ENTRIES {
+            idt[vector].set_handler_addr(addr(entry));
+        }
+    }
+}
+
+/// Interrupts go to their handlers. Of the exceptions, breakpoints continue
+/// and page faults may be resolved. Anything else ends the faulting thread
+/// if it came from ring 3 and the kernel otherwise.
+extern "C" fn dispatch(frame : &mut ExceptionFrame) {
+    match frame.vector {
+        vector if vector >= super::PIC_1_OFFSET as u64 => super::handle_interrupt(vector as u8),
+        BREAKPOINT => {
+            let _ = writeln!(ExceptionWriter, "EXCEPTION: BREAKPOINT\n{}", frame);
+        }
+        PAGE_FAULT => page_fault(frame),
+        DOUBLE_FAULT => double_fault(frame),
+        _ if frame.from_user() => kill_user_thread(frame, format_args!("")),
+        _ => fatal(frame, None),
+    }
+}
+
+/// Faults in a lazy region of the current address space are resolved and
+/// the access is retried.
+///
+/// Usually a kernel stack overflow ends up in `double_fault`, as the CPU
+/// cannot push the page fault frame; this catches accesses that skip over
+/// the stack pointer into the guard page.
+fn page_fault(frame : &mut ExceptionFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let raw_addr = Cr2::read_raw();
+    let addr = VirtAddr::try_new(raw_addr).ok();
+    if let Some(addr) = addr {
+        let resolved = thread::with_current_address_space(|address_space| {
+            address_space.handle_fault(addr, error_code)
+        });
+        if resolved == Some(true) {
+            return;
+        }
+    }
+
+    let reason = super::PageFaultReason(error_code);
+    if frame.from_user() {
+        kill_user_thread(frame, format_args!(": {} at {:#x}", reason, raw_addr));
+    }
+    match addr.and_then(crate::memory::overflowed_stack) {
+        Some(stack) => fatal(frame, Some(format_args!("stack overflow in {}", stack))),
+        None => fatal(frame, Some(format_args!("{} at {:#x}", reason, raw_addr))),
+    }
+}
+
+/// Runs on its own IST stack, so it also works after a kernel stack
+/// overflowed and the page fault could not be delivered.
+fn double_fault(frame : &mut ExceptionFrame) -> ! {
+    let overflowed = Cr2::read().ok().and_then(crate::memory::overflowed_stack)
+        .or_else(|| crate::memory::overflowed_stack(VirtAddr::new_truncate(frame.rsp)));
+    match overflowed {
+        Some(stack) => fatal(frame, Some(format_args!("stack overflow in {}", stack))),
+        None => fatal(frame, None),
+    }
+}
+
+fn kill_user_thread(frame : &ExceptionFrame, detail : fmt::Arguments) -> ! {
+    let (name, _) = describe(frame.vector);
+    let _ = writeln!(ExceptionWriter, "{}: killed thread {:?}{}, rip {:#x}",
+                     name, thread::current_id(), detail, frame.rip);
+    thread::kill_current();
+}
+
+/// Writes the crash report to VGA and serial and panics.
+fn fatal(frame : &ExceptionFrame, detail : Option<fmt::Arguments>) -> ! {
+    let (name, mnemonic) = describe(frame.vector);
+    let mut out = CrashWriter;
+    let _ = writeln!(out, "EXCEPTION: {} ({}, vector {})", name, mnemonic, frame.vector);
+    if let Some(detail) = detail {
+        let _ = writeln!(out, "{}", detail);
+    }
+    if has_error_code(frame.vector) && frame.vector != DOUBLE_FAULT {
+        let error_code = ErrorCode { vector : frame.vector, code : frame.error_code };
+        let _ = writeln!(out, "error code {:#x}: {}", frame.error_code, error_code);
+    }
+    let _ = writeln!(out, "at {}", Symbolized(frame.rip));
+    let _ = write!(out, "{}", frame);
+    let _ = writeln!(out, "cr2 {:#x} cr3 {:#x}", Cr2::read_raw(), Cr3::read().0.start_address());
+    if !frame.from_user() {
+        let _ = backtrace::write(&mut out, Frames::new(frame.rbp));
+    }
+
+    match detail {
+        Some(detail) => panic!("EXCEPTION: {}: {}", name, detail),
+        None => panic!("EXCEPTION: {}", name),
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_error_code_display() {
+    serial_print!("test_error_code_display... ");
+
+    // lib tests run without a heap
+    struct Buffer([u8; 64], usize);
+    impl Write for Buffer {
+        fn write_str(&mut self, s : &str) -> fmt::Result {
+            self.0[self.1..self.1 + s.len()].copy_from_slice(s.as_bytes());
+            self.1 += s.len();
+            Ok(())
+        }
+    }
+    fn display(error_code : ErrorCode) -> Buffer {
+        let mut buffer = Buffer([0; 64], 0);
+        write!(buffer, "{}", error_code).unwrap();
+        buffer
+    }
+    fn text(buffer : &Buffer) -> &str {
+        core::str::from_utf8(&buffer.0[..buffer.1]).unwrap()
+    }
+
+    let selector = display(ErrorCode { vector : GENERAL_PROTECTION_FAULT, code : 0x1d });
+    assert_eq!(text(&selector), "LDT entry 3, during an external event");
+    let none = display(ErrorCode { vector : GENERAL_PROTECTION_FAULT, code : 0 });
+    assert_eq!(text(&none), "not caused by a segment selector");
+    let page_fault = display(ErrorCode { vector : PAGE_FAULT, code : 0b110 });
+    assert_eq!(text(&page_fault), "user write to a non-present page");
+    assert_eq!(describe(0), ("DIVIDE ERROR", "#DE"));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,247 @@
+use core::fmt;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use lazy_static::lazy_static;
+use spin;
+use pic8259::ChainedPics;
+
+pub mod apic;
+pub mod exception;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use apic::LocalApic;
+
+pub const PIC_1_OFFSET : u8 = 32;
+pub const PIC_2_OFFSET : u8 = PIC_1_OFFSET + 8;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum InterruptIndx {
+    Timer = PIC_1_OFFSET,
+    Keyboard = PIC_1_OFFSET + 1,
+    /// COM1, ISA IRQ 4.
+    Serial1 = PIC_1_OFFSET + 4,
+}
+
+impl InterruptIndx {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+/// IPI that makes the other CPUs flush their TLB.
+pub const TLB_SHOOTDOWN_VECTOR : u8 = 0xf0;
+
+pub static PICS : spin::Mutex<ChainedPics> = 
+    spin::Mutex::new(unsafe {ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Command port of the master 8259, and the command that ends an interrupt.
+const PIC_1_COMMAND : u16 = 0x20;
+const PIC_EOI : u8 = 0x20;
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exception::install(&mut idt);
+        idt
+    };
+}
+
+/// Loads the IDT. All CPUs share it, but each has to load it.
+pub fn init() {
+    IDT.load();
+}
+
+/// Initializes the 8259 PICs and lets through the ISA IRQs that have a
+/// handler: the timer, the keyboard and COM1.
+pub fn init_pics() {
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        // IRQ 0, 1 and 4
+        const HANDLED : u8 = 1 << 0 | 1 << 1 | 1 << 4;
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(primary & !HANDLED, secondary);
+    }
+}
+
+/// Set once interrupts come from the APIC instead of the 8259.
+static APIC_ACTIVE : AtomicBool = AtomicBool::new(false);
+
+/// PIT ticks the APIC timer is measured over.
+const APIC_CALIBRATION_TICKS : u64 = 10;
+
+/// APIC timer counts per tick, as calibrated on the boot CPU.
+static APIC_TIMER_COUNT : AtomicU32 = AtomicU32::new(0);
+
+/// Moves interrupt delivery from the 8259 PIC to the local APIC and the
+/// IOAPICs if the machine has them: the keyboard and COM1 go through the
+/// IOAPIC and the APIC timer takes over from the PIT at the same tick rate.
+///
+/// Needs the kernel memory installed, to map the registers, and `time`
+/// ticking with interrupts enabled, to calibrate the timer. Returns whether
+/// the APIC is in use; otherwise the PIC stays in charge.
+pub fn init_apic() -> bool {
+    if !apic::is_supported() || crate::time::frequency() == 0 {
+        return false;
+    }
+    let Some(local_apic) = apic::init() else { return false };
+    let count = local_apic.calibrate_timer(APIC_CALIBRATION_TICKS);
+    if count == 0 {
+        return false;
+    }
+
+    // without a MADT, this is taken to be a PC with the usual pair of 8259s
+    let has_8259 = crate::acpi::madt().is_none_or(|madt| madt.has_8259);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if has_8259 {
+            unsafe { PICS.lock().disable() };
+        }
+        local_apic.mask_legacy_interrupts();
+        apic::route_isa_irq(1, InterruptIndx::Keyboard.as_u8(), local_apic.id());
+        apic::route_isa_irq(4, InterruptIndx::Serial1.as_u8(), local_apic.id());
+        local_apic.start_periodic_timer(InterruptIndx::Timer.as_u8(), count);
+        APIC_TIMER_COUNT.store(count, Ordering::Relaxed);
+        APIC_ACTIVE.store(true, Ordering::Relaxed);
+    });
+    crate::cpu::current().set_apic_id(local_apic.id());
+    true
+}
+
+/// Enables the local APIC of an application processor and starts its timer
+/// at the rate `init_apic` calibrated. Interrupts stay disabled.
+pub(crate) fn init_ap() {
+    let local_apic = LocalApic::enable();
+    local_apic.mask_legacy_interrupts();
+    local_apic.start_periodic_timer(InterruptIndx::Timer.as_u8(),
+                                    APIC_TIMER_COUNT.load(Ordering::Relaxed));
+}
+
+pub fn apic_active() -> bool {
+    APIC_ACTIVE.load(Ordering::Relaxed)
+}
+
+fn end_of_interrupt(index : InterruptIndx) {
+    if apic_active() {
+        LocalApic.end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Human-readable form of a page fault error code, e.g. "user write to a
+/// non-present page".
+pub struct PageFaultReason(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultReason {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a page it may not access"
+        } else {
+            "a non-present page"
+        };
+        write!(f, "{} {} {}", mode, access, page)?;
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", protection key violation")?;
+        }
+        if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            write!(f, ", shadow stack access")?;
+        }
+        if code.contains(PageFaultErrorCode::SGX) {
+            write!(f, ", SGX violation")?;
+        }
+        Ok(())
+    }
+}
+
+/// Called from the entry stubs for every vector above the exceptions.
+fn handle_interrupt(vector : u8) {
+    const TIMER : u8 = InterruptIndx::Timer as u8;
+    const KEYBOARD : u8 = InterruptIndx::Keyboard as u8;
+    const SERIAL1 : u8 = InterruptIndx::Serial1 as u8;
+    const SPURIOUS_IRQ15 : u8 = PIC_2_OFFSET + 7;
+
+    match vector {
+        TIMER => timer_interrupt(),
+        KEYBOARD => keyboard_interrupt(),
+        SERIAL1 => serial1_interrupt(),
+        TLB_SHOOTDOWN_VECTOR => {
+            crate::memory::mapping::acknowledge_tlb_shootdown();
+            LocalApic.end_of_interrupt();
+        }
+        SPURIOUS_IRQ15 if !apic_active() => spurious_irq15(),
+        // other spurious interrupts, of the master 8259 (IRQ 7) and the
+        // local APIC, must not be acknowledged
+        _ => {}
+    }
+}
+
+fn timer_interrupt() {
+    // every CPU has a timer, but only one of them keeps time
+    if crate::cpu::is_boot_cpu() {
+        crate::time::tick();
+    }
+
+    end_of_interrupt(InterruptIndx::Timer);
+
+    // may switch to another thread, so it has to come after the EOI
+    crate::thread::on_timer_tick();
+}
+
+fn keyboard_interrupt() {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(InterruptIndx::Keyboard);
+}
+
+/// The slave 8259 does not know about a spurious IRQ 15, but the master saw
+/// its cascade line raised and still waits for an EOI.
+fn spurious_irq15() {
+    use x86_64::instructions::port::Port;
+
+    unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+}
+
+fn serial1_interrupt() {
+    crate::serial::receive_pending();
+    end_of_interrupt(InterruptIndx::Serial1);
+}
+
+/// TLB shootdown hook for `memory::set_tlb_shootdown_hook`: interrupts the
+/// other CPUs, which flush their TLB. `memory` waits for them.
+pub(crate) fn shoot_down_tlbs(_start : x86_64::VirtAddr, _len : u64) {
+    if apic_active() {
+        LocalApic.send_to_others(TLB_SHOOTDOWN_VECTOR);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+#[test_case]
+fn test_breakpoint_exception() {
+    serial_print!("test_breakpoint_exception... ");
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+}
\ No newline at end of file
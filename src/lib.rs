@@ -1,29 +1,42 @@
 #![no_std]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
-#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
 pub mod serial;
+pub mod acpi;
 pub mod vga_buffer;
 pub mod interrupt;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod backtrace;
+pub mod cpu;
+pub mod crash;
+pub mod elf;
+pub mod shell;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod userspace;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: allocator::KernelHeap = allocator::KernelHeap::empty();
 
-#[panic_handler]
-#[cfg(not(test))]
-fn panic(_info : &PanicInfo) -> ! {
-    println!("{}", _info);
-    hlt_loop()
+#[alloc_error_handler]
+fn alloc_error_handler(layout : alloc::alloc::Layout) -> ! {
+    serial_println!("allocation error: {:?}", layout);
+    serial_println!("{:?}", allocator::heap_stats());
+    panic!("allocation error: {:?}", layout)
 }
 
 pub fn hlt_loop() -> ! {
@@ -32,13 +45,64 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Restarts the machine through the ACPI reset register, else the reset
+/// line of the 8042 keyboard controller, else by triple faulting.
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    use x86_64::structures::DescriptorTablePointer;
+
+    x86_64::instructions::interrupts::disable();
+    acpi::reset();
+
+    let mut status = Port::<u8>::new(0x64);
+    unsafe {
+        // wait for the input buffer to empty, then pulse the reset line
+        for _ in 0..100_000 {
+            if status.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        status.write(0xfe);
+    }
+
+    // with an empty IDT the breakpoint turns into a triple fault
+    let empty = DescriptorTablePointer { limit : 0, base : x86_64::VirtAddr::zero() };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    hlt_loop()
+}
+
 pub fn init() {
     interrupt::init();
     gdt::init();
-    unsafe {interrupt::PICS.lock().initialize()};
+    syscall::init();
+    interrupt::init_pics();
+    time::init(time::DEFAULT_FREQUENCY_HZ);
     x86_64::instructions::interrupts::enable();
 }
 
+/// Sets up what most integration tests need: `init`, the heap, the kernel
+/// mapper and frame allocator installed in `memory`, and threads.
+pub fn test_boot(boot_info : &'static bootloader::BootInfo) {
+    use x86_64::VirtAddr;
+
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install_kernel_mapper(mapper);
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
@@ -50,6 +114,7 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    let _ = backtrace::write(&mut *serial::SERIAL1.lock(), backtrace::Frames::here());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
@@ -60,8 +125,19 @@ use bootloader::{entry_point, BootInfo};
 #[cfg(test)]
 entry_point!(test_kernel_main);
 #[cfg(test)]
-pub fn test_kernel_main(_info : &'static BootInfo) -> ! {
+pub fn test_kernel_main(boot_info : &'static BootInfo) -> ! {
+    use memory::BitmapFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // for the executor tests
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
     test_main();
     hlt_loop()
 }
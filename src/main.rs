@@ -5,7 +5,8 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use rustOS::{allocator, memory, println};
+use rustOS::{allocator, interrupt, memory, println, shell, smp, thread};
+use rustOS::task::{Task, executor::Executor};
 use bootloader::{bootinfo, entry_point, BootInfo};
 use x86_64::structures::paging::page;
 
@@ -17,32 +18,54 @@ pub fn kernel_main(boot_info : &'static BootInfo) -> ! {
     
     rustOS::init();
 
-    use rustOS::memory::BootInfoFrameAllocator;
+    use rustOS::memory::BitmapFrameAllocator;
     use x86_64::{VirtAddr, structures::paging::Page};
     use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe{memory::init(phys_mem_offset)};
     let mut frame_allocator = unsafe { 
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
      };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install_kernel_mapper(mapper);
+    memory::install_frame_allocator(frame_allocator);
+
+    thread::init();
+    if interrupt::init_apic() {
+        println!("interrupts: local APIC and IOAPIC");
+    } else {
+        println!("interrupts: 8259 PIC");
+    }
+    println!("{} CPUs online", smp::init());
 
     #[cfg(test)]
     test_main();
-    
-    println!("it did not crash");
-    rustOS::hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task())).ok().expect("task queue full");
+    executor.spawn(Task::new(shell::run_on_vga())).ok().expect("task queue full");
+    executor.spawn(Task::new(shell::run_on_serial())).ok().expect("task queue full");
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
 }
 
-// #[panic_handler]
-// #[cfg(not(test))]
-// fn panic(_info : &PanicInfo) -> ! {
-//     println!("{}", _info);
-//     rustOS::hlt_loop()
-// }
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
+}
+
+#[panic_handler]
+#[cfg(not(test))]
+fn panic(_info : &PanicInfo) -> ! {
+    rustOS::crash::report_panic(_info);
+    rustOS::hlt_loop()
+}
 
 
 #[panic_handler]
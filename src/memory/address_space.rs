@@ -0,0 +1,363 @@
+use core::ops::Range;
+use x86_64::{
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+            PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+            mapper::{MappedFrame, MapToError, TranslateResult},
+            page_table::PageTableEntry,
+        },
+    },
+    VirtAddr,
+};
+use crate::userspace::{USER_SPACE_END, USER_SPACE_START};
+use super::BitmapFrameAllocator;
+use super::vma::{Vma, VmaError, VmaSet};
+
+/// Marks a page that is shared read-only after `AddressSpace::fork` but may
+/// be written: the first write gets a private copy.
+pub const COPY_ON_WRITE : PageTableFlags = PageTableFlags::BIT_9;
+
+/// P4 entries covering `USER_SPACE_START..USER_SPACE_END`. All other entries
+/// point to the kernel's own P3 tables and are shared by every address space.
+fn user_p4_entries() -> Range<usize> {
+    let start = usize::from(VirtAddr::new(USER_SPACE_START).p4_index());
+    let end = usize::from(VirtAddr::new(USER_SPACE_END - 1).p4_index()) + 1;
+    start..end
+}
+
+/// A level-4 page table of its own for a user program.
+///
+/// The kernel is not linked into the higher half, so every P4 entry outside
+/// the user range is copied from the kernel table. Since these entries are
+/// shared, later kernel mappings inside them (heap growth, thread stacks)
+/// show up in every address space. Mappings in brand new P4 entries do not.
+pub struct AddressSpace {
+    p4_frame : PhysFrame,
+    physical_memory_offset : VirtAddr,
+    vmas : VmaSet,
+}
+
+// the page tables are owned exclusively by this value
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// Allocates a P4 table with the kernel mappings and an empty user range,
+    /// or `None` if there is no free frame.
+    pub fn new() -> Option<AddressSpace> {
+        super::try_with_kernel_memory(|kernel, frame_allocator| {
+            let p4_frame = frame_allocator.allocate_frame()?;
+            let physical_memory_offset = kernel.phys_offset();
+            let table = unsafe { table_at(physical_memory_offset, p4_frame) };
+            table.zero();
+
+            let user = user_p4_entries();
+            for (index, entry) in kernel.level_4_table().iter().enumerate() {
+                if !user.contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+            Some(AddressSpace { p4_frame, physical_memory_offset, vmas : VmaSet::new() })
+        }).flatten()
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// A mapper for this address space, whether it is active or not.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            let table = table_at(self.physical_memory_offset, self.p4_frame);
+            OffsetPageTable::new(table, self.physical_memory_offset)
+        }
+    }
+
+    pub fn vmas(&self) -> &VmaSet {
+        &self.vmas
+    }
+
+    /// Registers `[start, start + size)` as a lazy region: its pages get a
+    /// zeroed frame mapped with `flags` the first time they are touched.
+    pub fn map_lazy(&mut self, start : VirtAddr, size : u64, flags : PageTableFlags)
+        -> Result<(), VmaError>
+    {
+        self.vmas.insert(Vma::new(start, size, flags)?)
+    }
+
+    /// Creates a copy of this address space without copying any memory.
+    ///
+    /// Both sides share every user frame; writable pages become read-only
+    /// and `COPY_ON_WRITE` in both, so whichever side writes first gets its
+    /// own copy. Lazy regions are inherited. Returns `None` if there are not
+    /// enough frames for the child's page tables.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+
+        let offset = self.physical_memory_offset;
+        let shared = super::with_frame_allocator(|frame_allocator| unsafe {
+            let parent_p4 = table_at(offset, self.p4_frame);
+            let child_p4 = table_at(offset, child.p4_frame);
+            for index in user_p4_entries() {
+                share_entry(offset, &mut parent_p4[index], &mut child_p4[index], 4,
+                            frame_allocator)?;
+            }
+            Some(())
+        });
+        // writable pages of the parent just became read-only
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        // a partly built child is freed by its `Drop`
+        shared.map(|()| child)
+    }
+
+    /// Tries to resolve a page fault at `addr`: by backing a page in a lazy
+    /// region with a zeroed frame, or by copying a `COPY_ON_WRITE` page that
+    /// is written to. Returns `false` if the access is not allowed, so the
+    /// fault is a real one, or if the fault hit while this CPU held the frame
+    /// allocator, which then cannot be used to resolve it.
+    pub fn handle_fault(&mut self, addr : VirtAddr, error_code : PageFaultErrorCode) -> bool {
+        // a present page was accessed in a way its flags forbid
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && self.copy_on_write(addr);
+        }
+        let vma = match self.vmas.find(addr) {
+            Some(vma) => *vma,
+            None => return false,
+        };
+        let flags = vma.flags();
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !flags.contains(PageTableFlags::WRITABLE)
+        {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && flags.contains(PageTableFlags::NO_EXECUTE)
+        {
+            return false;
+        }
+
+        let page : Page = Page::containing_address(addr);
+        let offset = self.physical_memory_offset;
+        let mut mapper = self.mapper();
+        super::with_frame_allocator_unless_held_here(|frame_allocator| {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            unsafe {
+                let virt = offset + frame.start_address().as_u64();
+                core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096);
+                match mapper.map_to(page, frame, flags, frame_allocator) {
+                    Ok(flush) => {
+                        flush.flush();
+                        true
+                    }
+                    Err(MapToError::FrameAllocationFailed) => {
+                        frame_allocator.deallocate_frame(frame);
+                        false
+                    }
+                    // resolved in the meantime
+                    Err(_) => {
+                        frame_allocator.deallocate_frame(frame);
+                        true
+                    }
+                }
+            }
+        }).unwrap_or(false)
+    }
+
+    fn copy_on_write(&mut self, addr : VirtAddr) -> bool {
+        let offset = self.physical_memory_offset;
+        let mut mapper = self.mapper();
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame : MappedFrame::Size4KiB(frame), flags, .. }
+                if flags.contains(COPY_ON_WRITE) =>
+            {
+                copy_page::<Size4KiB>(&mut mapper, offset, Page::containing_address(addr), frame, flags)
+            }
+            TranslateResult::Mapped { frame : MappedFrame::Size2MiB(frame), flags, .. }
+                if flags.contains(COPY_ON_WRITE) =>
+            {
+                copy_page::<Size2MiB>(&mut mapper, offset, Page::containing_address(addr), frame, flags)
+            }
+            _ => false,
+        }
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// Unsafe because the caller must make sure it stays alive while active.
+    /// Threads normally get this done by the scheduler, see
+    /// `thread::spawn_in`.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.p4_frame, flags);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every frame mapped in the user range, the page tables holding
+    /// them and the P4 table itself.
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe {
+                let (_, flags) = Cr3::read();
+                Cr3::write(super::kernel_p4_frame(), flags);
+            }
+        }
+
+        let offset = self.physical_memory_offset;
+        super::with_frame_allocator(|frame_allocator| unsafe {
+            let p4 = table_at(offset, self.p4_frame);
+            for index in user_p4_entries() {
+                if let Ok(p3_frame) = p4[index].frame() {
+                    free_p3(offset, p3_frame, frame_allocator);
+                }
+            }
+            frame_allocator.deallocate_frame(self.p4_frame);
+        });
+    }
+}
+
+unsafe fn table_at(physical_memory_offset : VirtAddr, frame : PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+/// Gives `page` a private, writable copy of `frame`, or just makes it
+/// writable if nobody else maps the frame anymore. Returns `false` if that
+/// takes a frame and there is none, or this CPU holds the frame allocator.
+fn copy_page<S : PageSize + core::fmt::Debug>(mapper : &mut impl Mapper<S>,
+                           offset : VirtAddr,
+                           page : Page<S>,
+                           frame : PhysFrame<S>,
+                           flags : PageTableFlags)
+    -> bool
+    where BitmapFrameAllocator : FrameAllocator<S> + FrameDeallocator<S>
+{
+    let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    super::with_frame_allocator_unless_held_here(|frame_allocator| unsafe {
+        // the other side already made its copy, or was dropped; huge frames
+        // are shared as a whole, so their first 4 KiB frame speaks for all
+        let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+        if frame_allocator.reference_count(first) == 1 {
+            mapper.update_flags(page, writable).expect("page vanished").flush();
+            return true;
+        }
+
+        let copy : PhysFrame<S> = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        core::ptr::copy_nonoverlapping(
+            (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            S::SIZE as usize,
+        );
+        mapper.unmap(page).expect("page vanished").1.ignore();
+        // the page table is still there, so this never allocates
+        mapper.map_to(page, copy, writable, frame_allocator)
+            .expect("failed to map private copy")
+            .flush();
+        frame_allocator.deallocate_frame(frame);
+        true
+    }).unwrap_or(false)
+}
+
+/// Makes `child` map the same user pages as `parent` at the given page
+/// table `level`, with a page table of its own for every level but the last.
+unsafe fn share_entry(offset : VirtAddr,
+                      parent : &mut PageTableEntry,
+                      child : &mut PageTableEntry,
+                      level : u8,
+                      frame_allocator : &mut BitmapFrameAllocator)
+    -> Option<()>
+{
+    let mut flags = parent.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Some(());
+    }
+    let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+    if level == 1 || huge {
+        assert!(level <= 2, "user space is never mapped with 1 GiB pages");
+        // frames the allocator does not count, like devices mapped with
+        // `Backing::Physical`, are shared as they are and never copied
+        let first = PhysFrame::<Size4KiB>::containing_address(parent.addr());
+        if frame_allocator.reference_count(first) > 0 && flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+            parent.set_flags(flags);
+        }
+        let count = if huge { Size2MiB::SIZE / Size4KiB::SIZE } else { 1 };
+        for frame in PhysFrame::range(first, first + count) {
+            if frame_allocator.reference_count(frame) > 0 {
+                frame_allocator.add_reference(frame);
+            }
+        }
+        child.set_addr(parent.addr(), flags);
+        return Some(());
+    }
+
+    let table_frame = frame_allocator.allocate_frame()?;
+    let child_table = table_at(offset, table_frame);
+    child_table.zero();
+    child.set_addr(table_frame.start_address(), flags);
+
+    let parent_table = table_at(offset, parent.frame().ok()?);
+    for (parent, child) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+        share_entry(offset, parent, child, level - 1, frame_allocator)?;
+    }
+    Some(())
+}
+
+unsafe fn free_p3(offset : VirtAddr, frame : PhysFrame, frame_allocator : &mut BitmapFrameAllocator) {
+    for entry in table_at(offset, frame).iter() {
+        // user space is never mapped with 1 GiB pages
+        if let Ok(p2_frame) = entry.frame() {
+            free_p2(offset, p2_frame, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+unsafe fn free_p2(offset : VirtAddr, frame : PhysFrame, frame_allocator : &mut BitmapFrameAllocator) {
+    for entry in table_at(offset, frame).iter() {
+        if entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) {
+            let first = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+            let count = Size2MiB::SIZE / Size4KiB::SIZE;
+            for frame in PhysFrame::range(first, first + count) {
+                free_mapped(frame, frame_allocator);
+            }
+        } else if let Ok(p1_frame) = entry.frame() {
+            free_p1(offset, p1_frame, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+unsafe fn free_p1(offset : VirtAddr, frame : PhysFrame, frame_allocator : &mut BitmapFrameAllocator) {
+    for entry in table_at(offset, frame).iter() {
+        if let Ok(page_frame) = entry.frame() {
+            free_mapped(page_frame, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Drops the reference a user mapping holds on `frame`, unless the
+/// allocator does not count it, as for `Backing::Physical` device memory.
+unsafe fn free_mapped(frame : PhysFrame, frame_allocator : &mut BitmapFrameAllocator) {
+    if frame_allocator.reference_count(frame) > 0 {
+        frame_allocator.deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,260 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB,
+        frame::PhysFrameRange,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+
+const FRAME_SIZE : u64 = 4096;
+const BITS_PER_WORD : usize = 64;
+/// Number of 4 KiB frames in a 2 MiB frame.
+const HUGE_FRAME_FRAMES : usize = 512;
+
+/// Snapshot of the physical frame usage, in 4 KiB frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total_frames : usize,
+    pub used_frames : usize,
+    pub free_frames : usize,
+}
+
+impl FrameStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.total_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames as u64 * FRAME_SIZE
+    }
+}
+
+/// Physical frame allocator backed by a bitmap with one bit per 4 KiB frame.
+///
+/// A set bit means the frame is in use or is not usable RAM at all. The
+/// bitmap itself lives in the first usable region large enough to hold it,
+/// reached through the bootloader's physical memory mapping.
+///
+/// Allocated frames are reference counted so that address spaces can share
+/// them: `add_reference` takes another reference and `deallocate_frame` drops
+/// one, freeing the frame with the last one. The counts are stored right
+/// behind the bitmap, so sharing never needs the heap.
+pub struct BitmapFrameAllocator {
+    bitmap : &'static mut [u64],
+    reference_counts : &'static mut [u16],
+    frame_count : usize,
+    total_frames : usize,
+    free_frames : usize,
+    /// Index of the first word that may contain a free frame.
+    next_word : usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the bootloader memory map.
+    ///
+    /// Unsafe because the caller must guarantee that all usable regions are
+    /// really unused and that the whole physical memory is mapped at
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map : &'static MemoryMap, physical_memory_offset : VirtAddr)
+        -> Self
+    {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let max_addr = usable_regions().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        // the bitmap is followed by one u16 reference count per frame
+        let bitmap_bytes = (words * 8 + frame_count * 2) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region large enough for the frame bitmap")
+            .range.start_addr();
+
+        let bitmap_ptr : *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(u64::MAX);
+        let counts_ptr = bitmap_ptr.add(words) as *mut u16;
+        let reference_counts = core::slice::from_raw_parts_mut(counts_ptr, frame_count);
+        reference_counts.fill(0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            reference_counts,
+            frame_count,
+            total_frames : 0,
+            free_frames : 0,
+            next_word : 0,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear(index);
+            }
+            allocator.total_frames += end - start;
+        }
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.set(index);
+        }
+        allocator.free_frames = allocator.total_frames - bitmap_frames as usize;
+
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames : self.total_frames,
+            used_frames : self.total_frames - self.free_frames,
+            free_frames : self.free_frames,
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    pub fn allocate_contiguous(&mut self, count : usize) -> Option<PhysFrameRange> {
+        let start = self.find_free_run(count, 1)?;
+        self.mark_run_used(start, count);
+        Some(PhysFrame::range(Self::frame(start), Self::frame(start + count)))
+    }
+
+    /// Allocates a frame that starts below `limit`, for code and devices that
+    /// can only reach low memory. Frame 0 is never handed out.
+    pub fn allocate_frame_below(&mut self, limit : PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count);
+        let index = (1..end).find(|&index| !self.is_used(index))?;
+        self.mark_run_used(index, 1);
+        Some(Self::frame(index))
+    }
+
+    /// Number of references to an allocated frame, 0 if it is free.
+    pub fn reference_count(&self, frame : PhysFrame) -> u16 {
+        self.reference_counts.get(Self::index(frame)).copied().unwrap_or(0)
+    }
+
+    /// Takes another reference to an allocated frame, which then needs one
+    /// more `deallocate_frame` before it is free again.
+    pub fn add_reference(&mut self, frame : PhysFrame) {
+        let index = self.managed_index(frame);
+        let count = &mut self.reference_counts[index];
+        assert!(*count > 0, "reference to unallocated {:?}", frame);
+        *count = count.checked_add(1).expect("frame reference count overflow");
+    }
+
+    /// Gives back a range handed out by `allocate_contiguous`.
+    pub unsafe fn deallocate_contiguous(&mut self, range : PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn frame(index : usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn index(frame : PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Index of `frame`, which has to be below the highest usable address;
+    /// frames above it, like most device memory, have no bit or count.
+    fn managed_index(&self, frame : PhysFrame) -> usize {
+        let index = Self::index(frame);
+        assert!(index < self.frame_count, "{:?} is not managed by the frame allocator", frame);
+        index
+    }
+
+    fn is_used(&self, index : usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index : usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index : usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    /// Finds `count` free frames starting at a multiple of `align` frames.
+    fn find_free_run(&self, count : usize, align : usize) -> Option<usize> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let align_up = |index : usize| (index + align - 1) / align * align;
+
+        let mut start = align_up(self.next_word * BITS_PER_WORD);
+        'candidates: while start + count <= self.frame_count {
+            for index in start..start + count {
+                if self.is_used(index) {
+                    start = align_up(index + 1);
+                    continue 'candidates;
+                }
+            }
+            return Some(start);
+        }
+        None
+    }
+
+    fn mark_run_used(&mut self, start : usize, count : usize) {
+        for index in start..start + count {
+            self.set(index);
+            self.reference_counts[index] = 1;
+        }
+        self.free_frames -= count;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let word = (self.next_word..self.bitmap.len())
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+        self.next_word = word;
+
+        let index = word * BITS_PER_WORD + (!self.bitmap[word]).trailing_zeros() as usize;
+        self.set(index);
+        self.reference_counts[index] = 1;
+        self.free_frames -= 1;
+        Some(Self::frame(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Drops one reference; the frame is only freed with the last one.
+    unsafe fn deallocate_frame(&mut self, frame : PhysFrame) {
+        let index = self.managed_index(frame);
+        assert!(self.is_used(index) && self.reference_counts[index] > 0,
+                "double free of {:?}", frame);
+        self.reference_counts[index] -= 1;
+        if self.reference_counts[index] > 0 {
+            return;
+        }
+        self.clear(index);
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let start = self.find_free_run(HUGE_FRAME_FRAMES, HUGE_FRAME_FRAMES)?;
+        self.mark_run_used(start, HUGE_FRAME_FRAMES);
+        Some(PhysFrame::containing_address(Self::frame(start).start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame : PhysFrame<Size2MiB>) {
+        let start = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+        self.deallocate_contiguous(PhysFrame::range(start, start + HUGE_FRAME_FRAMES as u64));
+    }
+}
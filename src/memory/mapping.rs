@@ -0,0 +1,361 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
+        mapper::{MappedFrame, MapToError, TranslateResult},
+    },
+    PhysAddr,
+    VirtAddr,
+};
+use crate::cpu::{self, MAX_CPUS};
+use super::BitmapFrameAllocator;
+
+const PAGE_SIZE : u64 = 4096;
+const HUGE_PAGE_SIZE : u64 = 2 * 1024 * 1024;
+
+/// Where the frames behind a `map_range` come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Fresh frames from the frame allocator, with whatever they contain.
+    Allocate,
+    /// Fresh frames from the frame allocator, zeroed.
+    AllocateZeroed,
+    /// The physical range starting here, e.g. device memory.
+    Physical(PhysAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// An address or the length is not page aligned.
+    Unaligned,
+    FrameAllocationFailed,
+    AlreadyMapped(VirtAddr),
+    NotMapped(VirtAddr),
+    /// The range covers only part of a huge page.
+    PartialHugePage(VirtAddr),
+}
+
+/// Called after mappings were removed or restricted, with the start and
+/// length of the range. It has to interrupt the other CPUs, which answer
+/// with `acknowledge_tlb_shootdown`; the local TLB is already flushed.
+pub type TlbShootdownHook = fn(VirtAddr, u64);
+
+static TLB_SHOOTDOWN_HOOK : Mutex<Option<TlbShootdownHook>> = Mutex::new(None);
+
+/// Per CPU, the number of flushes asked of it and the number it has
+/// answered. Only the CPU itself advances its `TLB_FLUSHES_DONE`.
+static TLB_FLUSHES_REQUESTED : [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static TLB_FLUSHES_DONE : [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+pub fn set_tlb_shootdown_hook(hook : TlbShootdownHook) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *TLB_SHOOTDOWN_HOOK.lock() = Some(hook);
+    });
+}
+
+/// Has every other online CPU flush its TLB and waits until they all did,
+/// so that the caller may hand out the unmapped frames again.
+///
+/// The callers hold the kernel memory locks with interrupts disabled. A CPU
+/// waiting for them with interrupts disabled cannot take the IPI, so lock
+/// waits call `acknowledge_tlb_shootdown` instead, and so does this wait in
+/// case another CPU shoots down at the same time.
+fn shootdown(start : VirtAddr, len : u64) {
+    let hook = x86_64::instructions::interrupts::without_interrupts(|| {
+        *TLB_SHOOTDOWN_HOOK.lock()
+    });
+    let Some(hook) = hook else { return };
+    if len == 0 {
+        return;
+    }
+
+    let this = cpu::id();
+    let others = || (0..cpu::count()).filter(move |&cpu| cpu != this);
+    let mut targets = [0; MAX_CPUS];
+    for cpu in others() {
+        targets[cpu] = TLB_FLUSHES_REQUESTED[cpu].fetch_add(1, Ordering::AcqRel) + 1;
+    }
+    hook(start, len);
+    for cpu in others() {
+        while TLB_FLUSHES_DONE[cpu].load(Ordering::Acquire) < targets[cpu] {
+            acknowledge_tlb_shootdown();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Flushes this CPU's TLB if a shootdown is waiting for it. Called by the
+/// shootdown IPI and by anything that spins with interrupts disabled.
+pub fn acknowledge_tlb_shootdown() {
+    let Some(this) = cpu::try_id() else { return };
+    let requested = TLB_FLUSHES_REQUESTED[this].load(Ordering::Acquire);
+    if TLB_FLUSHES_DONE[this].load(Ordering::Relaxed) < requested {
+        flush_tlb();
+        TLB_FLUSHES_DONE[this].store(requested, Ordering::Release);
+    }
+}
+
+/// Drops every TLB entry of this CPU, global ones included.
+fn flush_tlb() {
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        x86_64::instructions::tlb::flush_all();
+    }
+}
+
+/// Maps `[virt, virt + len)` with `flags` (plus `PRESENT`).
+///
+/// Stretches that are 2 MiB aligned on both the virtual and the physical side
+/// get huge pages, everything else 4 KiB pages. Frames that belong to the
+/// frame allocator get a reference per mapping, so `unmap_range` can always
+/// give them back. On failure, nothing of the range stays mapped.
+pub fn map_range(mapper : &mut OffsetPageTable,
+                 frame_allocator : &mut BitmapFrameAllocator,
+                 virt : VirtAddr,
+                 backing : Backing,
+                 len : u64,
+                 flags : PageTableFlags)
+    -> Result<(), MapError>
+{
+    let phys_aligned = match backing {
+        Backing::Physical(phys) => phys.is_aligned(PAGE_SIZE),
+        _ => true,
+    };
+    if !virt.is_aligned(PAGE_SIZE) || len % PAGE_SIZE != 0 || !phys_aligned {
+        return Err(MapError::Unaligned);
+    }
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let mut mapped = 0;
+    while mapped < len {
+        let addr = virt + mapped;
+        let phys = match backing {
+            Backing::Physical(phys) => Some(phys + mapped),
+            _ => None,
+        };
+        let huge = len - mapped >= HUGE_PAGE_SIZE
+            && addr.is_aligned(HUGE_PAGE_SIZE)
+            && phys.map_or(true, |phys| phys.is_aligned(HUGE_PAGE_SIZE));
+        if huge && map_huge_page(mapper, frame_allocator, addr, backing, phys, flags) {
+            mapped += HUGE_PAGE_SIZE;
+            continue;
+        }
+
+        if let Err(error) = map_page(mapper, frame_allocator, addr, backing, phys, flags) {
+            let _ = unmap_range(mapper, frame_allocator, virt, mapped);
+            return Err(error);
+        }
+        mapped += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+fn map_page(mapper : &mut OffsetPageTable,
+            frame_allocator : &mut BitmapFrameAllocator,
+            addr : VirtAddr,
+            backing : Backing,
+            phys : Option<PhysAddr>,
+            flags : PageTableFlags)
+    -> Result<(), MapError>
+{
+    let page : Page = Page::containing_address(addr);
+    let frame : PhysFrame = match phys {
+        Some(phys) => {
+            let frame = PhysFrame::containing_address(phys);
+            if frame_allocator.reference_count(frame) > 0 {
+                frame_allocator.add_reference(frame);
+            }
+            frame
+        }
+        None => frame_allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?,
+    };
+    if backing == Backing::AllocateZeroed {
+        zero(mapper, frame.start_address(), PAGE_SIZE);
+    }
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            if frame_allocator.reference_count(frame) > 0 {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(match error {
+                MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+                MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) =>
+                    MapError::AlreadyMapped(addr),
+            })
+        }
+    }
+}
+
+/// Returns `false` if the caller should fall back to 4 KiB pages, e.g. when
+/// there is no free 2 MiB frame or part of the stretch already has a page
+/// table.
+fn map_huge_page(mapper : &mut OffsetPageTable,
+                 frame_allocator : &mut BitmapFrameAllocator,
+                 addr : VirtAddr,
+                 backing : Backing,
+                 phys : Option<PhysAddr>,
+                 flags : PageTableFlags)
+    -> bool
+{
+    let page : Page<Size2MiB> = Page::containing_address(addr);
+    let frame : PhysFrame<Size2MiB> = match phys {
+        Some(phys) => PhysFrame::containing_address(phys),
+        None => match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        },
+    };
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(_) => {
+            if phys.is_none() {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            return false;
+        }
+    }
+
+    if backing == Backing::AllocateZeroed {
+        zero(mapper, frame.start_address(), HUGE_PAGE_SIZE);
+    }
+    if phys.is_some() {
+        for small in small_frames(frame) {
+            if frame_allocator.reference_count(small) > 0 {
+                frame_allocator.add_reference(small);
+            }
+        }
+    }
+    true
+}
+
+fn small_frames(frame : PhysFrame<Size2MiB>) -> impl Iterator<Item = PhysFrame> {
+    let start = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+    PhysFrame::range(start, start + HUGE_PAGE_SIZE / PAGE_SIZE)
+}
+
+fn zero(mapper : &OffsetPageTable, phys : PhysAddr, len : u64) {
+    let virt = mapper.phys_offset() + phys.as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, len as usize) };
+}
+
+/// Removes the mappings of `[virt, virt + len)` and gives their frames back
+/// to the frame allocator. Frames it does not manage (device memory) are
+/// left alone.
+///
+/// The frames are freed before the other CPUs have flushed, but nobody can
+/// allocate them before `frame_allocator` is released, and by then the
+/// shootdown has completed.
+///
+/// Fails without changing anything if part of the range is not mapped or
+/// only part of a huge page is covered.
+pub fn unmap_range(mapper : &mut OffsetPageTable,
+                   frame_allocator : &mut BitmapFrameAllocator,
+                   virt : VirtAddr,
+                   len : u64)
+    -> Result<(), MapError>
+{
+    if !virt.is_aligned(PAGE_SIZE) || len % PAGE_SIZE != 0 {
+        return Err(MapError::Unaligned);
+    }
+    check_mapped(mapper, virt, len)?;
+
+    let mut offset = 0;
+    while offset < len {
+        let addr = virt + offset;
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame : MappedFrame::Size2MiB(_), .. } => {
+                let (frame, flush) = Mapper::<Size2MiB>::unmap(mapper, Page::containing_address(addr))
+                    .expect("huge page vanished");
+                flush.flush();
+                for small in small_frames(frame) {
+                    if frame_allocator.reference_count(small) > 0 {
+                        unsafe { frame_allocator.deallocate_frame(small) };
+                    }
+                }
+                offset += HUGE_PAGE_SIZE;
+            }
+            _ => {
+                let (frame, flush) = Mapper::<Size4KiB>::unmap(mapper, Page::containing_address(addr))
+                    .expect("page vanished");
+                flush.flush();
+                if frame_allocator.reference_count(frame) > 0 {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                offset += PAGE_SIZE;
+            }
+        }
+    }
+    shootdown(virt, len);
+    Ok(())
+}
+
+/// Changes the flags of every page in `[virt, virt + len)` to `flags` (plus
+/// `PRESENT`) and flushes them from the TLB.
+///
+/// Only the last level entries change, so making a page writable or user
+/// accessible needs the page tables above it to allow that already.
+pub fn protect(mapper : &mut OffsetPageTable, virt : VirtAddr, len : u64, flags : PageTableFlags)
+    -> Result<(), MapError>
+{
+    if !virt.is_aligned(PAGE_SIZE) || len % PAGE_SIZE != 0 {
+        return Err(MapError::Unaligned);
+    }
+    check_mapped(mapper, virt, len)?;
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let mut offset = 0;
+    while offset < len {
+        let addr = virt + offset;
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame : MappedFrame::Size2MiB(_), .. } => {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                unsafe { mapper.update_flags(page, flags).expect("huge page vanished").flush() };
+                offset += HUGE_PAGE_SIZE;
+            }
+            _ => {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                unsafe { mapper.update_flags(page, flags).expect("page vanished").flush() };
+                offset += PAGE_SIZE;
+            }
+        }
+    }
+    shootdown(virt, len);
+    Ok(())
+}
+
+/// Checks that the whole range is mapped and that it does not cut a huge
+/// page in half.
+fn check_mapped(mapper : &OffsetPageTable, virt : VirtAddr, len : u64) -> Result<(), MapError> {
+    let mut offset = 0;
+    while offset < len {
+        let addr = virt + offset;
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame : MappedFrame::Size4KiB(_), .. } => offset += PAGE_SIZE,
+            TranslateResult::Mapped { frame : MappedFrame::Size2MiB(_), .. } => {
+                if !addr.is_aligned(HUGE_PAGE_SIZE) || len - offset < HUGE_PAGE_SIZE {
+                    return Err(MapError::PartialHugePage(addr));
+                }
+                offset += HUGE_PAGE_SIZE;
+            }
+            // nothing maps 1 GiB pages
+            TranslateResult::Mapped { .. } => return Err(MapError::PartialHugePage(addr)),
+            _ => return Err(MapError::NotMapped(addr)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+use super::{map_range, Backing};
+
+/// Virtual range device registers get mapped into. It shares the level-4
+/// entry of the kernel stacks (see `stack::KERNEL_STACKS_START`), so
+/// mappings made after an `AddressSpace` was created are visible in it too.
+pub const MMIO_START : u64 = 0x_4840_0000_0000;
+pub const MMIO_SIZE : u64 = 0x_40_0000_0000;
+
+const PAGE_SIZE : u64 = 4096;
+
+static NEXT : AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps the device memory `[phys, phys + len)` uncached and returns where
+/// `phys` ended up. Mappings are never removed.
+///
+/// Returns `None` if the kernel memory is not installed yet, the MMIO range
+/// is used up or mapping fails.
+pub fn map(phys : PhysAddr, len : u64) -> Option<VirtAddr> {
+    let start = phys.align_down(PAGE_SIZE);
+    let len = (phys + len).align_up(PAGE_SIZE) - start;
+    let virt = NEXT.fetch_add(len, Ordering::Relaxed);
+    if virt + len > MMIO_START + MMIO_SIZE {
+        return None;
+    }
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    super::try_with_kernel_memory(|mapper, frame_allocator| {
+        map_range(mapper, frame_allocator, VirtAddr::new(virt), Backing::Physical(start), len, flags)
+    })?.ok()?;
+    Some(VirtAddr::new(virt) + (phys - start))
+}
@@ -0,0 +1,203 @@
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::println;
+use crate::sync::IrqSafeSpinlock;
+
+pub mod address_space;
+pub mod frame_allocator;
+pub mod mapping;
+pub mod mmio;
+pub mod stack;
+pub mod vma;
+pub mod walk;
+
+pub use address_space::{AddressSpace, COPY_ON_WRITE};
+pub use frame_allocator::{BitmapFrameAllocator, FrameStats};
+pub use mapping::{map_range, protect, unmap_range, Backing, MapError};
+pub use stack::{overflowed_stack, KernelStack};
+pub use vma::{Vma, VmaError, VmaSet};
+pub use walk::{MappedRange, Walk, WalkStep};
+
+static KERNEL_MAPPER : IrqSafeSpinlock<Option<OffsetPageTable<'static>>> = IrqSafeSpinlock::new(None);
+static FRAME_ALLOCATOR : IrqSafeSpinlock<Option<BitmapFrameAllocator>> = IrqSafeSpinlock::new(None);
+/// Where the bootloader mapped physical memory, recorded by `init`.
+static PHYSICAL_MEMORY_OFFSET : AtomicU64 = AtomicU64::new(0);
+
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+
+pub unsafe fn init(physical_memory_offset : VirtAddr) 
+    -> OffsetPageTable<'static> 
+{
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Hands the kernel page table over to `memory`, so that code without access
+/// to `kernel_main`'s locals (thread stacks, ...) can change mappings.
+pub fn install_kernel_mapper(mapper : OffsetPageTable<'static>) {
+    *KERNEL_MAPPER.lock() = Some(mapper);
+}
+
+/// Runs `f` on the installed kernel page table.
+///
+/// Interrupts are disabled meanwhile so a preempted thread cannot hold the
+/// lock while an interrupt handler wants it.
+pub fn with_kernel_mapper<F, R>(f : F) -> R
+    where F : FnOnce(&mut OffsetPageTable<'static>) -> R
+{
+    let mut mapper = KERNEL_MAPPER.lock();
+    f(mapper.as_mut().expect("kernel mapper not installed"))
+}
+
+/// Hands the physical frame allocator over to `memory`, so that the heap and
+/// other subsystems can allocate frames after boot.
+pub fn install_frame_allocator(frame_allocator : BitmapFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Runs `f` on the installed frame allocator.
+pub fn with_frame_allocator<F, R>(f : F) -> R
+    where F : FnOnce(&mut BitmapFrameAllocator) -> R
+{
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    f(frame_allocator.as_mut().expect("frame allocator not installed"))
+}
+
+/// Like `with_frame_allocator`, but returns `None` instead of deadlocking if
+/// the running CPU holds the frame allocator, as the code an exception
+/// handler interrupted may.
+pub fn with_frame_allocator_unless_held_here<F, R>(f : F) -> Option<R>
+    where F : FnOnce(&mut BitmapFrameAllocator) -> R
+{
+    let mut frame_allocator = FRAME_ALLOCATOR.lock_unless_held_here()?;
+    Some(f(frame_allocator.as_mut().expect("frame allocator not installed")))
+}
+
+/// Runs `f` with both the kernel page table and the frame allocator, or
+/// returns `None` if either has not been installed yet.
+///
+/// Lock order is always mapper before frame allocator. The heap takes both
+/// to grow, so `f` must not allocate or free; see `KernelHeap`.
+pub fn try_with_kernel_memory<F, R>(f : F) -> Option<R>
+    where F : FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R
+{
+    let mut mapper = KERNEL_MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+}
+
+/// Runs `f` with the page table currently in CR3 and the frame allocator, or
+/// returns `None` if either has not been installed yet.
+///
+/// This is the kernel table for kernel threads and the thread's own
+/// `AddressSpace` for user programs. The kernel mapper stays locked
+/// meanwhile, so page table changes are never interleaved.
+pub fn try_with_active_memory<F, R>(f : F) -> Option<R>
+    where F : FnOnce(&mut OffsetPageTable, &mut BitmapFrameAllocator) -> R
+{
+    try_with_kernel_memory(|kernel, frame_allocator| {
+        let (active_frame, _) = Cr3::read();
+        if active_frame == p4_frame_of(kernel) {
+            return f(kernel, frame_allocator);
+        }
+        let offset = kernel.phys_offset();
+        let table = unsafe { &mut *(offset + active_frame.start_address().as_u64()).as_mut_ptr() };
+        let mut active = unsafe { OffsetPageTable::new(table, offset) };
+        f(&mut active, frame_allocator)
+    })
+}
+
+/// Whether the running CPU holds the kernel mapper or the frame allocator,
+/// and so must not use the heap.
+pub fn locked_here() -> bool {
+    KERNEL_MAPPER.is_held_here() || FRAME_ALLOCATOR.is_held_here()
+}
+
+/// Frame of the kernel's level-4 table, the one the bootloader set up.
+pub fn kernel_p4_frame() -> PhysFrame {
+    with_kernel_mapper(|mapper| p4_frame_of(mapper))
+}
+
+fn p4_frame_of(mapper : &mut OffsetPageTable) -> PhysFrame {
+    let virt = VirtAddr::from_ptr(mapper.level_4_table() as *const PageTable);
+    PhysFrame::containing_address(PhysAddr::new(virt - mapper.phys_offset()))
+}
+
+unsafe fn active_level_4_table(physical_memory_offset : VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr : *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// Translates `addr` with the active page table. Handles 2 MiB and 1 GiB
+/// pages.
+pub unsafe fn translate_addr(addr : VirtAddr, physical_memory_offset : VirtAddr)
+    -> Option<PhysAddr>
+{
+    let (level_4_table_frame, _) = Cr3::read();
+    walk::walk_table(level_4_table_frame, physical_memory_offset, addr).phys()
+}
+
+/// Walks the active page table for `addr`, for debugging.
+///
+/// Takes no locks, so it also works in fault handlers; a table that changes
+/// during the walk gives a garbled result.
+pub fn walk(addr : VirtAddr) -> Walk {
+    let (level_4_table_frame, _) = Cr3::read();
+    unsafe { walk::walk_table(level_4_table_frame, physical_memory_offset(), addr) }
+}
+
+/// Prints every mapped region of the active page table, one line per range
+/// of pages with contiguous frames and the same flags.
+pub fn dump_page_tables() {
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut count = 0;
+    unsafe {
+        walk::for_each_mapped_range(level_4_table_frame, physical_memory_offset(), |range| {
+            println!("{}", range);
+            count += 1;
+        });
+    }
+    println!("{} mapped ranges", count);
+}
+
+/// Whether `addr` is mapped in the active page table. Always `false` before
+/// `init`; takes no locks, so crash handlers can use it.
+pub fn is_mapped(addr : VirtAddr) -> bool {
+    let (level_4_table_frame, _) = Cr3::read();
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => false,
+        offset => unsafe {
+            walk::walk_table(level_4_table_frame, VirtAddr::new(offset), addr).phys().is_some()
+        },
+    }
+}
+
+/// Where all of physical memory is mapped. Panics before `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => panic!("memory::init has not been called"),
+        offset => VirtAddr::new(offset),
+    }
+}
@@ -0,0 +1,103 @@
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+use super::{map_range, unmap_range, Backing};
+
+/// Virtual range all kernel stacks live in. It is cut into fixed slots with
+/// the stack at the top of each, so everything below a stack up to the
+/// previous slot stays unmapped and catches overflows.
+///
+/// The whole range sits under one level-4 entry, which is created with the
+/// first stack. `thread::init` allocates stacks before any `AddressSpace`
+/// exists, so every address space copies that entry.
+pub const KERNEL_STACKS_START : u64 = 0x_4800_0000_0000;
+pub const STACK_SLOT_SIZE : u64 = 64 * 1024;
+pub const STACK_SLOTS : usize = 256;
+/// Largest stack that still leaves a guard page in its slot.
+pub const MAX_STACK_SIZE : usize = STACK_SLOT_SIZE as usize - PAGE_SIZE;
+
+const PAGE_SIZE : usize = 4096;
+
+/// Name of the stack in each slot, `None` for free slots.
+static SLOTS : Mutex<[Option<&'static str>; STACK_SLOTS]> = Mutex::new([None; STACK_SLOTS]);
+
+/// A kernel stack in its own slot of the stack range, with unmapped memory
+/// beneath it.
+pub struct KernelStack {
+    slot : usize,
+    size : u64,
+}
+
+impl KernelStack {
+    /// Maps a stack of at least `size` bytes. `name` is what overflows are
+    /// reported as.
+    pub fn new(name : &'static str, size : usize) -> KernelStack {
+        assert!(size <= MAX_STACK_SIZE, "kernel stack of {} bytes does not fit a slot", size);
+        let size = ((size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE) as u64;
+
+        let slot = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slot = slots.iter().position(Option::is_none).expect("out of kernel stack slots");
+            slots[slot] = Some(name);
+            slot
+        });
+        let stack = KernelStack { slot, size };
+
+        super::try_with_kernel_memory(|mapper, frame_allocator| {
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            map_range(mapper, frame_allocator, stack.bottom(), Backing::Allocate, size, flags)
+        })
+            .expect("kernel memory not installed")
+            .expect("out of memory allocating a kernel stack");
+        stack
+    }
+
+    /// Highest address of the stack; stacks grow down from here.
+    pub fn top(&self) -> VirtAddr {
+        slot_start(self.slot) + STACK_SLOT_SIZE
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size
+    }
+
+    /// The unmapped page right below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom() - 1u64)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        super::try_with_kernel_memory(|mapper, frame_allocator| {
+            unmap_range(mapper, frame_allocator, self.bottom(), self.size)
+        })
+            .expect("kernel memory not installed")
+            .expect("failed to unmap kernel stack");
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            SLOTS.lock()[self.slot] = None;
+        });
+    }
+}
+
+fn slot_start(slot : usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACKS_START + slot as u64 * STACK_SLOT_SIZE)
+}
+
+/// If `addr` lies in the unmapped part of a stack slot, the name of the
+/// stack that ran into it.
+///
+/// Meant for fault handlers: gives up instead of spinning if the slot table
+/// is locked.
+pub fn overflowed_stack(addr : VirtAddr) -> Option<&'static str> {
+    let offset = addr.as_u64().checked_sub(KERNEL_STACKS_START)?;
+    let slot = (offset / STACK_SLOT_SIZE) as usize;
+    if slot >= STACK_SLOTS {
+        return None;
+    }
+    let name = SLOTS.try_lock()?[slot]?;
+    let mapped = super::walk(addr).phys().is_some();
+    if mapped { None } else { Some(name) }
+}
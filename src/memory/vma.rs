@@ -0,0 +1,106 @@
+use alloc::collections::BTreeMap;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+use crate::userspace;
+
+/// A virtual memory area: a page-aligned range of user addresses that is
+/// backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    start : VirtAddr,
+    end : VirtAddr,
+    flags : PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    Empty,
+    Unaligned,
+    OutsideUserSpace,
+    /// The range intersects an area that is already registered.
+    Overlap,
+}
+
+impl Vma {
+    /// `flags` are what the pages get mapped with, `PRESENT` and
+    /// `USER_ACCESSIBLE` are added.
+    pub fn new(start : VirtAddr, size : u64, flags : PageTableFlags) -> Result<Vma, VmaError> {
+        if size == 0 {
+            return Err(VmaError::Empty);
+        }
+        if !start.is_aligned(4096u64) || size % 4096 != 0 {
+            return Err(VmaError::Unaligned);
+        }
+        if !userspace::is_user_range(start, size) {
+            return Err(VmaError::OutsideUserSpace);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        Ok(Vma { start, end : start + size, flags })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    pub fn contains(&self, addr : VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// The areas of one address space, keyed by start address.
+#[derive(Debug, Clone, Default)]
+pub struct VmaSet {
+    areas : BTreeMap<u64, Vma>,
+}
+
+impl VmaSet {
+    pub fn new() -> VmaSet {
+        VmaSet { areas : BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, vma : Vma) -> Result<(), VmaError> {
+        let before = self.areas.range(..vma.end.as_u64()).next_back();
+        if before.map_or(false, |(_, other)| other.end > vma.start) {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// Removes the area starting exactly at `start`.
+    pub fn remove(&mut self, start : VirtAddr) -> Option<Vma> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    pub fn find(&self, addr : VirtAddr) -> Option<&Vma> {
+        self.areas.range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Whether `[start, start + len)` lies in areas that all have at least
+    /// `flags`.
+    pub fn covers(&self, start : VirtAddr, len : u64, flags : PageTableFlags) -> bool {
+        let Some(end) = start.as_u64().checked_add(len) else { return false };
+        let mut addr = start.as_u64();
+        while addr < end {
+            match VirtAddr::try_new(addr).ok().and_then(|addr| self.find(addr)) {
+                Some(vma) if vma.flags.contains(flags) => addr = vma.end.as_u64(),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
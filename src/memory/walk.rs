@@ -0,0 +1,222 @@
+use core::fmt;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags, PageTableIndex, PhysFrame},
+    PhysAddr,
+    VirtAddr,
+};
+use super::COPY_ON_WRITE;
+
+/// One entry visited by a page table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// 4 for the level-4 table down to 1.
+    pub level : u8,
+    pub index : PageTableIndex,
+    /// The table the entry lives in.
+    pub table : PhysAddr,
+    /// The next table, or the frame if the entry maps a page.
+    pub addr : PhysAddr,
+    pub flags : PageTableFlags,
+}
+
+/// The entries the CPU looks at to translate `addr`, from the level-4 table
+/// down to the one that maps the page or is not present.
+#[derive(Debug, Clone, Copy)]
+pub struct Walk {
+    pub addr : VirtAddr,
+    steps : [Option<WalkStep>; 4],
+    phys : Option<PhysAddr>,
+    page_size : u64,
+}
+
+impl Walk {
+    pub fn steps(&self) -> impl Iterator<Item = &WalkStep> {
+        self.steps.iter().flatten()
+    }
+
+    /// The physical address `addr` maps to.
+    pub fn phys(&self) -> Option<PhysAddr> {
+        self.phys
+    }
+
+    /// 4 KiB, 2 MiB or 1 GiB if `addr` is mapped.
+    pub fn page_size(&self) -> Option<u64> {
+        self.phys.map(|_| self.page_size)
+    }
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "walk of {:?}:", self.addr)?;
+        for step in self.steps() {
+            writeln!(f, "  P{}[{:3}] in {:#x}: {:#x} {:?}",
+                     step.level, u16::from(step.index), step.table, step.addr, step.flags)?;
+        }
+        match self.phys {
+            Some(phys) => write!(f, "  -> {:#x} ({} KiB page)", phys, self.page_size / 1024),
+            None => write!(f, "  -> not mapped"),
+        }
+    }
+}
+
+/// Walks the page table rooted at `p4_frame`.
+///
+/// # Safety
+///
+/// All of physical memory must be mapped at `physical_memory_offset`, and the
+/// tables must not change during the walk.
+pub unsafe fn walk_table(p4_frame : PhysFrame, physical_memory_offset : VirtAddr, addr : VirtAddr)
+    -> Walk
+{
+    let mut walk = Walk { addr, steps : [None; 4], phys : None, page_size : 0 };
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_addr = p4_frame.start_address();
+
+    for (i, index) in indices.into_iter().enumerate() {
+        let level = 4 - i as u8;
+        let table = table_at(physical_memory_offset, table_addr);
+        let entry = &table[index];
+        let flags = entry.flags();
+        walk.steps[i] = Some(WalkStep { level, index, table : table_addr, addr : entry.addr(), flags });
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        if maps_page(level, flags) {
+            walk.page_size = page_size(level);
+            walk.phys = Some(entry.addr() + (addr.as_u64() & (walk.page_size - 1)));
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    walk
+}
+
+/// A stretch of virtual memory that maps contiguous physical memory with the
+/// same flags.
+///
+/// `flags` are what the CPU enforces: `WRITABLE` and `USER_ACCESSIBLE` only if
+/// every level allows them, `NO_EXECUTE` if any level sets it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start : VirtAddr,
+    pub phys : PhysAddr,
+    pub len : u64,
+    pub flags : PageTableFlags,
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let end = self.start.as_u64().wrapping_add(self.len).wrapping_sub(1);
+        write!(f, "{:#018x}-{:#018x} -> {:#x} {}{}{} {}",
+               self.start.as_u64(), end, self.phys,
+               'r',
+               if self.flags.contains(PageTableFlags::WRITABLE) { 'w' } else { '-' },
+               if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+               if self.flags.contains(PageTableFlags::USER_ACCESSIBLE) { "user" } else { "kernel" })?;
+        if self.flags.contains(PageTableFlags::GLOBAL) {
+            write!(f, " global")?;
+        }
+        if self.flags.contains(PageTableFlags::NO_CACHE) {
+            write!(f, " uncached")?;
+        }
+        if self.flags.contains(COPY_ON_WRITE) {
+            write!(f, " cow")?;
+        }
+        Ok(())
+    }
+}
+
+/// Calls `f` with every mapped region of the page table rooted at `p4_frame`,
+/// in address order, with neighbouring pages merged into one range.
+///
+/// # Safety
+///
+/// Same as for `walk_table`.
+pub unsafe fn for_each_mapped_range<F>(p4_frame : PhysFrame, physical_memory_offset : VirtAddr, f : F)
+    where F : FnMut(MappedRange)
+{
+    let mut collector = Collector { offset : physical_memory_offset, current : None, f };
+    let permissive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    collector.visit(p4_frame.start_address(), 4, 0, permissive);
+    if let Some(range) = collector.current.take() {
+        (collector.f)(range);
+    }
+}
+
+struct Collector<F> {
+    offset : VirtAddr,
+    current : Option<MappedRange>,
+    f : F,
+}
+
+impl<F : FnMut(MappedRange)> Collector<F> {
+    unsafe fn visit(&mut self, table_addr : PhysAddr, level : u8, base : u64, inherited : PageTableFlags) {
+        let table = table_at(self.offset, table_addr);
+        for (i, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let virt = base | (i as u64) << (12 + 9 * (level as u64 - 1));
+            let effective = effective_flags(inherited, flags, level);
+            if maps_page(level, flags) {
+                self.push(MappedRange {
+                    start : VirtAddr::new_truncate(virt),
+                    phys : entry.addr(),
+                    len : page_size(level),
+                    flags : effective,
+                });
+            } else {
+                self.visit(entry.addr(), level - 1, virt, effective);
+            }
+        }
+    }
+
+    fn push(&mut self, range : MappedRange) {
+        if let Some(current) = self.current.as_mut() {
+            let continues = current.start.as_u64().wrapping_add(current.len) == range.start.as_u64()
+                && current.phys + current.len == range.phys
+                && current.flags == range.flags;
+            if continues {
+                current.len += range.len;
+                return;
+            }
+        }
+        if let Some(done) = self.current.replace(range) {
+            (self.f)(done);
+        }
+    }
+}
+
+/// `entry` limited by what the tables above it allow. Accessed and dirty
+/// bits are dropped, they would split otherwise identical ranges.
+fn effective_flags(inherited : PageTableFlags, entry : PageTableFlags, level : u8) -> PageTableFlags {
+    let mut flags = entry - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+    if level > 1 {
+        flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+    for limit in [PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE] {
+        if !inherited.contains(limit) {
+            flags.remove(limit);
+        }
+    }
+    if inherited.contains(PageTableFlags::NO_EXECUTE) {
+        flags.insert(PageTableFlags::NO_EXECUTE);
+    }
+    flags
+}
+
+/// Whether an entry at `level` maps a page instead of pointing to a table.
+/// Bit 7 means `HUGE_PAGE` only in level 3 and 2 entries.
+fn maps_page(level : u8, flags : PageTableFlags) -> bool {
+    level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE))
+}
+
+fn page_size(level : u8) -> u64 {
+    4096 << (9 * (level as u64 - 1))
+}
+
+unsafe fn table_at(physical_memory_offset : VirtAddr, table : PhysAddr) -> &'static PageTable {
+    &*(physical_memory_offset + table.as_u64()).as_ptr()
+}
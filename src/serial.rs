@@ -1,27 +1,91 @@
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::Stream;
 use uart_16550::SerialPort;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use x86_64::instructions::port::PortReadOnly;
+use crate::println;
+use crate::sync::IrqSafeSpinlock;
+use crate::task::sync::mpsc;
 
+const COM1 : u16 = 0x3F8;
+const LINE_STATUS : u16 = COM1 + 5;
+const LINE_STATUS_DATA_READY : u8 = 1;
+
+/// Received bytes kept until `ByteStream` reads them.
+const RECEIVE_QUEUE_SIZE : usize = 256;
+
+static RECEIVED : OnceCell<mpsc::Sender<u8>> = OnceCell::uninit();
 
 lazy_static! {
-    pub static ref SERIAL1 : Mutex<SerialPort>  = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1 : IrqSafeSpinlock<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeSpinlock::new(serial_port)
     };
 }
 
+/// Called by the COM1 interrupt handler: drains the UART, since it raises
+/// no further interrupt while data is left in it. Bytes arriving before
+/// there is a `ByteStream` are dropped.
+///
+/// Must not block or allocate.
+pub(crate) fn receive_pending() {
+    loop {
+        let byte = {
+            let mut port = SERIAL1.lock();
+            let mut line_status = PortReadOnly::<u8>::new(LINE_STATUS);
+            if unsafe { line_status.read() } & LINE_STATUS_DATA_READY == 0 {
+                break;
+            }
+            port.receive()
+        };
+        if let Ok(sender) = RECEIVED.try_get() {
+            if let Err(mpsc::TrySendError::Full(_)) = sender.try_send(byte) {
+                println!("WARNING: serial receive queue full; dropping input");
+            }
+        }
+    }
+}
+
+/// Bytes received on COM1.
+pub struct ByteStream {
+    receiver : mpsc::Receiver<u8>,
+}
+
+impl ByteStream {
+    /// Can only be called once; until then received bytes are dropped.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(RECEIVE_QUEUE_SIZE);
+        RECEIVED
+            .try_init_once(|| sender)
+            .expect("ByteStream::new should only be called once");
+        ByteStream { receiver }
+    }
+}
+
+impl Default for ByteStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ByteStream {
+    type Item = u8;
+
+    fn poll_next(self : Pin<&mut Self>, cx : &mut Context) -> Poll<Option<u8>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| {
-        SERIAL1
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
-    });
+
+    SERIAL1
+    .lock()
+    .write_fmt(args)
+    .expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
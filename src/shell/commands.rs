@@ -0,0 +1,124 @@
+use alloc::{format, string::String, vec::Vec};
+use x86_64::VirtAddr;
+use crate::{allocator, memory, task, thread, time};
+use super::Console;
+
+struct Command {
+    name : &'static str,
+    usage : &'static str,
+    help : &'static str,
+    run : fn(&[&str], &mut dyn Console),
+}
+
+const COMMANDS : &[Command] = &[
+    Command { name : "help", usage : "help", help : "list the commands", run : help },
+    Command { name : "clear", usage : "clear", help : "clear the screen", run : clear },
+    Command { name : "mem", usage : "mem", help : "heap and physical frame usage", run : mem },
+    Command { name : "uptime", usage : "uptime", help : "time since boot", run : uptime },
+    Command { name : "tasks", usage : "tasks", help : "list threads and async tasks", run : tasks },
+    Command {
+        name : "translate",
+        usage : "translate <addr>",
+        help : "walk the page table for a virtual address",
+        run : translate,
+    },
+    Command { name : "reboot", usage : "reboot", help : "restart the machine", run : reboot },
+    Command { name : "panic", usage : "panic", help : "panic the kernel", run : panic },
+];
+
+/// Runs one command line, writing its output to `console`.
+pub fn execute(line : &str, console : &mut dyn Console) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else { return };
+    let args : Vec<&str> = words.collect();
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(&args, console),
+        None => {
+            let _ = writeln!(console, "unknown command `{}`; try `help`", name);
+        }
+    }
+}
+
+fn help(_ : &[&str], console : &mut dyn Console) {
+    for command in COMMANDS {
+        let _ = writeln!(console, "{:<18}{}", command.usage, command.help);
+    }
+}
+
+fn clear(_ : &[&str], console : &mut dyn Console) {
+    console.clear();
+}
+
+fn mem(_ : &[&str], console : &mut dyn Console) {
+    let heap = allocator::heap_stats();
+    let _ = writeln!(console, "heap: {} of {} bytes used, {} free, peak {}, limit {}",
+                     heap.used, heap.size, heap.free, heap.high_water_mark, heap.max_size);
+    let frames = memory::with_frame_allocator(|frame_allocator| frame_allocator.stats());
+    let _ = writeln!(console, "frames: {} of {} used, {} free ({} KiB total)",
+                     frames.used_frames, frames.total_frames, frames.free_frames,
+                     frames.total_bytes() / 1024);
+}
+
+fn uptime(_ : &[&str], console : &mut dyn Console) {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    let _ = writeln!(console, "up {}:{:02}:{:02}.{:03}, {} ticks at {} Hz",
+                     seconds / 3600, seconds / 60 % 60, seconds % 60, uptime.subsec_millis(),
+                     time::ticks(), time::frequency());
+}
+
+fn tasks(_ : &[&str], console : &mut dyn Console) {
+    let _ = writeln!(console, "thread  state          cpu");
+    for thread in thread::threads() {
+        let cpu = thread.cpu.map_or(String::from("-"), |cpu| format!("{}", cpu));
+        let state = format!("{:?}", thread.state);
+        let kind = match (thread.idle, thread.user) {
+            (true, _) => " idle",
+            (false, true) => " user",
+            (false, false) => "",
+        };
+        let _ = writeln!(console, "{:<8}{:<15}{}{}", thread.id.as_u64(), state, cpu, kind);
+    }
+
+    let tasks = task::executor::tasks();
+    let _ = write!(console, "{} async tasks:", tasks.len());
+    for task in tasks {
+        let _ = write!(console, " {}", task.as_u64());
+    }
+    let _ = writeln!(console);
+}
+
+fn translate(args : &[&str], console : &mut dyn Console) {
+    let &[addr] = args else {
+        let _ = writeln!(console, "usage: translate <addr>");
+        return;
+    };
+    match parse_address(addr) {
+        Some(addr) => {
+            let _ = writeln!(console, "{}", memory::walk(addr));
+        }
+        None => {
+            let _ = writeln!(console, "`{}` is not a canonical virtual address", addr);
+        }
+    }
+}
+
+fn reboot(_ : &[&str], console : &mut dyn Console) {
+    let _ = writeln!(console, "rebooting");
+    crate::reboot();
+}
+
+fn panic(_ : &[&str], _ : &mut dyn Console) {
+    panic!("panic command");
+}
+
+/// Hexadecimal with a `0x` prefix, decimal otherwise. `_` may separate
+/// digits.
+fn parse_address(s : &str) -> Option<VirtAddr> {
+    let digits : String = s.chars().filter(|&c| c != '_').collect();
+    let addr = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    VirtAddr::try_new(addr.ok()?).ok()
+}
@@ -0,0 +1,156 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use super::Key;
+
+/// Entered lines kept for the up and down keys.
+const HISTORY_SIZE : usize = 32;
+
+/// The line being typed at the prompt, with a cursor and a history of the
+/// lines entered before. It only keeps the text; the caller shows it.
+pub struct LineEditor {
+    /// Printable ASCII only, so byte and character positions agree.
+    line : String,
+    cursor : usize,
+    max_len : usize,
+    history : VecDeque<String>,
+    /// Position in `history` while browsing it, 0 being the newest entry.
+    browsing : Option<usize>,
+    /// The line as it was before browsing started, restored by going past
+    /// the newest entry.
+    draft : String,
+}
+
+/// What a key did to the line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// The line or cursor changed and has to be shown again.
+    Changed,
+    /// Nothing happened, e.g. backspace at the start of the line.
+    Unchanged,
+    /// Enter was pressed; this is the finished line.
+    Submitted(String),
+}
+
+impl LineEditor {
+    /// An empty editor for lines of at most `max_len` characters.
+    pub fn new(max_len : usize) -> LineEditor {
+        LineEditor {
+            line : String::new(),
+            cursor : 0,
+            max_len,
+            history : VecDeque::with_capacity(HISTORY_SIZE),
+            browsing : None,
+            draft : String::new(),
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Cursor position in characters from the start of the line.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().rev().map(String::as_str)
+    }
+
+    pub fn handle_key(&mut self, key : Key) -> Edit {
+        let changed = match key {
+            Key::Char(c) => self.insert(c),
+            Key::Backspace => self.cursor > 0 && {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                true
+            },
+            Key::Delete => self.cursor < self.line.len() && {
+                self.line.remove(self.cursor);
+                true
+            },
+            Key::Left => self.cursor > 0 && {
+                self.cursor -= 1;
+                true
+            },
+            Key::Right => self.cursor < self.line.len() && {
+                self.cursor += 1;
+                true
+            },
+            Key::Home => self.move_to(0),
+            Key::End => self.move_to(self.line.len()),
+            Key::Up => self.browse_older(),
+            Key::Down => self.browse_newer(),
+            Key::Enter => return Edit::Submitted(self.submit()),
+        };
+        match changed {
+            true => Edit::Changed,
+            false => Edit::Unchanged,
+        }
+    }
+
+    fn insert(&mut self, c : char) -> bool {
+        if !(c == ' ' || c.is_ascii_graphic()) || self.line.len() == self.max_len {
+            return false;
+        }
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+        true
+    }
+
+    fn move_to(&mut self, cursor : usize) -> bool {
+        let moved = self.cursor != cursor;
+        self.cursor = cursor;
+        moved
+    }
+
+    fn browse_older(&mut self) -> bool {
+        let index = match self.browsing {
+            None if self.history.is_empty() => return false,
+            None => {
+                self.draft = core::mem::take(&mut self.line);
+                0
+            }
+            Some(index) if index + 1 == self.history.len() => return false,
+            Some(index) => index + 1,
+        };
+        self.show_history(Some(index));
+        true
+    }
+
+    fn browse_newer(&mut self) -> bool {
+        match self.browsing {
+            None => return false,
+            Some(0) => self.show_history(None),
+            Some(index) => self.show_history(Some(index - 1)),
+        }
+        true
+    }
+
+    fn show_history(&mut self, browsing : Option<usize>) {
+        self.browsing = browsing;
+        self.line = match browsing {
+            Some(index) => self.history[self.history.len() - 1 - index].clone(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.cursor = self.line.len();
+    }
+
+    /// Ends the line, remembering it unless it is blank or repeats the
+    /// previous one.
+    fn submit(&mut self) -> String {
+        let line = core::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && self.history.back().map(String::as_str) != Some(trimmed) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(String::from(trimmed));
+        }
+        line
+    }
+}
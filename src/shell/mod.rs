@@ -0,0 +1,127 @@
+use core::fmt::{self, Write};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::serial::ByteStream;
+use crate::task::keyboard::KeyStream;
+use crate::vga_buffer::{self, WRITER};
+
+mod commands;
+mod line_editor;
+mod serial;
+
+pub use commands::execute;
+pub use line_editor::{Edit, LineEditor};
+pub use serial::{KeyDecoder, SerialConsole};
+
+pub const PROMPT : &str = "> ";
+
+/// A key press, as far as editing a line is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Enter,
+}
+
+/// Where the shell shows its prompt and output.
+pub trait Console : Write {
+    /// Replaces the line the output is on with `prompt` and `line`, and
+    /// puts the cursor `cursor` characters into `line`.
+    fn show_line(&mut self, prompt : &str, line : &str, cursor : usize);
+
+    /// Blanks the screen.
+    fn clear(&mut self);
+
+    /// Characters that fit on a line.
+    fn width(&self) -> usize;
+}
+
+/// Reads lines from `keys` and runs them as commands until `keys` ends.
+pub async fn run<K, C>(mut keys : K, console : &mut C)
+    where K : Stream<Item = Key> + Unpin, C : Console
+{
+    // the line may not wrap, or `show_line` could not replace it
+    let mut editor = LineEditor::new(console.width() - PROMPT.len() - 1);
+    let _ = writeln!(console, "rustOS shell; `help` lists the commands");
+    console.show_line(PROMPT, "", 0);
+
+    while let Some(key) = keys.next().await {
+        match editor.handle_key(key) {
+            Edit::Changed => console.show_line(PROMPT, editor.line(), editor.cursor()),
+            Edit::Unchanged => {}
+            Edit::Submitted(line) => {
+                console.show_line(PROMPT, &line, line.len());
+                let _ = writeln!(console);
+                execute(&line, console);
+                console.show_line(PROMPT, "", 0);
+            }
+        }
+    }
+}
+
+/// The shell on the VGA text screen, typed into with the PS/2 keyboard.
+///
+/// Takes over the keyboard, see `KeyStream::new`.
+pub async fn run_on_vga() {
+    let keys = KeyStream::new().filter_map(|key| core::future::ready(key_from_keyboard(key)));
+    let mut console = VgaConsole;
+    WRITER.lock().enable_cursor();
+    run(core::pin::pin!(keys), &mut console).await;
+}
+
+/// The shell on a terminal attached to COM1, e.g. QEMU's `-serial stdio`.
+///
+/// Takes over serial input, see `ByteStream::new`.
+pub async fn run_on_serial() {
+    let mut decoder = KeyDecoder::new();
+    let keys = ByteStream::new().filter_map(move |byte| core::future::ready(decoder.feed(byte)));
+    run(core::pin::pin!(keys), &mut SerialConsole).await;
+}
+
+fn key_from_keyboard(key : DecodedKey) -> Option<Key> {
+    match key {
+        DecodedKey::Unicode('\n') => Some(Key::Enter),
+        DecodedKey::Unicode('\u{8}') => Some(Key::Backspace),
+        DecodedKey::Unicode('\u{7f}') => Some(Key::Delete),
+        DecodedKey::Unicode(c) => Some(Key::Char(c)),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+        DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+        DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+        DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+        DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+        DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+        DecodedKey::RawKey(_) => None,
+    }
+}
+
+/// The VGA text screen, shared with `println!`.
+pub struct VgaConsole;
+
+impl Write for VgaConsole {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        WRITER.lock().write_str(s)
+    }
+}
+
+impl Console for VgaConsole {
+    fn show_line(&mut self, prompt : &str, line : &str, cursor : usize) {
+        let mut writer = WRITER.lock();
+        writer.rewrite_raw(&[prompt, line]);
+        writer.move_cursor(prompt.len() + cursor);
+    }
+
+    fn clear(&mut self) {
+        WRITER.lock().clear_screen();
+    }
+
+    fn width(&self) -> usize {
+        vga_buffer::VGA_BUFFER_WIDTH
+    }
+}
@@ -0,0 +1,135 @@
+use core::fmt::{self, Write};
+use crate::serial::SERIAL1;
+use super::{Console, Key};
+
+/// Turns the bytes a VT100-style terminal sends into key presses.
+pub struct KeyDecoder {
+    state : State,
+    /// A `\n` right after a `\r` ends the same line.
+    after_cr : bool,
+}
+
+enum State {
+    Ground,
+    /// After ESC.
+    Escape,
+    /// In a control sequence, ESC `[`; only the first parameter is kept.
+    Csi { param : u16, more_params : bool },
+    /// After ESC `O`, which some terminals use for the cursor keys.
+    Ss3,
+}
+
+impl KeyDecoder {
+    pub fn new() -> KeyDecoder {
+        KeyDecoder { state : State::Ground, after_cr : false }
+    }
+
+    /// Takes the next byte, returning the key it completes, if any.
+    pub fn feed(&mut self, byte : u8) -> Option<Key> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.state {
+            State::Ground => match byte {
+                0x1b => {
+                    self.state = State::Escape;
+                    None
+                }
+                b'\r' => Some(Key::Enter),
+                b'\n' if after_cr => None,
+                b'\n' => Some(Key::Enter),
+                0x08 | 0x7f => Some(Key::Backspace),
+                0x20..=0x7e => Some(Key::Char(byte as char)),
+                _ => None,
+            },
+            State::Escape => {
+                self.state = match byte {
+                    b'[' => State::Csi { param : 0, more_params : false },
+                    b'O' => State::Ss3,
+                    _ => State::Ground,
+                };
+                None
+            }
+            State::Csi { param, more_params } => match byte {
+                b'0'..=b'9' if !more_params => {
+                    let param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    self.state = State::Csi { param, more_params };
+                    None
+                }
+                // parameter and intermediate bytes
+                0x20..=0x3f => {
+                    self.state = State::Csi { param, more_params : more_params || byte == b';' };
+                    None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    match byte {
+                        b'~' => match param {
+                            1 | 7 => Some(Key::Home),
+                            3 => Some(Key::Delete),
+                            4 | 8 => Some(Key::End),
+                            _ => None,
+                        },
+                        _ => cursor_key(byte),
+                    }
+                }
+            },
+            State::Ss3 => {
+                self.state = State::Ground;
+                cursor_key(byte)
+            }
+        }
+    }
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn cursor_key(final_byte : u8) -> Option<Key> {
+    match final_byte {
+        b'A' => Some(Key::Up),
+        b'B' => Some(Key::Down),
+        b'C' => Some(Key::Right),
+        b'D' => Some(Key::Left),
+        b'H' => Some(Key::Home),
+        b'F' => Some(Key::End),
+        _ => None,
+    }
+}
+
+/// A VT100-style terminal on COM1, shared with `serial_println!`.
+pub struct SerialConsole;
+
+impl Write for SerialConsole {
+    fn write_str(&mut self, s : &str) -> fmt::Result {
+        // the terminal is in raw mode, so a newline does not return
+        let mut port = SERIAL1.lock();
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                port.write_str("\r\n")?;
+            }
+            port.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Console for SerialConsole {
+    fn show_line(&mut self, prompt : &str, line : &str, cursor : usize) {
+        let mut port = SERIAL1.lock();
+        // back to the start of the line, then erase whatever is left of it
+        let _ = write!(port, "\r{}{}\x1b[K", prompt, line);
+        if cursor < line.len() {
+            let _ = write!(port, "\x1b[{}D", line.len() - cursor);
+        }
+    }
+
+    fn clear(&mut self) {
+        let _ = SERIAL1.lock().write_str("\x1b[2J\x1b[H");
+    }
+
+    fn width(&self) -> usize {
+        80
+    }
+}
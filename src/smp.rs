@@ -0,0 +1,265 @@
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::registers::control::{Cr0, Cr4};
+use x86_64::structures::paging::{FrameDeallocator, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::cpu::{self, PerCpu};
+use crate::interrupt::{self, apic::LocalApic};
+use crate::memory::{self, Backing, KernelStack};
+use crate::{acpi, gdt, syscall, thread, time};
+
+/// Where in the trampoline page `TrampolineData` goes; the code comes first.
+const DATA_OFFSET : usize = 0xf00;
+
+/// How long to wait for a started CPU to report in.
+const STARTUP_TIMEOUT : Duration = Duration::from_millis(500);
+
+/// Filled in by the boot CPU for each application processor it starts.
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt_limit : u16,
+    gdt_base : u32,
+    _padding : u16,
+    /// Null, 32-bit code based at the trampoline, flat 32-bit data and
+    /// 64-bit code.
+    gdt : [u64; 4],
+    /// Kernel level-4 table; it has to be below 4 GiB.
+    cr3 : u64,
+    stack_top : u64,
+    entry : u64,
+    argument : u64,
+}
+
+// Real-mode entry of the application processors. It is copied to a page
+// below 1 MiB, where it starts with CS:IP = page:0, and takes the CPU
+// through protected mode into long mode on the kernel page table. The page
+// is identity mapped while CPUs start, so paging can be switched on in it.
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lgdt [{data} + {gdt_limit}]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // jmp far 0x08:ap_trampoline_32, relative to the code segment base
+    ".byte 0xea",
+    ".2byte ap_trampoline_32 - ap_trampoline_start",
+    ".2byte 0x08",
+    ".code32",
+    "ap_trampoline_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "lea esp, [ebx + {data}]",
+    // PAE, the kernel page table, long mode with no-execute, paging with
+    // write protection
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [ebx + {data} + {cr3}]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 0x80010000",
+    "mov cr0, eax",
+    // the 64-bit code segment has no base, so the target is absolute
+    "mov eax, ebx",
+    // add eax, ap_trampoline_64 - ap_trampoline_start
+    ".byte 0x05",
+    ".4byte ap_trampoline_64 - ap_trampoline_start",
+    "push 0x18",
+    "push eax",
+    "retf",
+    ".code64",
+    "ap_trampoline_64:",
+    "mov ebx, ebx",
+    "mov rsp, [rbx + {data} + {stack_top}]",
+    "mov rdi, [rbx + {data} + {argument}]",
+    "mov rax, [rbx + {data} + {entry}]",
+    "xor ebp, ebp",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    ".popsection",
+    data = const DATA_OFFSET,
+    gdt_limit = const offset_of!(TrampolineData, gdt_limit),
+    cr3 = const offset_of!(TrampolineData, cr3),
+    stack_top = const offset_of!(TrampolineData, stack_top),
+    entry = const offset_of!(TrampolineData, entry),
+    argument = const offset_of!(TrampolineData, argument),
+);
+
+extern "C" {
+    static ap_trampoline_start : u8;
+    static ap_trampoline_end : u8;
+}
+
+/// Handed from the boot CPU to `ap_entry`.
+struct ApStart {
+    cpu : &'static mut PerCpu,
+    stack : KernelStack,
+}
+
+/// Control register values of the boot CPU, for the others to copy.
+static BOOT_CR0 : AtomicU64 = AtomicU64::new(0);
+static BOOT_CR4 : AtomicU64 = AtomicU64::new(0);
+
+/// Starts every enabled CPU the MADT lists, up to `cpu::MAX_CPUS`, and
+/// returns how many CPUs are online afterwards.
+///
+/// Needs `interrupt::init_apic` to have switched to the APIC, and the
+/// thread subsystem, since each new CPU joins scheduling with an idle
+/// thread of its own.
+pub fn init() -> usize {
+    if !interrupt::apic_active() || cpu::count() > 1 {
+        return cpu::count();
+    }
+    let Some(madt) = acpi::madt() else { return 1 };
+    let boot_apic_id = LocalApic.id();
+    memory::mapping::set_tlb_shootdown_hook(interrupt::shoot_down_tlbs);
+
+    let Some(trampoline) = map_trampoline() else { return 1 };
+    BOOT_CR0.store(Cr0::read_raw(), Ordering::Relaxed);
+    BOOT_CR4.store(Cr4::read_raw(), Ordering::Relaxed);
+
+    let application_processors = madt.processors.iter()
+        .filter(|processor| processor.enabled && processor.apic_id != boot_apic_id);
+    for processor in application_processors {
+        let index = cpu::count();
+        if index == cpu::MAX_CPUS {
+            break;
+        }
+        if !start(trampoline, index, processor.apic_id) {
+            crate::println!("smp: CPU with APIC id {} did not start", processor.apic_id);
+        }
+    }
+
+    unmap_trampoline(trampoline);
+    cpu::count()
+}
+
+/// Copies the trampoline into a free page below 1 MiB and identity maps it.
+fn map_trampoline() -> Option<PhysFrame> {
+    let code = unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    assert!(code.len() <= DATA_OFFSET, "AP trampoline does not fit in front of its data");
+
+    let frame = memory::with_frame_allocator(|frame_allocator| {
+        frame_allocator.allocate_frame_below(PhysAddr::new(0x10_0000))
+    })?;
+    let addr = frame.start_address();
+    let mapped = memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::map_range(mapper, frame_allocator, VirtAddr::new(addr.as_u64()),
+                          Backing::Physical(addr), 4096, PageTableFlags::WRITABLE)
+    });
+    if !matches!(mapped, Some(Ok(()))) {
+        memory::with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+        return None;
+    }
+
+    let page = (memory::physical_memory_offset() + addr.as_u64()).as_mut_ptr::<u8>();
+    unsafe { page.copy_from_nonoverlapping(code.as_ptr(), code.len()) };
+    Some(frame)
+}
+
+fn unmap_trampoline(frame : PhysFrame) {
+    let addr = frame.start_address().as_u64();
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::unmap_range(mapper, frame_allocator, VirtAddr::new(addr), 4096)
+    })
+        .expect("kernel memory not installed")
+        .expect("failed to unmap the AP trampoline");
+    memory::with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+}
+
+/// Starts one CPU with INIT-SIPI-SIPI and waits until it is online.
+fn start(trampoline : PhysFrame, index : usize, apic_id : u32) -> bool {
+    let cpu = cpu::new_cpu(index, apic_id);
+    gdt::allocate_stacks(&mut cpu.tables);
+    let stack = KernelStack::new("idle stack", thread::THREAD_STACK_SIZE);
+    let stack_top = stack.top().as_u64();
+    let argument = Box::into_raw(Box::new(ApStart { cpu, stack }));
+
+    let base = trampoline.start_address().as_u64();
+    let code32 = 0x00cf_9a00_0000_ffff | (base & 0xff_ffff) << 16 | (base >> 24) << 56;
+    let cr3 = memory::kernel_p4_frame().start_address().as_u64();
+    assert!(cr3 < 1 << 32, "kernel page table above 4 GiB");
+    let data = TrampolineData {
+        gdt_limit : 4 * 8 - 1,
+        gdt_base : (base as usize + DATA_OFFSET + offset_of!(TrampolineData, gdt)) as u32,
+        _padding : 0,
+        gdt : [0, code32, 0x00cf_9200_0000_ffff, 0x00af_9a00_0000_ffff],
+        cr3,
+        stack_top,
+        entry : ap_entry as *const () as u64,
+        argument : argument as u64,
+    };
+    let data_addr = memory::physical_memory_offset() + base + DATA_OFFSET as u64;
+    unsafe { data_addr.as_mut_ptr::<TrampolineData>().write_volatile(data) };
+
+    let local_apic = LocalApic;
+    let page = (base / 4096) as u8;
+    local_apic.send_init(apic_id);
+    thread::sleep(Duration::from_millis(10));
+    for _ in 0..2 {
+        local_apic.send_startup(apic_id, page);
+        if wait_online(index + 1) {
+            return true;
+        }
+    }
+    // the CPU never ran, so its stacks and data are lost rather than freed
+    // under its feet in case it still starts
+    false
+}
+
+fn wait_online(count : usize) -> bool {
+    let deadline = time::uptime() + STARTUP_TIMEOUT;
+    while cpu::count() < count {
+        if time::uptime() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// First Rust code on an application processor, called by the trampoline
+/// on the idle stack with interrupts disabled.
+extern "C" fn ap_entry(start : *mut ApStart) -> ! {
+    let ApStart { cpu, stack } = *unsafe { Box::from_raw(start) };
+    unsafe {
+        Cr0::write_raw(BOOT_CR0.load(Ordering::Relaxed));
+        Cr4::write_raw(BOOT_CR4.load(Ordering::Relaxed));
+
+        let tables = &mut *core::ptr::addr_of_mut!(cpu.tables);
+        cpu::activate(cpu);
+        gdt::load(tables);
+    }
+    interrupt::init();
+    syscall::init();
+    interrupt::init_ap();
+    thread::run_application_processor(stack)
+}
@@ -0,0 +1,239 @@
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use crate::cpu::MAX_CPUS;
+
+/// Rounds a lock is waited for before the wait is reported as a deadlock.
+#[cfg(debug_assertions)]
+const SPIN_LIMIT : u64 = 1 << 30;
+/// Locks one CPU can hold at once and still have their order checked.
+#[cfg(debug_assertions)]
+const MAX_HELD : usize = 16;
+/// Distinct pairs of locks whose order is remembered.
+#[cfg(debug_assertions)]
+const MAX_ORDERS : usize = 256;
+
+/// Per CPU, the locks it holds with interrupts disabled.
+#[cfg(debug_assertions)]
+static HELD : [[AtomicPtr<Holder>; MAX_HELD]; MAX_CPUS] =
+    [const { [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_HELD] }; MAX_CPUS];
+/// Pairs of lock classes seen so far, the first held while the second was
+/// taken.
+#[cfg(debug_assertions)]
+static ORDERS : [[AtomicPtr<Location<'static>>; 2]; MAX_ORDERS] =
+    [const { [const { AtomicPtr::new(core::ptr::null_mut()) }; 2] }; MAX_ORDERS];
+#[cfg(debug_assertions)]
+static ORDER_COUNT : AtomicUsize = AtomicUsize::new(0);
+/// Set by the first report, so the panic that follows is not reported too.
+#[cfg(debug_assertions)]
+static REPORTED : AtomicBool = AtomicBool::new(false);
+
+/// Who holds a lock, tracked in debug builds to turn deadlocks into panics.
+///
+/// A lock taken with interrupts disabled cannot leave its CPU before it is
+/// released. If that CPU waits for it again with interrupts disabled, as an
+/// interrupt or exception handler does, nothing is ever going to release
+/// it, and the wait is reported right away.
+///
+/// Each CPU also keeps the locks it holds with interrupts disabled, and
+/// every lock taken on top of them is remembered to come after them. Taking
+/// two locks the other way round later is reported, whether or not it
+/// deadlocks this time. Locks are told apart by where they were first
+/// taken, so all instances of a type usually count as one. Waits that are
+/// neither are reported after `SPIN_LIMIT` rounds.
+///
+/// In release builds only the holding CPU is kept, for `held_here`.
+pub(super) struct Holder {
+    /// Index + 1 of the CPU holding the lock with interrupts disabled, or 0.
+    cpu : AtomicUsize,
+    /// Where the lock was last taken.
+    #[cfg(debug_assertions)]
+    location : AtomicPtr<Location<'static>>,
+    /// Where the lock was first taken, which stands for it in `ORDERS`.
+    #[cfg(debug_assertions)]
+    class : AtomicPtr<Location<'static>>,
+}
+
+/// Counts the rounds of one wait for a lock.
+pub(super) struct Wait {
+    #[cfg(debug_assertions)]
+    spins : u64,
+}
+
+impl Holder {
+    pub const fn new() -> Holder {
+        Holder {
+            cpu : AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            location : AtomicPtr::new(core::ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            class : AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Records that the running code waited for the lock and took it at
+    /// `location`.
+    #[inline]
+    pub fn acquired(&self, location : &'static Location<'static>) {
+        self.record(location, true);
+    }
+
+    /// Like `acquired`, for a lock taken without waiting. That cannot
+    /// deadlock whatever the order, so the order is not checked.
+    #[inline]
+    pub fn try_acquired(&self, location : &'static Location<'static>) {
+        self.record(location, false);
+    }
+
+    #[inline]
+    fn record(&self, location : &'static Location<'static>, waited : bool) {
+        let cpu = match x86_64::instructions::interrupts::are_enabled() {
+            true => 0,
+            false => crate::cpu::try_id().map_or(0, |id| id + 1),
+        };
+        self.cpu.store(cpu, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        {
+            let location_ptr = location as *const _ as *mut _;
+            self.location.store(location_ptr, Ordering::Relaxed);
+            let _ = self.class.compare_exchange(core::ptr::null_mut(), location_ptr,
+                                                Ordering::Relaxed, Ordering::Relaxed);
+            if cpu != 0 && !REPORTED.load(Ordering::Relaxed) {
+                self.add_held(cpu - 1, waited);
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = (location, waited);
+    }
+
+    #[inline]
+    pub fn released(&self) {
+        let cpu = self.cpu.swap(0, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        if cpu != 0 {
+            self.remove_held(cpu - 1);
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = cpu;
+    }
+
+    /// Whether the running CPU took the lock with interrupts disabled and
+    /// has not released it yet. Only meaningful with interrupts disabled.
+    pub fn held_here(&self) -> bool {
+        crate::cpu::try_id().is_some_and(|id| self.cpu.load(Ordering::Relaxed) == id + 1)
+    }
+
+    /// Called for every round spent waiting for the lock at `lock`, which
+    /// `location` wants.
+    #[inline]
+    pub fn waiting(&self, wait : &mut Wait, lock : *const (), location : &'static Location<'static>) {
+        #[cfg(debug_assertions)]
+        {
+            if wait.spins == 0 && !x86_64::instructions::interrupts::are_enabled() && self.held_here() {
+                self.report("held by this CPU with interrupts disabled", lock, location);
+            }
+            wait.spins += 1;
+            if wait.spins == SPIN_LIMIT {
+                self.report("not released for too long", lock, location);
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = (wait, lock, location);
+    }
+
+    /// Checks this lock against the others `cpu` holds and adds it to them.
+    #[cfg(debug_assertions)]
+    fn add_held(&self, cpu : usize, waited : bool) {
+        let this = self as *const Holder as *mut Holder;
+        let class = self.class.load(Ordering::Relaxed);
+        let mut free = None;
+        for (slot, held) in HELD[cpu].iter().enumerate() {
+            let held = held.load(Ordering::Relaxed);
+            if held.is_null() {
+                free = free.or(Some(slot));
+                continue;
+            }
+            if held == this || !waited {
+                continue;
+            }
+            let held = unsafe { &*held };
+            let held_class = held.class.load(Ordering::Relaxed);
+            if held_class == class {
+                continue;
+            }
+            if order_seen(class, held_class) {
+                self.report_inversion(held);
+            }
+            add_order(held_class, class);
+        }
+        // with more locks held, the newest ones just go unchecked
+        if let Some(slot) = free {
+            HELD[cpu][slot].store(this, Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets this lock in the locks `cpu` holds. A crash report may
+    /// break it open from another CPU.
+    #[cfg(debug_assertions)]
+    fn remove_held(&self, cpu : usize) {
+        let this = self as *const Holder as *mut Holder;
+        for held in HELD[cpu].iter() {
+            if held.compare_exchange(this, core::ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                return;
+            }
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[cold]
+    fn report(&self, problem : &str, lock : *const (), location : &'static Location<'static>) -> ! {
+        REPORTED.store(true, Ordering::Relaxed);
+        let holder = self.location.load(Ordering::Relaxed);
+        match unsafe { holder.as_ref() } {
+            Some(holder) => panic!("deadlock: lock {:p} wanted at {} is {}; it was taken at {}",
+                                   lock, location, problem, holder),
+            None => panic!("deadlock: lock {:p} wanted at {} is {}", lock, location, problem),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[cold]
+    fn report_inversion(&self, held : &Holder) -> ! {
+        REPORTED.store(true, Ordering::Relaxed);
+        let location = |holder : &Holder| unsafe { &*holder.location.load(Ordering::Relaxed) };
+        panic!("deadlock: lock {:p} taken at {} while holding the lock taken at {}, the reverse \
+                of an earlier order", self, location(self), location(held));
+    }
+}
+
+#[cfg(debug_assertions)]
+fn order_seen(first : *mut Location<'static>, second : *mut Location<'static>) -> bool {
+    let count = ORDER_COUNT.load(Ordering::Acquire).min(MAX_ORDERS);
+    ORDERS[..count].iter().any(|[a, b]| {
+        a.load(Ordering::Relaxed) == first && b.load(Ordering::Relaxed) == second
+    })
+}
+
+#[cfg(debug_assertions)]
+fn add_order(first : *mut Location<'static>, second : *mut Location<'static>) {
+    if order_seen(first, second) {
+        return;
+    }
+    // once the table is full, new orders go unchecked
+    let index = ORDER_COUNT.fetch_add(1, Ordering::AcqRel);
+    if let Some([a, b]) = ORDERS.get(index) {
+        b.store(second, Ordering::Relaxed);
+        a.store(first, Ordering::Relaxed);
+    }
+}
+
+impl Wait {
+    pub fn new() -> Wait {
+        Wait {
+            #[cfg(debug_assertions)]
+            spins : 0,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+mod lockdep;
+mod rwlock;
+mod spinlock;
+mod ticket;
+
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use spinlock::{IrqSafeSpinlock, IrqSafeSpinlockGuard};
+pub use ticket::{TicketLock, TicketLockGuard};
+
+/// One round of waiting for a lock. The holder may itself be waiting for
+/// this CPU to flush its TLB, which the IPI cannot ask for while interrupts
+/// are disabled.
+fn relax() {
+    crate::memory::mapping::acknowledge_tlb_shootdown();
+    core::hint::spin_loop();
+}
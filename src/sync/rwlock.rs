@@ -0,0 +1,159 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lockdep::{Holder, Wait};
+
+const WRITER : usize = 1;
+const READER : usize = 2;
+
+/// A spinning reader-writer lock: any number of readers or one writer.
+///
+/// Readers are let in whenever no writer holds the lock, so a reader may
+/// take it again while an interrupted reader on the same CPU holds it. The
+/// price is that a steady stream of readers keeps writers waiting. Like
+/// `TicketLock` it leaves interrupts alone; only the writer is tracked by
+/// the deadlock detector.
+pub struct RwLock<T : ?Sized> {
+    /// `WRITER` if write locked, otherwise `READER` times the readers.
+    state : AtomicUsize,
+    holder : Holder,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T : ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+#[must_use]
+pub struct RwLockReadGuard<'a, T : ?Sized> {
+    lock : &'a RwLock<T>,
+}
+
+#[must_use]
+pub struct RwLockWriteGuard<'a, T : ?Sized> {
+    lock : &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value : T) -> RwLock<T> {
+        RwLock {
+            state : AtomicUsize::new(0),
+            holder : Holder::new(),
+            value : UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T : ?Sized> RwLock<T> {
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let location = Location::caller();
+        let mut wait = Wait::new();
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.holder.waiting(&mut wait, self as *const Self as *const (), location);
+            super::relax();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 {
+            return None;
+        }
+        self.state.compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(RwLockReadGuard { lock : self })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let location = Location::caller();
+        let mut wait = Wait::new();
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.holder.waiting(&mut wait, self as *const Self as *const (), location);
+            super::relax();
+        }
+        self.holder.acquired(location);
+        RwLockWriteGuard { lock : self }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        self.holder.try_acquired(Location::caller());
+        Some(RwLockWriteGuard { lock : self })
+    }
+
+    /// Number of readers holding the lock.
+    pub fn readers(&self) -> usize {
+        match self.state.load(Ordering::Relaxed) {
+            WRITER => 0,
+            state => state / READER,
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == WRITER
+    }
+}
+
+impl<T : ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T : ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.holder.released();
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_rwlock() {
+    serial_print!("test_rwlock... ");
+    let lock = RwLock::new(1);
+    {
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 2);
+        assert_eq!(lock.readers(), 2);
+        assert!(lock.try_write().is_none());
+    }
+    {
+        let mut value = lock.write();
+        *value = 5;
+        assert!(lock.is_write_locked());
+        assert!(lock.try_read().is_none());
+    }
+    assert_eq!(*lock.read(), 5);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,165 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use super::lockdep::{Holder, Wait};
+
+/// A spinlock that keeps interrupts disabled while it is held.
+///
+/// An interrupt handler can therefore never find the lock held by the code
+/// it interrupted, and the holder cannot be preempted. The interrupt flag
+/// is restored to what it was before `lock` when the guard is dropped.
+pub struct IrqSafeSpinlock<T : ?Sized> {
+    locked : AtomicBool,
+    holder : Holder,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Sync for IrqSafeSpinlock<T> {}
+unsafe impl<T : ?Sized + Send> Send for IrqSafeSpinlock<T> {}
+
+#[must_use]
+pub struct IrqSafeSpinlockGuard<'a, T : ?Sized> {
+    lock : &'a IrqSafeSpinlock<T>,
+    interrupts_were_enabled : bool,
+}
+
+impl<T> IrqSafeSpinlock<T> {
+    pub const fn new(value : T) -> IrqSafeSpinlock<T> {
+        IrqSafeSpinlock {
+            locked : AtomicBool::new(false),
+            holder : Holder::new(),
+            value : UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T : ?Sized> IrqSafeSpinlock<T> {
+    /// Disables interrupts and waits for the lock.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeSpinlockGuard<'_, T> {
+        let location = Location::caller();
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let mut wait = Wait::new();
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.holder.waiting(&mut wait, self as *const Self as *const (), location);
+            super::relax();
+        }
+        self.holder.acquired(location);
+        IrqSafeSpinlockGuard { lock : self, interrupts_were_enabled }
+    }
+
+    /// Takes the lock if it is free, leaving the interrupt flag alone if not.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeSpinlockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            if interrupts_were_enabled {
+                interrupts::enable();
+            }
+            return None;
+        }
+        self.holder.try_acquired(Location::caller());
+        Some(IrqSafeSpinlockGuard { lock : self, interrupts_were_enabled })
+    }
+
+    /// Like `lock`, but gives up if this CPU holds the lock already, as the
+    /// code an exception handler interrupted may. Waits if another CPU
+    /// holds it.
+    #[track_caller]
+    pub fn lock_unless_held_here(&self) -> Option<IrqSafeSpinlockGuard<'_, T>> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            if self.is_held_here() {
+                return None;
+            }
+            super::relax();
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Whether the running CPU holds the lock, which it cannot take again.
+    pub fn is_held_here(&self) -> bool {
+        interrupts::without_interrupts(|| self.holder.held_here())
+    }
+
+    /// Runs `f` on the value without taking the lock, for an exception
+    /// handler that interrupted its holder on this CPU.
+    ///
+    /// Unsafe because the interrupted holder may be in the middle of
+    /// changing it; nothing else may use the value meanwhile.
+    pub unsafe fn with_unchecked<R>(&self, f : impl FnOnce(&mut T) -> R) -> R {
+        f(&mut *self.value.get())
+    }
+
+    /// Releases the lock without a guard, for crash reports that have to
+    /// print no matter who holds it.
+    ///
+    /// Unsafe because the current holder keeps using the value.
+    pub unsafe fn force_unlock(&self) {
+        self.holder.released();
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T : ?Sized> Deref for IrqSafeSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for IrqSafeSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for IrqSafeSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.holder.released();
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_irq_safe_spinlock_restores_interrupt_flag() {
+    serial_print!("test_irq_safe_spinlock_restores_interrupt_flag... ");
+    let lock = IrqSafeSpinlock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(lock.lock_unless_held_here().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*lock.lock(), 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,103 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::lockdep::{Holder, Wait};
+
+/// A spinlock that hands itself out in the order it was asked for, so no
+/// CPU waits forever while others keep taking it.
+///
+/// It leaves interrupts alone: data also used by interrupt handlers has to
+/// be locked with interrupts disabled, or be in an `IrqSafeSpinlock`.
+pub struct TicketLock<T : ?Sized> {
+    next_ticket : AtomicUsize,
+    now_serving : AtomicUsize,
+    holder : Holder,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Sync for TicketLock<T> {}
+unsafe impl<T : ?Sized + Send> Send for TicketLock<T> {}
+
+#[must_use]
+pub struct TicketLockGuard<'a, T : ?Sized> {
+    lock : &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value : T) -> TicketLock<T> {
+        TicketLock {
+            next_ticket : AtomicUsize::new(0),
+            now_serving : AtomicUsize::new(0),
+            holder : Holder::new(),
+            value : UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T : ?Sized> TicketLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let location = Location::caller();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut wait = Wait::new();
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            self.holder.waiting(&mut wait, self as *const Self as *const (), location);
+            super::relax();
+        }
+        self.holder.acquired(location);
+        TicketLockGuard { lock : self }
+    }
+
+    /// Takes the lock only if nobody holds or waits for it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket.compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        self.holder.try_acquired(Location::caller());
+        Some(TicketLockGuard { lock : self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+}
+
+impl<T : ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.holder.released();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_ticket_lock() {
+    serial_print!("test_ticket_lock... ");
+    let lock = TicketLock::new(1);
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_lock().unwrap(), 2);
+    serial_println!("[ok]");
+}
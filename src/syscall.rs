@@ -0,0 +1,273 @@
+use core::arch::naked_asm;
+use core::time::Duration;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::memory::MapError;
+use crate::{cpu, gdt, print, serial_print, thread, userspace};
+
+pub const SYS_WRITE : u64 = 0;
+pub const SYS_EXIT : u64 = 1;
+pub const SYS_YIELD : u64 = 2;
+pub const SYS_SLEEP : u64 = 3;
+pub const SYS_GETPID : u64 = 4;
+pub const SYS_MMAP : u64 = 5;
+
+/// `fd` argument of `write`.
+pub const STDOUT : u64 = 1;
+pub const STDERR : u64 = 2;
+
+/// `prot` bits of `mmap`; memory is always readable.
+pub const PROT_WRITE : u64 = 1 << 1;
+pub const PROT_EXEC : u64 = 1 << 2;
+
+/// Where `mmap` places mappings when user code passes a null address.
+const MMAP_BASE : u64 = userspace::USER_SPACE_START + 0x0800_0000_0000;
+
+/// Errors are returned to user code as the negated value, like Linux does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    BadFileDescriptor = 9,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    InvalidArgument = 22,
+    NoSuchSyscall = 38,
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+type SyscallHandler = fn(&[u64; 6]) -> SyscallResult;
+
+/// Indexed by syscall number.
+static SYSCALL_TABLE : [SyscallHandler; 6] = [
+    sys_write,
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_getpid,
+    sys_mmap,
+];
+
+/// Runs syscall `number` and encodes the result the way user code sees it:
+/// the return value, or a negative `SyscallError`.
+pub fn dispatch(number : u64, args : [u64; 6]) -> i64 {
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(&args),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    match result {
+        Ok(value) => value as i64,
+        Err(error) => -(error as i64),
+    }
+}
+
+/// `write(fd, buf, len)`: stdout goes to the VGA console, stderr to serial.
+fn sys_write(args : &[u64; 6]) -> SyscallResult {
+    let (fd, buf, len) = (args[0], VirtAddr::try_new(args[1]), args[2]);
+    let buf = buf.map_err(|_| SyscallError::BadAddress)?;
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    if !userspace::is_user_accessible(buf, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(buf.as_ptr::<u8>(), len as usize) };
+    for chunk in bytes.utf8_chunks() {
+        let text = chunk.valid();
+        let replacement = if chunk.invalid().is_empty() { "" } else { "\u{fffd}" };
+        if fd == STDOUT {
+            print!("{}{}", text, replacement);
+        } else {
+            serial_print!("{}{}", text, replacement);
+        }
+    }
+    Ok(len)
+}
+
+/// `exit(code)`: ends the calling thread.
+fn sys_exit(_args : &[u64; 6]) -> SyscallResult {
+    thread::exit()
+}
+
+fn sys_yield(_args : &[u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// `sleep(milliseconds)`
+fn sys_sleep(args : &[u64; 6]) -> SyscallResult {
+    thread::sleep(Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+fn sys_getpid(_args : &[u64; 6]) -> SyscallResult {
+    thread::current_id()
+        .map(|id| id.as_u64())
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+/// `mmap(addr, len, prot)`: maps zeroed anonymous memory and returns its
+/// address. A null `addr` lets the kernel pick one.
+///
+/// Threads with an address space of their own get a lazy region, backed
+/// page by page on first access; kernel threads get the pages right away.
+fn sys_mmap(args : &[u64; 6]) -> SyscallResult {
+    static NEXT_MMAP_ADDR : AtomicU64 = AtomicU64::new(MMAP_BASE);
+
+    let (addr, len, prot) = (args[0], args[1], args[2]);
+    if len == 0 || addr % 4096 != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let len = len.checked_add(4095).ok_or(SyscallError::InvalidArgument)? / 4096 * 4096;
+    let addr = match addr {
+        0 => NEXT_MMAP_ADDR.fetch_add(len, Ordering::Relaxed),
+        addr => addr,
+    };
+    let addr = VirtAddr::try_new(addr).map_err(|_| SyscallError::InvalidArgument)?;
+
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let lazy = thread::with_current_address_space(|address_space| {
+        address_space.map_lazy(addr, len, flags)
+    });
+    match lazy {
+        Some(Ok(())) => return Ok(addr.as_u64()),
+        Some(Err(_)) => return Err(SyscallError::InvalidArgument),
+        None => {}
+    }
+    match userspace::map_user_range(addr, len, flags) {
+        Ok(()) => Ok(addr.as_u64()),
+        Err(userspace::UserMapError::Map(MapError::FrameAllocationFailed)) =>
+            Err(SyscallError::OutOfMemory),
+        Err(_) => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// User registers as pushed by `syscall_entry`, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15 : u64,
+    pub r14 : u64,
+    pub r13 : u64,
+    pub r12 : u64,
+    pub rbp : u64,
+    pub rbx : u64,
+    pub rdi : u64,
+    pub rsi : u64,
+    pub rdx : u64,
+    pub r10 : u64,
+    pub r8 : u64,
+    pub r9 : u64,
+    /// Syscall number on entry, return value on exit.
+    pub rax : u64,
+    pub rflags : u64,
+    pub rip : u64,
+    pub rsp : u64,
+}
+
+extern "C" fn syscall_handler(frame : &mut SyscallFrame) {
+    // `SFMask` cleared IF on entry; we are on the kernel stack now, so
+    // blocking syscalls may be preempted
+    interrupts::enable();
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = dispatch(frame.rax, args) as u64;
+    interrupts::disable();
+}
+
+/// Target of `syscall`: switches to the per-CPU data and the ring-0 stack
+/// from the TSS, saves the user registers as a `SyscallFrame` and returns
+/// with `sysretq`.
+///
+/// Interrupts are masked by `SFMask` until the user stack pointer is saved,
+/// so one scratch slot per CPU is enough.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
+        "push qword ptr gs:[{user_rsp}]",
+        "push rcx",
+        "push r11",
+        "push rax",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rax",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_rsp = const cpu::USER_RSP_SCRATCH_OFFSET,
+        kernel_rsp = const cpu::KERNEL_RSP_OFFSET,
+        handler = sym syscall_handler,
+    )
+}
+
+/// Enables `syscall`/`sysret` and points `LSTAR` at `syscall_entry`.
+///
+/// The MSRs are per CPU, so every CPU runs this. Needs `gdt::init` first,
+/// since the selectors go into `STAR`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    ).expect("GDT layout does not match what syscall/sysret expect");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+#[cfg(test)]
+use crate::serial_println;
+
+#[test_case]
+fn test_dispatch_rejects_bad_input() {
+    serial_print!("test_dispatch_rejects_bad_input... ");
+    assert_eq!(dispatch(0xffff, [0; 6]), -(SyscallError::NoSuchSyscall as i64));
+    // a kernel address is never a valid user buffer
+    let kernel_buf = [0u8; 4];
+    let args = [STDOUT, kernel_buf.as_ptr() as u64, 4, 0, 0, 0];
+    assert_eq!(dispatch(SYS_WRITE, args), -(SyscallError::BadAddress as i64));
+    assert_eq!(dispatch(SYS_WRITE, [7, 0, 0, 0, 0, 0]), -(SyscallError::BadFileDescriptor as i64));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,271 @@
+use super::{Task, TaskId};
+use alloc::{collections::{BTreeMap, BTreeSet}, sync::Arc, task::Wake, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use crate::println;
+use crate::sync::IrqSafeSpinlock;
+
+/// Most tasks an executor holds at once.
+pub const TASK_QUEUE_SIZE : usize = 100;
+
+/// Tasks of every executor that have not completed yet.
+static LIVE_TASKS : IrqSafeSpinlock<BTreeSet<TaskId>> = IrqSafeSpinlock::new(BTreeSet::new());
+
+/// Ids of the spawned tasks that have not completed, across all executors.
+pub fn tasks() -> Vec<TaskId> {
+    LIVE_TASKS.lock().iter().copied().collect()
+}
+
+/// Cooperative executor: tasks are polled until they return `Pending` and
+/// are only polled again once their waker pushes them back on the queue.
+///
+/// A task is in the queue at most once, and `spawn` turns tasks away
+/// beyond `TASK_QUEUE_SIZE`, so the queue cannot overflow.
+pub struct Executor {
+    tasks : BTreeMap<TaskId, Task>,
+    task_queue : Arc<ArrayQueue<TaskId>>,
+    waker_cache : BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks : BTreeMap::new(),
+            task_queue : Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache : BTreeMap::new(),
+        }
+    }
+
+    /// Hands `task` back if the executor has `TASK_QUEUE_SIZE` tasks already.
+    pub fn spawn(&mut self, task : Task) -> Result<TaskId, Task> {
+        let task_id = task.id;
+        if self.tasks.len() >= TASK_QUEUE_SIZE {
+            return Err(task);
+        }
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        LIVE_TASKS.lock().insert(task_id);
+        let task_waker = TaskWaker::new(task_id, self.task_queue.clone());
+        task_waker.wake_task();
+        self.waker_cache.insert(task_id, task_waker);
+        Ok(task_id)
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // task no longer exists
+                None => continue,
+            };
+            let task_waker = &waker_cache[&task_id];
+            // cleared first, so that a wakeup during the poll queues it again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                    LIVE_TASKS.lock().remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // interrupts are disabled for the check so that a wakeup arriving
+        // between the check and the `hlt` is not lost
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Executor {
+    /// Its tasks are dropped unfinished, so they are not live anymore.
+    fn drop(&mut self) {
+        let mut live_tasks = LIVE_TASKS.lock();
+        for task_id in self.tasks.keys() {
+            live_tasks.remove(task_id);
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id : TaskId,
+    task_queue : Arc<ArrayQueue<TaskId>>,
+    /// Whether the task is in the queue and not polled yet.
+    queued : AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id : TaskId, task_queue : Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            queued : AtomicBool::new(false),
+        })
+    }
+
+    /// Queues the task unless it is queued already. Interrupt handlers wake
+    /// tasks, so this must not panic.
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if self.task_queue.push(self.task_id).is_err() {
+            self.queued.store(false, Ordering::Release);
+            println!("WARNING: task queue full; dropping wakeup of task {}", self.task_id.as_u64());
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self : Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self : &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_spawned_task_runs_to_completion() {
+    serial_print!("test_spawned_task_runs_to_completion... ");
+    let ran = Arc::new(AtomicBool::new(false));
+    let mut executor = Executor::new();
+    let task = Task::new({
+        let ran = ran.clone();
+        async move { ran.store(true, Ordering::Relaxed) }
+    });
+    let task_id = executor.spawn(task).ok().unwrap();
+    assert!(tasks().contains(&task_id));
+
+    executor.run_ready_tasks();
+    assert!(ran.load(Ordering::Relaxed));
+    assert!(executor.tasks.is_empty() && executor.waker_cache.is_empty());
+    assert!(!tasks().contains(&task_id));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_task_woken_twice_is_queued_once() {
+    serial_print!("test_task_woken_twice_is_queued_once... ");
+    let polls = Arc::new(core::sync::atomic::AtomicUsize::new(0));
+    let waker = Arc::new(IrqSafeSpinlock::new(None::<Waker>));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new({
+        let (polls, waker) = (polls.clone(), waker.clone());
+        core::future::poll_fn(move |context| {
+            match polls.fetch_add(1, Ordering::Relaxed) {
+                0 => {
+                    *waker.lock() = Some(context.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            }
+        })
+    })).ok().unwrap();
+    executor.run_ready_tasks();
+    assert_eq!(polls.load(Ordering::Relaxed), 1);
+    assert!(executor.task_queue.is_empty());
+
+    let waker = waker.lock().take().unwrap();
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    assert_eq!(executor.task_queue.len(), 1);
+    executor.run_ready_tasks();
+    assert_eq!(polls.load(Ordering::Relaxed), 2);
+
+    // the task is gone, so a late wakeup is dropped when it is popped
+    waker.wake();
+    executor.run_ready_tasks();
+    assert!(executor.task_queue.is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_task_waking_itself_is_polled_again() {
+    serial_print!("test_task_waking_itself_is_polled_again... ");
+    let polls = Arc::new(core::sync::atomic::AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new({
+        let polls = polls.clone();
+        core::future::poll_fn(move |context| {
+            match polls.fetch_add(1, Ordering::Relaxed) {
+                0 => {
+                    context.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            }
+        })
+    })).ok().unwrap();
+    executor.run_ready_tasks();
+    assert_eq!(polls.load(Ordering::Relaxed), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_spawn_hands_back_task_when_full() {
+    serial_print!("test_spawn_hands_back_task_when_full... ");
+    let mut executor = Executor::new();
+    for _ in 0..TASK_QUEUE_SIZE {
+        assert!(executor.spawn(Task::new(core::future::pending())).is_ok());
+    }
+    let task = Task::new(async {});
+    let task_id = task.id();
+    match executor.spawn(task) {
+        Err(task) => assert_eq!(task.id(), task_id),
+        Ok(_) => panic!("spawned more tasks than the queue holds"),
+    }
+    assert!(!tasks().contains(&task_id));
+
+    let pending : Vec<TaskId> = executor.tasks.keys().copied().collect();
+    drop(executor);
+    assert!(pending.iter().all(|task_id| !tasks().contains(task_id)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_idle_executor_sleeps_until_interrupt() {
+    serial_print!("test_idle_executor_sleeps_until_interrupt... ");
+    let executor = Executor::new();
+    let ticks = crate::time::ticks();
+    executor.sleep_if_idle();
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    assert!(crate::time::ticks() > ticks);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,186 @@
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, task::{Context, Poll}};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::{Stream, StreamExt}, task::AtomicWaker};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use crate::println;
+
+const SCANCODE_QUEUE_SIZE : usize = 100;
+
+static SCANCODE_QUEUE : OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER : AtomicWaker = AtomicWaker::new();
+
+/// Called by the keyboard interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn add_scancode(scancode : u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_err() {
+            println!("WARNING: scancode queue full; dropping keyboard input");
+        } else {
+            WAKER.wake();
+        }
+    } else {
+        println!("WARNING: scancode queue uninitialized");
+    }
+}
+
+pub struct ScancodeStream {
+    _private : (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        Self::try_new().expect("ScancodeStream::new should only be called once")
+    }
+
+    /// Like `new`, but `None` if the scancode queue has been taken already.
+    fn try_new() -> Option<Self> {
+        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE)).ok()?;
+        Some(ScancodeStream { _private : () })
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self : Pin<&mut Self>, cx : &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+
+        // fast path
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Scancodes decoded into key presses with the US layout.
+pub struct KeyStream {
+    scancodes : ScancodeStream,
+    keyboard : Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl KeyStream {
+    /// Takes over the scancode queue, so like `ScancodeStream::new` it can
+    /// only be called once.
+    pub fn new() -> Self {
+        KeyStream {
+            scancodes : ScancodeStream::new(),
+            keyboard : Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self : Pin<&mut Self>, cx : &mut Context) -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        loop {
+            let scancode = match this.scancodes.poll_next_unpin(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(Some(key_event)) = this.keyboard.add_byte(scancode) {
+                if let Some(key) = this.keyboard.process_keyevent(key_event) {
+                    return Poll::Ready(Some(key));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+#[cfg(test)]
+use alloc::{sync::Arc, task::Wake};
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use core::task::Waker;
+
+/// The one stream there can be, shared by the tests.
+#[cfg(test)]
+fn test_stream() -> ScancodeStream {
+    let _ = ScancodeStream::try_new();
+    ScancodeStream { _private : () }
+}
+
+#[cfg(test)]
+struct WokenFlag(AtomicBool);
+
+#[cfg(test)]
+impl Wake for WokenFlag {
+    fn wake(self : Arc<Self>) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_scancode_stream_is_created_once() {
+    serial_print!("test_scancode_stream_is_created_once... ");
+    let _stream = test_stream();
+    assert!(ScancodeStream::try_new().is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_scancode_wakes_stream() {
+    serial_print!("test_scancode_wakes_stream... ");
+    let mut stream = test_stream();
+    let woken = Arc::new(WokenFlag(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+
+    assert_eq!(stream.poll_next_unpin(&mut context), Poll::Pending);
+    assert!(!woken.0.load(Ordering::Relaxed));
+    add_scancode(0x1e);
+    assert!(woken.0.load(Ordering::Relaxed));
+    assert_eq!(stream.poll_next_unpin(&mut context), Poll::Ready(Some(0x1e)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_full_scancode_queue_drops_and_warns() {
+    serial_print!("test_full_scancode_queue_drops_and_warns... ");
+    let mut stream = test_stream();
+    let waker = Waker::from(Arc::new(WokenFlag(AtomicBool::new(false))));
+    let mut context = Context::from_waker(&waker);
+
+    for scancode in 0..SCANCODE_QUEUE_SIZE as u8 + 5 {
+        add_scancode(scancode);
+    }
+    let warning = crate::vga_buffer::row_text(crate::vga_buffer::VGA_BUFFER_HEIGHT - 2);
+    assert!(warning.starts_with("WARNING: scancode queue full"));
+
+    // the oldest input is kept
+    for scancode in 0..SCANCODE_QUEUE_SIZE as u8 {
+        assert_eq!(stream.poll_next_unpin(&mut context), Poll::Ready(Some(scancode)));
+    }
+    assert_eq!(stream.poll_next_unpin(&mut context), Poll::Pending);
+    serial_println!("[ok]");
+}
@@ -1,31 +1,82 @@
-use core::{future::{self, Future}, pin::Pin};
-use alloc::boxed::Box;
+use core::{future::Future, pin::Pin};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+
+pub mod executor;
+pub mod keyboard;
+pub mod sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID : AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
 
 pub struct Task {
-    future : Pin<Box<dyn future<output = ()>>>,
+    id : TaskId,
+    future : Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future : impl future<output = ()> + 'static) -> Task {
+    pub fn new(future : impl Future<Output = ()> + 'static) -> Task {
         Task {
+            id : TaskId::new(),
             future : Box::pin(future),
         }
     }
-}
-
-
-
-
-
-
-
-
-
-
-
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
 
+    fn poll(&mut self, context : &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
 
+/// Polls `future` on the calling thread until it completes, halting the
+/// CPU while it waits, for code outside of an `Executor`.
+pub fn block_on<F : Future>(future : F) -> F::Output {
+    use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+    let woken = Arc::new(WokenFlag(AtomicBool::new(true)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = core::pin::pin!(future);
+    loop {
+        if woken.0.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+        // as in `Executor::sleep_if_idle`, a wakeup right before the `hlt`
+        // must not be lost
+        interrupts::disable();
+        if woken.0.load(Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            enable_and_hlt();
+        }
+    }
+}
 
+struct WokenFlag(AtomicBool);
 
+impl Wake for WokenFlag {
+    fn wake(self : Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
 
+    fn wake_by_ref(self : &Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
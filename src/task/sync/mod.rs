@@ -0,0 +1,10 @@
+pub mod mpsc;
+mod mutex;
+mod notify;
+mod semaphore;
+mod wait_queue;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use wait_queue::{Wait, WaitQueue, WaitUntil};
@@ -0,0 +1,190 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use crate::sync::IrqSafeSpinlock;
+use super::WaitQueue;
+
+/// A bounded queue from any number of `Sender`s to one `Receiver`.
+///
+/// The buffer is allocated up front, so `Sender::try_send` neither blocks
+/// nor allocates and works in interrupt handlers.
+pub fn channel<T>(capacity : usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let channel = Arc::new(Channel {
+        buffer : IrqSafeSpinlock::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        senders : AtomicUsize::new(1),
+        receiver_alive : AtomicBool::new(true),
+        receiver_waker : AtomicWaker::new(),
+        not_full : WaitQueue::new(),
+    });
+    (Sender { channel : channel.clone() }, Receiver { channel })
+}
+
+struct Channel<T> {
+    buffer : IrqSafeSpinlock<VecDeque<T>>,
+    capacity : usize,
+    senders : AtomicUsize,
+    receiver_alive : AtomicBool,
+    /// The receiver, waiting for a value or for the senders to go away.
+    receiver_waker : AtomicWaker,
+    /// Senders waiting for space or for the receiver to go away.
+    not_full : WaitQueue,
+}
+
+pub struct Sender<T> {
+    channel : Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel : Arc<Channel<T>>,
+}
+
+/// The receiver is gone; the value is handed back.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Empty, and every sender is gone.
+    Disconnected,
+}
+
+impl<T> Sender<T> {
+    /// Waits for space and sends `value`, or returns it if the receiver is
+    /// gone.
+    pub async fn send(&self, value : T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        self.channel.not_full.wait_until(|| {
+            match self.try_send(value.take().expect("value already sent")) {
+                Ok(()) => Some(Ok(())),
+                Err(TrySendError::Closed(value)) => Some(Err(SendError(value))),
+                Err(TrySendError::Full(rejected)) => {
+                    value = Some(rejected);
+                    None
+                }
+            }
+        }).await
+    }
+
+    pub fn try_send(&self, value : T) -> Result<(), TrySendError<T>> {
+        if !self.channel.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        {
+            let mut buffer = self.channel.buffer.lock();
+            if buffer.len() == self.channel.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            buffer.push_back(value);
+        }
+        self.channel.receiver_waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.channel.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { channel : self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.receiver_waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value. `None` once the channel is empty and every
+    /// sender is gone.
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx : &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.channel.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        // checked first, so that a value sent just before the last sender
+        // went away is still received
+        let disconnected = self.channel.senders.load(Ordering::Acquire) == 0;
+        let value = self.channel.buffer.lock().pop_front();
+        match value {
+            Some(value) => {
+                self.channel.not_full.wake_one();
+                Ok(value)
+            }
+            None if disconnected => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.buffer.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_alive.store(false, Ordering::Release);
+        self.channel.not_full.wake_all();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self : Pin<&mut Self>, cx : &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::WaitQueue;
+
+/// A lock for tasks: waiting for it yields to the executor instead of
+/// spinning, so it may be held across `.await`.
+pub struct Mutex<T : ?Sized> {
+    locked : AtomicBool,
+    waiters : WaitQueue,
+    value : UnsafeCell<T>,
+}
+
+unsafe impl<T : ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T : ?Sized + Send> Sync for Mutex<T> {}
+
+#[must_use]
+pub struct MutexGuard<'a, T : ?Sized> {
+    mutex : &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value : T) -> Mutex<T> {
+        Mutex {
+            locked : AtomicBool::new(false),
+            waiters : WaitQueue::new(),
+            value : UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T : ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_lock()).await
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(MutexGuard { mutex : self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T : ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T : ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
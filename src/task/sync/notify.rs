@@ -0,0 +1,54 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use super::WaitQueue;
+
+/// Wakes tasks without passing data.
+///
+/// `notify_one` with no task waiting stores a permit, so the next
+/// `notified` completes right away and no signal is lost between a task
+/// checking for work and waiting. Both notify functions are safe to call
+/// from interrupt handlers.
+pub struct Notify {
+    permit : AtomicBool,
+    /// Counts `notify_waiters` calls, which wake the tasks waiting then.
+    generation : AtomicU64,
+    waiters : WaitQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Notify {
+        Notify {
+            permit : AtomicBool::new(false),
+            generation : AtomicU64::new(0),
+            waiters : WaitQueue::new(),
+        }
+    }
+
+    /// Completes on the next `notify_one`, or right away if a permit is
+    /// stored, or on a `notify_waiters` after the first poll.
+    pub async fn notified(&self) {
+        let generation = self.generation.load(Ordering::Acquire);
+        self.waiters.wait_until(|| {
+            let notified = self.permit.swap(false, Ordering::AcqRel)
+                || self.generation.load(Ordering::Acquire) != generation;
+            notified.then_some(())
+        }).await
+    }
+
+    /// Wakes one waiting task, or stores a permit for the next.
+    pub fn notify_one(&self) {
+        self.permit.store(true, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes all tasks waiting now. Stores no permit.
+    pub fn notify_waiters(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+/// A count of permits that tasks wait for.
+///
+/// `add_permits` never blocks or allocates, so an interrupt handler can
+/// count events that a task then takes one at a time.
+pub struct Semaphore {
+    permits : AtomicUsize,
+    waiters : WaitQueue,
+}
+
+/// Gives its permit back when dropped, unless `forget` is called.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore : &'a Semaphore,
+}
+
+impl Semaphore {
+    pub const fn new(permits : usize) -> Semaphore {
+        Semaphore {
+            permits : AtomicUsize::new(permits),
+            waiters : WaitQueue::new(),
+        }
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.waiters.wait_until(|| self.try_acquire()).await
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .ok()?;
+        Some(SemaphorePermit { semaphore : self })
+    }
+
+    pub fn add_permits(&self, count : usize) {
+        self.permits.fetch_add(count, Ordering::Release);
+        for _ in 0..count {
+            if !self.waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken for good.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
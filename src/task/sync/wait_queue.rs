@@ -0,0 +1,185 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crate::sync::IrqSafeSpinlock;
+
+/// Tasks waiting for something, woken by whoever provides it.
+///
+/// Waking takes an `IrqSafeSpinlock` and never allocates, so interrupt
+/// handlers may call `wake_one` and `wake_all`. The usual pattern is an
+/// interrupt handler pushing to a lock-free queue and waking, and a task
+/// waiting with `wait_until` for something to pop.
+pub struct WaitQueue {
+    waiters : IrqSafeSpinlock<VecDeque<(u64, Waker)>>,
+    next_key : AtomicU64,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters : IrqSafeSpinlock::new(VecDeque::new()),
+            next_key : AtomicU64::new(0),
+        }
+    }
+
+    /// Wakes the task that has waited longest. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        match waiter {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting task and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    /// Completes once the queue is woken after the first poll. A wakeup that
+    /// comes earlier is missed, so most callers want `wait_until`.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { waiter : Waiter::new(self) }
+    }
+
+    /// Completes with the first `Some` that `condition` returns. It is
+    /// tried on every poll and again after registering, so a wakeup between
+    /// a failed try and the registration is not lost.
+    pub fn wait_until<F, R>(&self, condition : F) -> WaitUntil<'_, F>
+        where F : FnMut() -> Option<R>
+    {
+        WaitUntil { waiter : Waiter::new(self), condition }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One entry a future keeps in a `WaitQueue`.
+struct Waiter<'a> {
+    queue : &'a WaitQueue,
+    key : Option<u64>,
+    done : bool,
+}
+
+impl<'a> Waiter<'a> {
+    fn new(queue : &'a WaitQueue) -> Waiter<'a> {
+        Waiter { queue, key : None, done : false }
+    }
+
+    /// Queues the waker, or refreshes it if still queued.
+    fn register(&mut self, waker : &Waker) {
+        let mut waiters = self.queue.waiters.lock();
+        if let Some(key) = self.key {
+            if let Some((_, queued)) = waiters.iter_mut().find(|(queued_key, _)| *queued_key == key) {
+                if !queued.will_wake(waker) {
+                    *queued = waker.clone();
+                }
+                return;
+            }
+        }
+        let key = self.queue.next_key.fetch_add(1, Ordering::Relaxed);
+        waiters.push_back((key, waker.clone()));
+        self.key = Some(key);
+    }
+
+    /// Whether the entry was queued and has been woken since.
+    fn is_woken(&self) -> bool {
+        let Some(key) = self.key else { return false };
+        !self.queue.waiters.lock().iter().any(|(queued_key, _)| *queued_key == key)
+    }
+
+    /// Leaves the queue. Returns whether the entry had been woken already.
+    fn remove(&mut self) -> bool {
+        let Some(key) = self.key.take() else { return false };
+        let mut waiters = self.queue.waiters.lock();
+        match waiters.iter().position(|(queued_key, _)| *queued_key == key) {
+            Some(index) => {
+                waiters.remove(index);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.remove();
+        self.done = true;
+    }
+}
+
+impl Drop for Waiter<'_> {
+    /// A future dropped after being woken but before using the wakeup hands
+    /// it on, so that another waiter is not left behind.
+    fn drop(&mut self) {
+        if !self.done && self.remove() {
+            self.queue.wake_one();
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct Wait<'a> {
+    waiter : Waiter<'a>,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, cx : &mut Context) -> Poll<()> {
+        let waiter = &mut self.waiter;
+        if waiter.is_woken() {
+            waiter.finish();
+            return Poll::Ready(());
+        }
+        waiter.register(cx.waker());
+        Poll::Pending
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct WaitUntil<'a, F> {
+    waiter : Waiter<'a>,
+    condition : F,
+}
+
+// the condition is never pinned
+impl<F> Unpin for WaitUntil<'_, F> {}
+
+impl<F, R> Future for WaitUntil<'_, F>
+    where F : FnMut() -> Option<R>
+{
+    type Output = R;
+
+    fn poll(self : Pin<&mut Self>, cx : &mut Context) -> Poll<R> {
+        let this = self.get_mut();
+        if let Some(result) = (this.condition)() {
+            this.waiter.finish();
+            return Poll::Ready(result);
+        }
+        this.waiter.register(cx.waker());
+        match (this.condition)() {
+            Some(result) => {
+                this.waiter.finish();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use core::arch::naked_asm;
+use x86_64::VirtAddr;
+
+/// RFLAGS for a fresh thread: reserved bit 1 set, interrupts still disabled
+/// (the entry point enables them once it is safe).
+const INITIAL_RFLAGS : u64 = 0x2;
+
+/// Saves the callee-saved registers and RFLAGS on the current stack, stores
+/// the stack pointer to `*old_rsp` and resumes the context saved at `new_rsp`.
+///
+/// Caller-saved registers are already spilled by the compiler at the call
+/// site, so this is everything a suspended thread needs.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old_rsp : *mut u64, new_rsp : u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Lays out a frame on a new stack so that the first `switch` to it
+/// "returns" into `entry`.
+///
+/// Returns the stack pointer to hand to `switch`.
+pub unsafe fn init_stack(stack_top : VirtAddr, entry : extern "C" fn() -> !) -> u64 {
+    let mut rsp = stack_top.align_down(16u64).as_u64() as *mut u64;
+    let mut push = |value : u64| {
+        rsp = rsp.sub(1);
+        rsp.write(value);
+    };
+
+    // fake return address for `entry`, keeps the SysV stack alignment
+    push(0);
+    push(entry as usize as u64);
+    // rbp, rbx, r12, r13, r14, r15
+    for _ in 0..6 {
+        push(0);
+    }
+    push(INITIAL_RFLAGS);
+
+    rsp as u64
+}
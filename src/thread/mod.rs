@@ -0,0 +1,680 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use crate::memory::{AddressSpace, KernelStack};
+use crate::sync::IrqSafeSpinlock;
+use crate::{cpu, gdt, time};
+
+pub mod context;
+
+/// Upper bound on live threads, counting the idle thread of every CPU. The
+/// thread table and run queue are sized once at `init` so that the timer
+/// interrupt never has to allocate.
+pub const MAX_THREADS : usize = 64;
+pub const THREAD_STACK_SIZE : usize = 4096 * 4;
+/// Number of timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS : u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID : AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    /// Blocked until the tick counter reaches the given value.
+    Sleeping(u64),
+    /// Blocked until the given thread has finished.
+    Joining(ThreadId),
+    Finished,
+}
+
+/// A thread as listed by `threads`.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id : ThreadId,
+    pub state : ThreadState,
+    /// The CPU running it, if any.
+    pub cpu : Option<usize>,
+    /// Whether it runs a user program, i.e. has an address space of its own.
+    pub user : bool,
+    /// Whether it is the idle thread of a CPU.
+    pub idle : bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Sleeping,
+    Joining(ThreadId),
+    Finished,
+}
+
+impl From<State> for ThreadState {
+    fn from(state : State) -> ThreadState {
+        match state {
+            State::Running => ThreadState::Running,
+            State::Ready => ThreadState::Ready,
+            State::Sleeping(_) => ThreadState::Sleeping,
+            State::Joining(target) => ThreadState::Joining(target),
+            State::Finished => ThreadState::Finished,
+        }
+    }
+}
+
+/// How a thread ended, as reported by `JoinHandle::join`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Returned from its entry function or called `exit`.
+    Exited,
+    /// Ended by the kernel, e.g. after a page fault it could not resolve.
+    Killed,
+}
+
+struct Thread {
+    id : ThreadId,
+    state : State,
+    /// Saved stack pointer while the thread is switched out.
+    rsp : u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack.
+    stack : Option<KernelStack>,
+    /// Running on a CPU or still being switched away from. Such a thread
+    /// must not be started on another CPU, and its stack must stay.
+    on_cpu : bool,
+    entry : Option<Box<dyn FnOnce() + Send>>,
+    detached : bool,
+    /// `None` for kernel threads, which run on the kernel page table. It has
+    /// a lock of its own, which is never taken with the scheduler locked.
+    address_space : Option<Arc<IrqSafeSpinlock<AddressSpace>>>,
+    /// Level-4 table of `address_space`, to switch to without its lock.
+    p4_frame : Option<PhysFrame>,
+    exit_status : ExitStatus,
+}
+
+impl Thread {
+    fn new(entry : Box<dyn FnOnce() + Send>, address_space : Option<AddressSpace>)
+        -> Box<Thread>
+    {
+        let stack = KernelStack::new("thread stack", THREAD_STACK_SIZE);
+        let rsp = unsafe { context::init_stack(stack.top(), thread_entry) };
+        let p4_frame = address_space.as_ref().map(AddressSpace::p4_frame);
+        let address_space = address_space.map(|address_space| {
+            Arc::new(IrqSafeSpinlock::new(address_space))
+        });
+        Box::new(Thread {
+            id : ThreadId::new(),
+            state : State::Ready,
+            rsp,
+            stack : Some(stack),
+            on_cpu : false,
+            entry : Some(entry),
+            detached : false,
+            address_space,
+            p4_frame,
+            exit_status : ExitStatus::Exited,
+        })
+    }
+}
+
+/// What one CPU is running.
+struct CpuState {
+    current : ThreadId,
+    /// Runs when nothing else is ready; never in the run queue.
+    idle : ThreadId,
+    slice_left : u32,
+    /// The thread this CPU is switching away from, until the thread it
+    /// switched to calls `finish_switch`.
+    previous : Option<ThreadId>,
+}
+
+/// The threads of all CPUs. They share one run queue, and every CPU picks
+/// its next thread from it.
+struct Scheduler {
+    threads : [Option<Box<Thread>>; MAX_THREADS],
+    run_queue : VecDeque<ThreadId>,
+    /// Indexed by `cpu::id`, `None` for CPUs that do not schedule yet.
+    cpus : [Option<CpuState>; cpu::MAX_CPUS],
+    kernel_p4 : PhysFrame,
+}
+
+impl Scheduler {
+    fn slot(&self, id : ThreadId) -> Option<usize> {
+        self.threads.iter().position(|t| matches!(t, Some(t) if t.id == id))
+    }
+
+    /// State of the running CPU; interrupts have to be disabled.
+    fn cpu(&mut self) -> &mut CpuState {
+        self.cpus[cpu::id()].as_mut().expect("CPU is not scheduling threads")
+    }
+
+    fn current(&self) -> ThreadId {
+        self.cpus[cpu::id()].as_ref().expect("CPU is not scheduling threads").current
+    }
+
+    fn thread(&self, id : ThreadId) -> &Thread {
+        let slot = self.slot(id).expect("unknown thread id");
+        self.threads[slot].as_ref().unwrap()
+    }
+
+    fn thread_mut(&mut self, id : ThreadId) -> &mut Thread {
+        let slot = self.slot(id).expect("unknown thread id");
+        self.threads[slot].as_mut().unwrap()
+    }
+
+    /// Hands `thread` back if the table is full.
+    fn insert(&mut self, thread : Box<Thread>) -> Result<(), Box<Thread>> {
+        match self.threads.iter().position(Option::is_none) {
+            Some(slot) => {
+                self.threads[slot] = Some(thread);
+                Ok(())
+            }
+            None => Err(thread),
+        }
+    }
+
+    fn make_ready(&mut self, id : ThreadId) {
+        self.thread_mut(id).state = State::Ready;
+        self.run_queue.push_back(id);
+    }
+
+    /// Finished and off its CPU, so that its stack can go.
+    fn is_finished(&self, id : ThreadId) -> bool {
+        match self.slot(id) {
+            Some(slot) => {
+                let thread = self.threads[slot].as_ref().unwrap();
+                thread.state == State::Finished && !thread.on_cpu
+            }
+            None => true,
+        }
+    }
+
+    /// Takes one finished, detached thread out of the table so the caller
+    /// can free it outside the lock.
+    fn take_detached(&mut self) -> Option<Box<Thread>> {
+        let slot = self.threads.iter().position(|t| matches!(t,
+            Some(t) if t.detached && t.state == State::Finished && !t.on_cpu))?;
+        self.threads[slot].take()
+    }
+
+    fn wake_sleepers(&mut self, now : u64) {
+        for slot in 0..MAX_THREADS {
+            let id = match &self.threads[slot] {
+                Some(t) => match t.state {
+                    State::Sleeping(until) if until <= now => t.id,
+                    _ => continue,
+                },
+                None => continue,
+            };
+            self.make_ready(id);
+        }
+    }
+
+    fn wake_joiners(&mut self, target : ThreadId) {
+        for slot in 0..MAX_THREADS {
+            let id = match &self.threads[slot] {
+                Some(t) if t.state == State::Joining(target) => t.id,
+                _ => continue,
+            };
+            self.make_ready(id);
+        }
+    }
+
+    /// Takes the first thread off the run queue that is not still on
+    /// another CPU.
+    fn pick_next(&mut self, current : ThreadId) -> Option<ThreadId> {
+        let position = self.run_queue.iter()
+            .position(|&id| id == current || !self.thread(id).on_cpu)?;
+        self.run_queue.remove(position)
+    }
+
+    /// Puts the current thread into `state` and picks the next one to run.
+    ///
+    /// Returns the `(old, new)` stack pointers to switch with, or `None` if
+    /// the current thread keeps running. The old thread stays `on_cpu` until
+    /// `finish_switch`, as its registers are only saved by the switch.
+    fn prepare_switch(&mut self, state : State) -> Option<(*mut u64, u64)> {
+        match state {
+            State::Joining(target) if self.is_finished(target) => return None,
+            State::Sleeping(until) if until <= time::ticks() => return None,
+            _ => {}
+        }
+
+        let CpuState { current, idle, .. } = *self.cpu();
+        self.thread_mut(current).state = state;
+        if state == State::Ready && current != idle {
+            self.run_queue.push_back(current);
+        }
+
+        let next = self.pick_next(current).unwrap_or(idle);
+        self.thread_mut(next).state = State::Running;
+        self.cpu().slice_left = TIME_SLICE_TICKS;
+        if next == current {
+            return None;
+        }
+        self.thread_mut(next).on_cpu = true;
+        let cpu = self.cpu();
+        cpu.current = next;
+        cpu.previous = Some(current);
+
+        // interrupts from ring 3 must land on the stack of the thread that
+        // is actually running
+        let stack_top = self.thread_mut(next).stack.as_ref()
+            .map_or_else(gdt::default_kernel_stack_top, KernelStack::top);
+        gdt::set_kernel_stack(stack_top);
+
+        let next_p4 = self.thread(next).p4_frame.unwrap_or(self.kernel_p4);
+        let (active_p4, cr3_flags) = Cr3::read();
+        if active_p4 != next_p4 {
+            unsafe { Cr3::write(next_p4, cr3_flags) };
+        }
+
+        let old_rsp = &mut self.thread_mut(current).rsp as *mut u64;
+        let new_rsp = self.thread_mut(next).rsp;
+        Some((old_rsp, new_rsp))
+    }
+
+    /// Runs on the thread that was switched to: the previous one is off the
+    /// CPU now and may run elsewhere or be freed.
+    fn finish_switch(&mut self) {
+        let Some(previous) = self.cpu().previous.take() else { return };
+        let thread = self.thread_mut(previous);
+        thread.on_cpu = false;
+        if thread.state == State::Finished {
+            self.wake_joiners(previous);
+        }
+    }
+
+    fn tick(&mut self, now : u64) -> Option<(*mut u64, u64)> {
+        self.wake_sleepers(now);
+        let cpu = self.cpu();
+        cpu.slice_left = cpu.slice_left.saturating_sub(1);
+
+        let expired = cpu.slice_left == 0 || cpu.current == cpu.idle;
+        if expired && !self.run_queue.is_empty() {
+            self.prepare_switch(State::Ready)
+        } else {
+            None
+        }
+    }
+}
+
+static SCHEDULER : IrqSafeSpinlock<Option<Scheduler>> = IrqSafeSpinlock::new(None);
+
+/// Turns the caller into the first kernel thread and creates the idle thread.
+///
+/// Needs the heap, `memory::install_kernel_mapper` and
+/// `memory::install_frame_allocator`, since thread stacks are mapped from the
+/// kernel stack range. Also moves the TSS stacks there.
+pub fn init() {
+    gdt::init_stacks();
+
+    let boot = Box::new(Thread {
+        id : ThreadId::new(),
+        state : State::Running,
+        rsp : 0,
+        stack : None,
+        on_cpu : true,
+        entry : None,
+        detached : false,
+        address_space : None,
+        p4_frame : None,
+        exit_status : ExitStatus::Exited,
+    });
+    let idle = Thread::new(Box::new(|| idle_loop()), None);
+
+    let mut scheduler = Scheduler {
+        threads : core::array::from_fn(|_| None),
+        run_queue : VecDeque::with_capacity(MAX_THREADS),
+        cpus : core::array::from_fn(|_| None),
+        // the boot thread runs on the kernel page table
+        kernel_p4 : Cr3::read().0,
+    };
+    scheduler.cpus[cpu::id()] = Some(CpuState {
+        current : boot.id,
+        idle : idle.id,
+        slice_left : TIME_SLICE_TICKS,
+        previous : None,
+    });
+    // the table is still empty
+    scheduler.insert(boot).ok().unwrap();
+    scheduler.insert(idle).ok().unwrap();
+
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+}
+
+/// Turns the first code an application processor runs into the idle thread
+/// of that CPU, with `stack` as its stack, and starts scheduling there.
+///
+/// Must be called with interrupts disabled, after `init`.
+pub(crate) fn run_application_processor(stack : KernelStack) -> ! {
+    let idle = Box::new(Thread {
+        id : ThreadId::new(),
+        state : State::Running,
+        rsp : 0,
+        stack : Some(stack),
+        on_cpu : true,
+        entry : None,
+        detached : false,
+        address_space : None,
+        p4_frame : None,
+        exit_status : ExitStatus::Exited,
+    });
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init has not been called");
+        scheduler.cpus[cpu::id()] = Some(CpuState {
+            current : idle.id,
+            idle : idle.id,
+            slice_left : TIME_SLICE_TICKS,
+            previous : None,
+        });
+        if scheduler.insert(idle).is_err() {
+            panic!("no thread slot left for the idle thread of CPU {}", cpu::id());
+        }
+    }
+    cpu::mark_online();
+    idle_loop()
+}
+
+fn idle_loop() -> ! {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Switches away from the current thread, leaving it in `state`.
+///
+/// Must be called with interrupts disabled; the scheduler lock is released
+/// before the actual switch so the next thread can take it.
+fn reschedule(state : State) {
+    let switch = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.prepare_switch(state),
+        None => None,
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { context::switch(old_rsp, new_rsp) };
+        finish_switch();
+    }
+}
+
+/// Called right after `context::switch` returns, or a new thread starts.
+fn finish_switch() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.finish_switch();
+    }
+}
+
+/// Called by the timer interrupt handler after the EOI has been sent.
+///
+/// Preempts the current thread once its time slice is used up.
+pub(crate) fn on_timer_tick() {
+    // the lock is only ever held with interrupts disabled, so only another
+    // CPU can hold it; rather than spin in an ISR this tick is skipped then
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => match scheduler.as_mut() {
+            Some(scheduler) => scheduler.tick(time::ticks()),
+            None => None,
+        },
+        None => None,
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { context::switch(old_rsp, new_rsp) };
+        finish_switch();
+    }
+}
+
+/// First code run on a new thread's stack, entered from `context::switch`.
+extern "C" fn thread_entry() -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread subsystem not initialized");
+        scheduler.finish_switch();
+        let current = scheduler.current();
+        scheduler.thread_mut(current).entry.take()
+    };
+    interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Handle to a spawned thread. Dropping it detaches the thread.
+pub struct JoinHandle {
+    id : ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks the calling thread until the thread has finished.
+    pub fn join(self) -> ExitStatus {
+        let id = self.id;
+        core::mem::forget(self);
+
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                let finished = SCHEDULER.lock().as_ref()
+                    .map_or(true, |scheduler| scheduler.is_finished(id));
+                if !finished {
+                    reschedule(State::Joining(id));
+                }
+                finished
+            });
+            if finished {
+                break;
+            }
+        }
+
+        let thread = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut()?;
+            let slot = scheduler.slot(id)?;
+            scheduler.threads[slot].take()
+        });
+        thread.map_or(ExitStatus::Exited, |thread| thread.exit_status)
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let id = self.id;
+        interrupts::without_interrupts(|| {
+            if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+                if scheduler.slot(id).is_some() {
+                    scheduler.thread_mut(id).detached = true;
+                }
+            }
+        });
+        reap_detached();
+    }
+}
+
+/// Frees the stacks of detached threads that have finished.
+///
+/// Runs in thread context with the lock released while freeing, since the
+/// heap may be locked by a preempted thread.
+fn reap_detached() {
+    loop {
+        let thread = interrupts::without_interrupts(|| {
+            SCHEDULER.lock().as_mut().and_then(Scheduler::take_detached)
+        });
+        match thread {
+            Some(thread) => drop(thread),
+            None => break,
+        }
+    }
+}
+
+/// All `MAX_THREADS` slots of the thread table are taken. Holds what the
+/// thread would have owned.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TooManyThreads<T>(pub T);
+
+/// Starts `f` on a new kernel thread.
+///
+/// Panics if there are `MAX_THREADS` threads already; see `try_spawn`.
+pub fn spawn<F>(f : F) -> JoinHandle
+    where F : FnOnce() + Send + 'static
+{
+    try_spawn(f).expect("too many threads")
+}
+
+/// Like `spawn`, but fails if the thread table is full.
+pub fn try_spawn<F>(f : F) -> Result<JoinHandle, TooManyThreads<()>>
+    where F : FnOnce() + Send + 'static
+{
+    spawn_thread(Box::new(f), None).map_err(|_| TooManyThreads(()))
+}
+
+/// Starts `f` on a new thread that runs with `address_space` loaded. The
+/// address space is freed once the thread has finished and been joined or
+/// detached.
+///
+/// Panics if there are `MAX_THREADS` threads already; see `try_spawn_in`.
+pub fn spawn_in<F>(address_space : AddressSpace, f : F) -> JoinHandle
+    where F : FnOnce() + Send + 'static
+{
+    try_spawn_in(address_space, f).ok().expect("too many threads")
+}
+
+/// Like `spawn_in`, but fails if the thread table is full and hands the
+/// address space back.
+pub fn try_spawn_in<F>(address_space : AddressSpace, f : F)
+    -> Result<JoinHandle, TooManyThreads<AddressSpace>>
+    where F : FnOnce() + Send + 'static
+{
+    spawn_thread(Box::new(f), Some(address_space))
+        .map_err(|address_space| TooManyThreads(address_space.unwrap()))
+}
+
+/// Returns the address space back if there is no free slot for the thread.
+fn spawn_thread(f : Box<dyn FnOnce() + Send>, address_space : Option<AddressSpace>)
+    -> Result<JoinHandle, Option<AddressSpace>>
+{
+    reap_detached();
+
+    let thread = Thread::new(f, address_space);
+    let id = thread.id;
+    let inserted : Result<(), Box<Thread>> = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init has not been called");
+        scheduler.insert(thread)?;
+        scheduler.run_queue.push_back(id);
+        Ok(())
+    });
+
+    match inserted {
+        Ok(()) => Ok(JoinHandle { id }),
+        // the thread never ran, so nothing else has its address space
+        Err(mut thread) => Err(thread.address_space.take().map(|address_space| {
+            Arc::try_unwrap(address_space).ok()
+                .expect("address space of an unstarted thread is shared")
+                .into_inner()
+        })),
+    }
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| reschedule(State::Ready));
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration : Duration) {
+    let deadline = time::ticks() + time::duration_to_ticks(duration);
+    while time::ticks() < deadline {
+        interrupts::without_interrupts(|| reschedule(State::Sleeping(deadline)));
+    }
+}
+
+/// Ends the current thread and wakes any thread joining it.
+pub fn exit() -> ! {
+    interrupts::disable();
+    reschedule(State::Finished);
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Ends the current thread like `exit`, but reports it as killed.
+///
+/// Meant for exception handlers; the interrupted state is abandoned.
+pub fn kill_current() -> ! {
+    interrupts::disable();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        let current = scheduler.current();
+        scheduler.thread_mut(current).exit_status = ExitStatus::Killed;
+    }
+    exit()
+}
+
+/// Runs `f` on the address space of the current thread, or returns `None`
+/// for kernel threads.
+///
+/// Also called from the page fault handler, for faults in kernel code too.
+/// If that code holds the scheduler lock or the address space, this returns
+/// `None` rather than wait for it forever, so the fault gets reported.
+pub fn with_current_address_space<F, R>(f : F) -> Option<R>
+    where F : FnOnce(&mut AddressSpace) -> R
+{
+    // the current thread keeps its address space alive meanwhile
+    let address_space = interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock_unless_held_here()?;
+        let scheduler = scheduler.as_ref()?;
+        scheduler.thread(scheduler.current()).address_space.clone()
+    })?;
+    let mut address_space = address_space.lock_unless_held_here()?;
+    Some(f(&mut address_space))
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().map(Scheduler::current))
+}
+
+/// Every thread that has not been freed yet, ordered by id.
+pub fn threads() -> Vec<ThreadInfo> {
+    // copied into an array under the lock and only collected into the `Vec`
+    // after releasing it, so this does not allocate with the scheduler locked
+    let infos : [Option<ThreadInfo>; MAX_THREADS] = interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else { return [None; MAX_THREADS] };
+        core::array::from_fn(|slot| {
+            let thread = scheduler.threads[slot].as_ref()?;
+            let running_on = |cpu : &Option<CpuState>| {
+                cpu.as_ref().is_some_and(|cpu| cpu.current == thread.id)
+            };
+            Some(ThreadInfo {
+                id : thread.id,
+                state : thread.state.into(),
+                cpu : scheduler.cpus.iter().position(running_on),
+                user : thread.address_space.is_some(),
+                idle : scheduler.cpus.iter().flatten().any(|cpu| cpu.idle == thread.id),
+            })
+        })
+    });
+    let mut threads : Vec<ThreadInfo> = infos.into_iter().flatten().collect();
+    threads.sort_by_key(|thread| thread.id);
+    threads
+}
@@ -0,0 +1,223 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Input clock of the 8253/8254 PIT.
+pub const PIT_BASE_FREQUENCY_HZ : u32 = 1_193_182;
+/// Tick rate used by `crate::init`.
+pub const DEFAULT_FREQUENCY_HZ : u32 = 100;
+
+const PIT_CHANNEL0_PORT : u16 = 0x40;
+const PIT_COMMAND_PORT : u16 = 0x43;
+/// Channel 0, lobyte/hibyte access, mode 3 (square wave), binary.
+const PIT_CHANNEL0_SQUARE_WAVE : u8 = 0x36;
+
+const TIMER_WHEEL_SLOTS : usize = 64;
+
+static TICKS : AtomicU64 = AtomicU64::new(0);
+static PIT_DIVISOR : AtomicU32 = AtomicU32::new(0);
+
+/// Programs PIT channel 0 to fire IRQ0 at (roughly) `frequency_hz`.
+///
+/// The divisor is clamped to what the PIT supports, so the effective
+/// frequency is returned.
+pub fn init(frequency_hz : u32) -> u32 {
+    let divisor = (PIT_BASE_FREQUENCY_HZ / frequency_hz.max(1)).clamp(1, 0xffff);
+
+    let mut command : Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel0 : Port<u8> = Port::new(PIT_CHANNEL0_PORT);
+    interrupts::without_interrupts(|| unsafe {
+        command.write(PIT_CHANNEL0_SQUARE_WAVE);
+        channel0.write((divisor & 0xff) as u8);
+        channel0.write((divisor >> 8) as u8);
+    });
+    PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+
+    PIT_BASE_FREQUENCY_HZ / divisor
+}
+
+/// Effective tick frequency, or 0 if the timer was never initialized.
+pub fn frequency() -> u32 {
+    match PIT_DIVISOR.load(Ordering::Relaxed) {
+        0 => 0,
+        divisor => PIT_BASE_FREQUENCY_HZ / divisor,
+    }
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Monotonic time since the timer was initialized.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+fn ticks_to_duration(ticks : u64) -> Duration {
+    let divisor = PIT_DIVISOR.load(Ordering::Relaxed) as u128;
+    let nanos = ticks as u128 * divisor * 1_000_000_000 / PIT_BASE_FREQUENCY_HZ as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+pub(crate) fn duration_to_ticks(duration : Duration) -> u64 {
+    let divisor = PIT_DIVISOR.load(Ordering::Relaxed).max(1) as u128;
+    let ticks = duration.as_nanos() * PIT_BASE_FREQUENCY_HZ as u128
+        / (divisor * 1_000_000_000);
+    // round up so we never wake early
+    ticks as u64 + 1
+}
+
+/// Called by the timer interrupt handler.
+///
+/// Must not block or allocate: it only wakes the sleepers that are due.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    TIMER_WHEEL.lock().expire(now);
+}
+
+struct TimerEntry {
+    id : u64,
+    deadline : u64,
+    waker : Waker,
+    fired : bool,
+}
+
+/// Hashed timer wheel: a sleeper lives in slot `deadline % TIMER_WHEEL_SLOTS`,
+/// so each tick only has to look at one slot.
+///
+/// Entries are added and removed from task context only (with interrupts
+/// disabled); the interrupt handler just wakes them, so it never touches
+/// the heap.
+struct TimerWheel {
+    slots : [Vec<TimerEntry>; TIMER_WHEEL_SLOTS],
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        TimerWheel {
+            slots : core::array::from_fn(|_| Vec::new()),
+        }
+    }
+
+    fn slot(deadline : u64) -> usize {
+        (deadline % TIMER_WHEEL_SLOTS as u64) as usize
+    }
+
+    fn expire(&mut self, now : u64) {
+        for entry in self.slots[Self::slot(now)].iter_mut() {
+            if !entry.fired && entry.deadline <= now {
+                entry.fired = true;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+
+    fn register(&mut self, id : u64, deadline : u64, waker : &Waker) {
+        let slot = &mut self.slots[Self::slot(deadline)];
+        match slot.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => {
+                if !entry.waker.will_wake(waker) {
+                    entry.waker = waker.clone();
+                }
+            }
+            None => slot.push(TimerEntry {
+                id,
+                deadline,
+                waker : waker.clone(),
+                fired : false,
+            }),
+        }
+    }
+
+    fn remove(&mut self, id : u64, deadline : u64) {
+        self.slots[Self::slot(deadline)].retain(|entry| entry.id != id);
+    }
+}
+
+lazy_static! {
+    static ref TIMER_WHEEL : Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+}
+
+/// Future returned by [`sleep`].
+pub struct Sleep {
+    id : u64,
+    deadline : u64,
+    registered : bool,
+}
+
+/// Completes once at least `duration` has passed.
+pub fn sleep(duration : Duration) -> Sleep {
+    sleep_until_tick(ticks() + duration_to_ticks(duration))
+}
+
+/// Completes once the tick counter reaches `deadline`.
+pub fn sleep_until_tick(deadline : u64) -> Sleep {
+    static NEXT_ID : AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        id : NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+        registered : false,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, cx : &mut Context) -> Poll<()> {
+        let (id, deadline, registered) = (self.id, self.deadline, self.registered);
+        // with interrupts disabled a tick cannot slip in between the deadline
+        // check and the registration, and the ISR cannot spin on the lock
+        let ready = interrupts::without_interrupts(|| {
+            let mut wheel = TIMER_WHEEL.lock();
+            if ticks() >= deadline {
+                if registered {
+                    wheel.remove(id, deadline);
+                }
+                true
+            } else {
+                wheel.register(id, deadline, cx.waker());
+                false
+            }
+        });
+
+        if ready {
+            self.registered = false;
+            Poll::Ready(())
+        } else {
+            self.registered = true;
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            interrupts::without_interrupts(|| {
+                TIMER_WHEEL.lock().remove(self.id, self.deadline);
+            });
+        }
+    }
+}
+
+#[test_case]
+fn test_uptime_advances() {
+    serial_print!("test_uptime_advances... ");
+    let start = ticks();
+    while ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() > ticks_to_duration(start));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,141 @@
+use core::arch::asm;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageTableFlags, Translate, mapper::TranslateResult},
+};
+use crate::memory::{Backing, MapError};
+use crate::{gdt, memory, thread};
+
+/// Lower-half range reserved for user mappings (P4 entries 32..64). The
+/// bootloader only hands out the lowest P4 entries, so this never overlaps
+/// the kernel, its stack or the physical memory mapping.
+pub const USER_SPACE_START : u64 = 0x0000_1000_0000_0000;
+pub const USER_SPACE_END : u64 = 0x0000_2000_0000_0000;
+
+/// Interrupts enabled plus the always-set reserved bit 1.
+const USER_RFLAGS : u64 = 0x202;
+
+#[derive(Debug)]
+pub enum UserMapError {
+    /// The range is not inside `USER_SPACE_START..USER_SPACE_END`.
+    OutsideUserSpace,
+    /// `memory` has no kernel mapper or frame allocator installed.
+    NoKernelMemory,
+    Map(MapError),
+}
+
+impl From<MapError> for UserMapError {
+    fn from(error : MapError) -> Self {
+        UserMapError::Map(error)
+    }
+}
+
+pub fn is_user_range(start : VirtAddr, size : u64) -> bool {
+    match start.as_u64().checked_add(size) {
+        Some(end) => start.as_u64() >= USER_SPACE_START && end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+/// Checks that every page of `[start, start + len)` is mapped for ring 3 in
+/// the active address space, and writable if `write` is set. Used to vet
+/// pointers passed in by user code.
+///
+/// Lazy regions of the current thread count as mapped, and copy-on-write
+/// pages as writable: the page fault handler resolves both when the kernel
+/// touches them.
+pub fn is_user_accessible(start : VirtAddr, len : u64, write : bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    if !is_user_range(start, len) {
+        return false;
+    }
+
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let lazy = thread::with_current_address_space(|address_space| {
+        address_space.vmas().covers(start, len, required)
+    });
+    if lazy == Some(true) {
+        return true;
+    }
+    let start_page : Page = Page::containing_address(start);
+    let end_page : Page = Page::containing_address(start + (len - 1));
+    memory::try_with_active_memory(|mapper, _| {
+        Page::range_inclusive(start_page, end_page).all(|page| {
+            match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => {
+                    let flags = if flags.contains(memory::COPY_ON_WRITE) {
+                        flags | PageTableFlags::WRITABLE
+                    } else {
+                        flags
+                    };
+                    flags.contains(required)
+                }
+                _ => false,
+            }
+        })
+    }).unwrap_or(false)
+}
+
+/// Maps `size` bytes starting at `start` in the active address space to
+/// fresh, zeroed frames that are accessible from ring 3.
+///
+/// `flags` are added to `PRESENT | USER_ACCESSIBLE`. If mapping fails, none
+/// of the range stays mapped.
+pub fn map_user_range(start : VirtAddr, size : u64, flags : PageTableFlags)
+    -> Result<(), UserMapError>
+{
+    if size == 0 {
+        return Ok(());
+    }
+    if !is_user_range(start, size) {
+        return Err(UserMapError::OutsideUserSpace);
+    }
+
+    let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+    let start_page : Page = Page::containing_address(start);
+    let end_page : Page = Page::containing_address(start + (size - 1));
+    let len = end_page.start_address() + 4096u64 - start_page.start_address();
+
+    memory::try_with_active_memory(|mapper, frame_allocator| {
+        memory::map_range(mapper, frame_allocator, start_page.start_address(),
+                          Backing::AllocateZeroed, len, flags)?;
+        Ok(())
+    }).ok_or(UserMapError::NoKernelMemory)?
+}
+
+/// Drops to ring 3 and starts executing at `entry` with the stack pointer
+/// set to `user_stack_top`.
+///
+/// Unsafe because both addresses must be mapped user-accessible, and the
+/// current kernel stack is abandoned.
+pub unsafe fn enter_user_mode(entry : VirtAddr, user_stack_top : VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64 | 3;
+    let data = selectors.user_data_selector.0 | 3;
+
+    asm!(
+        // GS goes back to the user's base, see `cpu::PerCpu`
+        "cli",
+        "swapgs",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        data = in(reg) data,
+        ss = in(reg) data as u64,
+        rsp = in(reg) user_stack_top.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        cs = in(reg) code,
+        rip = in(reg) entry.as_u64(),
+        options(noreturn),
+    )
+}
@@ -1,7 +1,8 @@
 use volatile::Volatile;
 use core::fmt::{self, write, Write};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::sync::IrqSafeSpinlock;
 
 #[cfg(test)]
 use crate::{serial_print, serial_println};
@@ -49,8 +50,8 @@ struct Unit {
 }
 
 
-const VGA_BUFFER_WIDTH : usize = 80;
-const VGA_BUFFER_HEIGHT : usize = 25;
+pub const VGA_BUFFER_WIDTH : usize = 80;
+pub const VGA_BUFFER_HEIGHT : usize = 25;
 
 #[repr(transparent)]
 struct VgaBuffer {
@@ -131,10 +132,64 @@ impl Writer {
         }
         self.cursor_y = 0;
     }
+
+    /// Blanks every row and continues writing at the top.
+    pub fn clear_screen(&mut self) {
+        for raw in 0..VGA_BUFFER_HEIGHT {
+            self.cursor_x = raw as u8;
+            self.clear_raw();
+        }
+        self.cursor_x = 0;
+        self.move_cursor(0);
+    }
+
+    /// Replaces the current row with `parts`, written one after the other.
+    pub fn rewrite_raw(&mut self, parts : &[&str]) {
+        self.clear_raw();
+        for part in parts {
+            self.write_string(part);
+        }
+    }
+
+    /// Shows the blinking hardware cursor as an underline.
+    pub fn enable_cursor(&mut self) {
+        let (mut index, mut data) = crtc_ports();
+        unsafe {
+            index.write(CRTC_CURSOR_START);
+            let start = data.read();
+            data.write(start & 0xc0 | 14);
+            index.write(CRTC_CURSOR_END);
+            let end = data.read();
+            data.write(end & 0xe0 | 15);
+        }
+    }
+
+    /// Puts the hardware cursor into `col` of the current row. Writing does
+    /// not move it.
+    pub fn move_cursor(&mut self, col : usize) {
+        let position = self.cursor_x as usize * VGA_BUFFER_WIDTH + col.min(VGA_BUFFER_WIDTH - 1);
+        let (mut index, mut data) = crtc_ports();
+        unsafe {
+            index.write(CRTC_CURSOR_LOCATION_LOW);
+            data.write(position as u8);
+            index.write(CRTC_CURSOR_LOCATION_HIGH);
+            data.write((position >> 8) as u8);
+        }
+    }
+}
+
+const CRTC_CURSOR_START : u8 = 0x0a;
+const CRTC_CURSOR_END : u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH : u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW : u8 = 0x0f;
+
+/// Index and data port of the CRT controller, in color mode.
+fn crtc_ports() -> (Port<u8>, Port<u8>) {
+    (Port::new(0x3d4), Port::new(0x3d5))
 }
 
 lazy_static! {
-    pub static ref WRITER : Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER : IrqSafeSpinlock<Writer> = IrqSafeSpinlock::new(Writer {
         vga_buffer : unsafe { &mut *(0xb8000 as *mut VgaBuffer) },
         cursor_x : 0,
         cursor_y : 0,
@@ -155,11 +210,14 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args : fmt::Arguments) {
-    // use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+/// The text in `row` of the screen, for tests of code that prints.
+#[cfg(test)]
+pub(crate) fn row_text(row : usize) -> alloc::string::String {
+    let writer = WRITER.lock();
+    writer.vga_buffer.chars[row].iter().map(|unit| char::from(unit.read().ascii_code)).collect()
 }
 
 #[test_case]
@@ -176,15 +234,12 @@ fn test_println_output() {
     serial_print!("test_println_output... ");
 
     let s = "Some test string that fits on a single line";
-    use x86_64::instructions::interrupts;
     let mut writer = WRITER.lock();
     writeln!(writer, "\n{}", s).expect("write failed");
-    interrupt::without_interrupts(|| {    
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = WRITER.lock().vga_buffer.chars[VGA_BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_code), c);
-        }
-    });
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.vga_buffer.chars[VGA_BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_code), c);
+    }
 
     serial_println!("[ok]");
 }
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(acpi_test_main);
+
+fn acpi_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::acpi::{self, AddressSpace};
+use rustOS::{serial_print, serial_println};
+
+#[test_case]
+fn lists_tables() {
+    serial_print!("lists tables ... ");
+    let signatures = acpi::signatures();
+    assert!(signatures.contains(b"APIC"));
+    assert!(signatures.contains(b"FACP"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn madt_lists_boot_cpu() {
+    serial_print!("madt lists boot cpu ... ");
+    let madt = acpi::madt().expect("QEMU provides a MADT");
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled && cpu.apic_id == 0));
+    assert!(madt.io_apics.iter().any(|io_apic| io_apic.address.as_u64() == 0xfec0_0000));
+    // QEMU wires the PIT to GSI 2
+    assert!(madt.overrides.iter().any(|o| o.irq == 0 && o.gsi == 2));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fadt_has_power_management_ports() {
+    serial_print!("fadt has power management ports ... ");
+    let fadt = acpi::fadt().expect("QEMU provides a FADT");
+    assert_ne!(fadt.pm1a_event_block, 0);
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.pm_timer_block, 0);
+    assert_eq!(fadt.sci_interrupt, 9);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn hpet_is_memory_mapped() {
+    serial_print!("hpet is memory mapped ... ");
+    let hpet = acpi::hpet().expect("QEMU provides an HPET");
+    assert_eq!(hpet.address.address_space, AddressSpace::Memory);
+    assert_eq!(hpet.address.address, 0xfed0_0000);
+    assert!(hpet.comparators >= 3);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(address_space_test_main);
+
+fn address_space_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::memory::{self, AddressSpace, Backing, COPY_ON_WRITE};
+use rustOS::{serial_print, serial_println, userspace};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+const USER_PAGE: u64 = userspace::USER_SPACE_START + 0x20_0000;
+
+fn map_user_page(address_space: &mut AddressSpace, addr: u64) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE;
+    let mut mapper = address_space.mapper();
+    memory::with_frame_allocator(|frame_allocator| unsafe {
+        let frame: PhysFrame = frame_allocator.allocate_frame().expect("out of frames");
+        mapper.map_to(Page::containing_address(VirtAddr::new(addr)), frame, flags,
+                      frame_allocator)
+            .expect("map_to failed")
+            .flush();
+    });
+}
+
+#[test_case]
+fn frames_freed_on_drop() {
+    serial_print!("frames freed on drop ... ");
+    let before = memory::with_frame_allocator(|frame_allocator| frame_allocator.stats());
+    {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        map_user_page(&mut address_space, USER_PAGE);
+        // a second P3/P2/P1 chain
+        map_user_page(&mut address_space, userspace::USER_SPACE_END - 4096);
+    }
+    let after = memory::with_frame_allocator(|frame_allocator| frame_allocator.stats());
+    assert_eq!(before, after);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    serial_print!("user mappings are private ... ");
+    let mut address_space = AddressSpace::new().expect("out of frames");
+    map_user_page(&mut address_space, USER_PAGE);
+    let addr = VirtAddr::new(USER_PAGE);
+    assert!(address_space.mapper().translate_addr(addr).is_some());
+    assert!(memory::with_kernel_mapper(|mapper| mapper.translate_addr(addr)).is_none());
+    assert!(AddressSpace::new().expect("out of frames").mapper().translate_addr(addr).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn activate_keeps_kernel_mapped() {
+    serial_print!("activate keeps kernel mapped ... ");
+    let kernel_p4 = Cr3::read().0;
+    let mut address_space = AddressSpace::new().expect("out of frames");
+    map_user_page(&mut address_space, USER_PAGE);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        address_space.activate();
+        // kernel code, stack and heap keep working, and the user page is there
+        let boxed = alloc::boxed::Box::new(42u64);
+        (USER_PAGE as *mut u64).write(*boxed);
+        assert_eq!((USER_PAGE as *const u64).read(), 42);
+    });
+    // dropping the active address space switches back to the kernel table
+    drop(address_space);
+    assert_eq!(Cr3::read().0, kernel_p4);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn device_mappings_survive_fork_and_drop() {
+    serial_print!("device mappings survive fork and drop ... ");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let before = memory::with_frame_allocator(|frame_allocator| frame_allocator.stats());
+    {
+        let mut parent = AddressSpace::new().expect("out of frames");
+        // VGA text memory, and the local APIC above all RAM
+        for (virt, phys) in [(USER_PAGE, 0xb_8000), (USER_PAGE + 4096, 0xfee0_0000)] {
+            let mut mapper = parent.mapper();
+            memory::with_frame_allocator(|frame_allocator| {
+                memory::map_range(&mut mapper, frame_allocator, VirtAddr::new(virt),
+                                  Backing::Physical(PhysAddr::new(phys)), 4096, flags)
+            }).expect("map_range failed");
+        }
+        let mut child = parent.fork().expect("out of frames");
+        for address_space in [&mut parent, &mut child] {
+            match address_space.mapper().translate(VirtAddr::new(USER_PAGE)) {
+                TranslateResult::Mapped { flags, .. } => {
+                    assert!(flags.contains(PageTableFlags::WRITABLE));
+                    assert!(!flags.contains(COPY_ON_WRITE));
+                }
+                other => panic!("device page not mapped: {:?}", other),
+            }
+        }
+    }
+    let after = memory::with_frame_allocator(|frame_allocator| frame_allocator.stats());
+    assert_eq!(before, after);
+    serial_println!("[ok]");
+}
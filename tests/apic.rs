@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(apic_test_main);
+
+fn apic_test_main(boot_info: &'static BootInfo) -> ! {
+    use rustOS::interrupt;
+
+    rustOS::test_boot(boot_info);
+    assert!(interrupt::init_apic(), "QEMU has an APIC, it should be used");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use core::time::Duration;
+use rustOS::{acpi, interrupt, serial_print, serial_println, thread, time};
+
+#[test_case]
+fn madt_lists_io_apic() {
+    serial_print!("madt lists io apic ... ");
+    let madt = acpi::madt().expect("QEMU provides a MADT");
+    assert!(!madt.io_apics.is_empty());
+    assert_eq!(madt.local_apic_address.as_u64(), interrupt::apic::DEFAULT_LOCAL_APIC_ADDRESS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn apic_timer_ticks() {
+    serial_print!("apic timer ticks ... ");
+    assert!(interrupt::apic_active());
+    let start = time::ticks();
+    while time::ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_keeps_its_length() {
+    serial_print!("sleep keeps its length ... ");
+    let start = time::uptime();
+    thread::sleep(Duration::from_millis(100));
+    let slept = time::uptime() - start;
+    assert!(slept >= Duration::from_millis(100), "slept {:?}", slept);
+    assert!(slept < Duration::from_millis(300), "slept {:?}", slept);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(backtrace_test_main);
+
+fn backtrace_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::backtrace::{self, Frames};
+use rustOS::{serial_print, serial_println};
+
+#[inline(never)]
+fn outer() -> [u64; 4] {
+    inner()
+}
+
+#[inline(never)]
+fn inner() -> [u64; 4] {
+    let mut frames = [0; 4];
+    for (slot, return_address) in frames.iter_mut().zip(Frames::here()) {
+        *slot = return_address;
+    }
+    core::hint::black_box(frames)
+}
+
+#[test_case]
+fn functions_are_symbolized() {
+    serial_print!("functions are symbolized ... ");
+    // the runner embeds the symbol table
+    let (name, offset) = backtrace::symbolize(outer as *const () as u64 + 1)
+        .expect("no symbol table");
+    assert_eq!(name, "backtrace::outer");
+    assert_eq!(offset, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn frames_follow_callers() {
+    serial_print!("frames follow callers ... ");
+    let frames = outer();
+    // the first frame returns into `outer`, the next into this test
+    assert_eq!(backtrace::symbolize(frames[0]).map(|(name, _)| name), Some("backtrace::outer"));
+    let (caller, _) = backtrace::symbolize(frames[1]).expect("no symbol table");
+    assert!(caller.contains("frames_follow_callers"), "unexpected caller {}", caller);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(copy_on_write_test_main);
+
+fn copy_on_write_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::memory::{self, AddressSpace};
+use rustOS::thread::{self, ExitStatus};
+use rustOS::{serial_print, serial_println, userspace};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame, Translate};
+use x86_64::VirtAddr;
+
+const PAGE: u64 = userspace::USER_SPACE_START + 0x40_0000;
+const LAZY: u64 = userspace::USER_SPACE_START + 0x50_0000;
+
+fn frame_of(address_space: &mut AddressSpace, addr: u64) -> (PhysFrame, PageTableFlags) {
+    match address_space.mapper().translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        other => panic!("{:#x} not mapped: {:?}", addr, other),
+    }
+}
+
+fn reference_count(frame: PhysFrame) -> u16 {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.reference_count(frame))
+}
+
+fn used_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.stats().used_frames)
+}
+
+/// Runs `f` on a thread whose address space has one writable page at `PAGE`
+/// holding `1`.
+fn with_parent<F>(f: F) -> ExitStatus
+    where F: FnOnce() + Send + 'static
+{
+    let address_space = AddressSpace::new().expect("out of frames");
+    thread::spawn_in(address_space, move || {
+        userspace::map_user_range(VirtAddr::new(PAGE), 4096, PageTableFlags::WRITABLE)
+            .expect("mapping user page failed");
+        unsafe { (PAGE as *mut u64).write_volatile(1) };
+        f();
+    }).join()
+}
+
+fn fork_current() -> AddressSpace {
+    thread::with_current_address_space(|address_space| address_space.fork())
+        .expect("not running in an address space")
+        .expect("fork ran out of frames")
+}
+
+#[test_case]
+fn fork_shares_frames() {
+    serial_print!("fork shares frames ... ");
+    let status = with_parent(|| {
+        let before = used_frames();
+        let mut child = fork_current();
+        // P4, P3, P2 and P1 of the child, no copy of the page itself
+        assert_eq!(used_frames() - before, 4);
+
+        let (frame, flags) = frame_of(&mut child, PAGE);
+        assert_eq!(reference_count(frame), 2);
+        assert!(flags.contains(memory::COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        let parent_frame = thread::with_current_address_space(|parent| frame_of(parent, PAGE).0);
+        assert_eq!(parent_frame, Some(frame));
+    });
+    assert_eq!(status, ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn write_copies_page() {
+    serial_print!("write copies page ... ");
+    let status = with_parent(|| {
+        let mut child = fork_current();
+        let (shared, _) = frame_of(&mut child, PAGE);
+
+        let before = used_frames();
+        unsafe { (PAGE as *mut u64).write_volatile(2) };
+        assert_eq!(used_frames() - before, 1);
+        assert_eq!(reference_count(shared), 1);
+
+        let (parent_frame, flags) = thread::with_current_address_space(|parent| {
+            frame_of(parent, PAGE)
+        }).unwrap();
+        assert_ne!(parent_frame, shared);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+
+        // the child still sees the old value and can write without copying
+        let child_status = thread::spawn_in(child, || unsafe {
+            assert_eq!((PAGE as *const u64).read_volatile(), 1);
+            let before = used_frames();
+            (PAGE as *mut u64).write_volatile(3);
+            assert_eq!(used_frames(), before);
+        }).join();
+        assert_eq!(child_status, ExitStatus::Exited);
+        assert_eq!(unsafe { (PAGE as *const u64).read_volatile() }, 2);
+    });
+    assert_eq!(status, ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn lazy_regions_are_inherited() {
+    serial_print!("lazy regions are inherited ... ");
+    let status = with_parent(|| {
+        thread::with_current_address_space(|parent| {
+            parent.map_lazy(VirtAddr::new(LAZY), 4096, PageTableFlags::WRITABLE)
+        }).unwrap().expect("lazy region rejected");
+        let child = fork_current();
+        let child_status = thread::spawn_in(child, || unsafe {
+            (LAZY as *mut u64).write_volatile(4);
+            assert_eq!((LAZY as *const u64).read_volatile(), 4);
+        }).join();
+        assert_eq!(child_status, ExitStatus::Exited);
+    });
+    assert_eq!(status, ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn frames_freed_after_fork() {
+    serial_print!("frames freed after fork ... ");
+    // let the heap grow to what a thread needs before counting frames
+    with_parent(|| {});
+    let before = used_frames();
+    let status = with_parent(|| {
+        let child = fork_current();
+        unsafe { (PAGE as *mut u64).write_volatile(2) };
+        drop(child);
+    });
+    assert_eq!(status, ExitStatus::Exited);
+    assert_eq!(used_frames(), before);
+    serial_println!("[ok]");
+}
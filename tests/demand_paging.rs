@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(demand_paging_test_main);
+
+fn demand_paging_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::memory::{self, AddressSpace};
+use rustOS::thread::{self, ExitStatus, JoinHandle};
+use rustOS::{serial_print, serial_println, userspace};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const USER_CODE: u64 = userspace::USER_SPACE_START;
+const USER_STACK: u64 = userspace::USER_SPACE_START + 0x10_0000;
+const USER_STACK_SIZE: u64 = 4 * 4096;
+const LAZY: u64 = userspace::USER_SPACE_START + 0x20_0000;
+
+/// ```text
+///     movabs [LAZY], rax
+///     push rax
+///     pop rax
+///     mov eax, 1      ; exit(0)
+///     xor edi, edi
+///     syscall
+///     ud2
+/// ```
+const TOUCH_LAZY: &[u8] = &[
+    0x48, 0xa3, 0x00, 0x00, 0x20, 0x00, 0x00, 0x10, 0x00, 0x00, 0x50, 0x58,
+    0xb8, 0x01, 0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05, 0x0f, 0x0b,
+];
+
+/// ```text
+///     movabs rax, [LAZY + 0x10_0000]  ; not in any region
+///     ud2
+/// ```
+const TOUCH_UNMAPPED: &[u8] = &[
+    0x48, 0xa1, 0x00, 0x00, 0x30, 0x00, 0x00, 0x10, 0x00, 0x00, 0x0f, 0x0b,
+];
+
+fn lazy_address_space() -> AddressSpace {
+    let mut address_space = AddressSpace::new().expect("out of frames");
+    let data = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_lazy(VirtAddr::new(USER_STACK), USER_STACK_SIZE, data)
+        .expect("stack region rejected");
+    address_space.map_lazy(VirtAddr::new(LAZY), 16 * 4096, data)
+        .expect("lazy region rejected");
+    address_space
+}
+
+fn run_in_ring3(program: &'static [u8]) -> JoinHandle {
+    thread::spawn_in(lazy_address_space(), move || unsafe {
+        userspace::map_user_range(VirtAddr::new(USER_CODE), 4096, PageTableFlags::WRITABLE)
+            .expect("mapping user code failed");
+        core::ptr::copy_nonoverlapping(program.as_ptr(), USER_CODE as *mut u8, program.len());
+        userspace::enter_user_mode(VirtAddr::new(USER_CODE),
+                                   VirtAddr::new(USER_STACK + USER_STACK_SIZE))
+    })
+}
+
+fn used_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.stats().used_frames)
+}
+
+#[test_case]
+fn frames_allocated_on_touch() {
+    serial_print!("frames allocated on touch ... ");
+    let handle = thread::spawn_in(lazy_address_space(), || {
+        let first = LAZY as *mut u64;
+        unsafe { first.write_volatile(1) };
+        // the page tables for the region exist now, so every further page
+        // costs exactly one frame
+        let before = used_frames();
+        unsafe {
+            assert_eq!(((LAZY + 4096) as *const u64).read_volatile(), 0);
+            ((LAZY + 5 * 4096) as *mut u64).write_volatile(5);
+            first.add(1).write_volatile(2);
+        }
+        assert_eq!(used_frames() - before, 2);
+        assert_eq!(unsafe { first.read_volatile() }, 1);
+    });
+    assert_eq!(handle.join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_fault_in_lazy_region() {
+    serial_print!("user fault in lazy region ... ");
+    assert_eq!(run_in_ring3(TOUCH_LAZY).join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_fault_outside_regions_kills_thread() {
+    serial_print!("user fault outside regions kills thread ... ");
+    assert_eq!(run_in_ring3(TOUCH_UNMAPPED).join(), ExitStatus::Killed);
+    // the rest of the system keeps running
+    assert_eq!(run_in_ring3(TOUCH_LAZY).join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn overlapping_regions_rejected() {
+    serial_print!("overlapping regions rejected ... ");
+    let mut address_space = lazy_address_space();
+    let flags = PageTableFlags::WRITABLE;
+    assert_eq!(address_space.map_lazy(VirtAddr::new(LAZY + 4096), 4096, flags),
+               Err(memory::VmaError::Overlap));
+    assert_eq!(address_space.map_lazy(VirtAddr::new(LAZY - 4096), 2 * 4096, flags),
+               Err(memory::VmaError::Overlap));
+    assert_eq!(address_space.map_lazy(VirtAddr::new(LAZY - 4096), 4096, flags), Ok(()));
+    assert_eq!(address_space.map_lazy(VirtAddr::new(0x1000), 4096, flags),
+               Err(memory::VmaError::OutsideUserSpace));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fault_with_frame_allocator_held_is_unresolved() {
+    serial_print!("fault with frame allocator held is unresolved ... ");
+    let mut address_space = lazy_address_space();
+    let addr = VirtAddr::new(LAZY);
+    let error_code = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE;
+    // the fault handler must not wait for a lock this CPU already holds
+    let resolved = memory::with_frame_allocator(|_| address_space.handle_fault(addr, error_code));
+    assert!(!resolved);
+    assert!(address_space.handle_fault(addr, error_code));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(elf_loader_test_main);
+
+fn elf_loader_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::elf::{self, ElfError, ElfFile};
+use rustOS::memory::{self, AddressSpace};
+use rustOS::thread::ExitStatus;
+use rustOS::{serial_print, serial_println};
+use x86_64::structures::paging::{OffsetPageTable, PageTableFlags, Translate};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::VirtAddr;
+
+const HELLO: &[u8] = include_bytes!("../user/hello.elf");
+
+fn flags_of(mapper: &OffsetPageTable, addr: u64) -> PageTableFlags {
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        other => panic!("{:#x} not mapped: {:?}", addr, other),
+    }
+}
+
+#[test_case]
+fn segment_flags() {
+    serial_print!("segment flags ... ");
+    let elf = ElfFile::parse(HELLO).expect("hello.elf does not parse");
+    let mut address_space = AddressSpace::new().expect("out of frames");
+    let mut mapper = address_space.mapper();
+    let program = memory::with_frame_allocator(|frame_allocator| {
+        elf::load(&elf, &mut mapper, frame_allocator)
+    }).expect("loading hello.elf failed");
+    assert_eq!(program.entry, elf.entry());
+
+    let text = flags_of(&mapper, 0x1000_0040_0000);
+    assert!(text.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+    let rodata = flags_of(&mapper, 0x1000_0040_1000);
+    assert!(!rodata.contains(PageTableFlags::WRITABLE));
+    assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+    let data = flags_of(&mapper, 0x1000_0040_2000);
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    let stack = flags_of(&mapper, program.stack_top.as_u64() - 8);
+    assert!(stack.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn run_hello() {
+    serial_print!("load and run hello.elf ... ");
+    // hello.elf faults unless its .data and .bss were set up
+    let handle = elf::spawn(HELLO).expect("loading hello.elf failed");
+    assert_eq!(handle.join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn programs_are_isolated() {
+    serial_print!("programs are isolated ... ");
+    // both copies use the same addresses; with a shared address space the
+    // second one would find the counters already bumped and fault
+    let first = elf::spawn(HELLO).expect("loading hello.elf failed");
+    let second = elf::spawn(HELLO).expect("loading hello.elf failed");
+    assert_eq!(first.join(), ExitStatus::Exited);
+    assert_eq!(second.join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reject_kernel_segment() {
+    serial_print!("reject segment outside user space ... ");
+    let mut image = [0u8; 4096];
+    image.copy_from_slice(&HELLO[..4096]);
+    // move the first PT_LOAD (vaddr at offset 64 + 16) into kernel memory
+    image[80..88].copy_from_slice(&0x20_0000u64.to_le_bytes());
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::SegmentOutsideUserSpace)));
+    serial_println!("[ok]");
+}
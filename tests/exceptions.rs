@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(exceptions_test_main);
+
+fn exceptions_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::memory::AddressSpace;
+use rustOS::thread::{self, ExitStatus, JoinHandle};
+use rustOS::{serial_print, serial_println, userspace};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const USER_CODE: u64 = userspace::USER_SPACE_START;
+const USER_STACK: u64 = userspace::USER_SPACE_START + 0x10_0000;
+const USER_STACK_SIZE: u64 = 4 * 4096;
+
+/// `mov eax, 1; xor edi, edi; syscall` - exit(0)
+const EXIT: &[u8] = &[0xb8, 0x01, 0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05];
+/// `ud2`
+const INVALID_OPCODE: &[u8] = &[0x0f, 0x0b];
+/// `hlt`, which is privileged
+const GENERAL_PROTECTION: &[u8] = &[0xf4];
+/// `xor ecx, ecx; div ecx`
+const DIVIDE_BY_ZERO: &[u8] = &[0x31, 0xc9, 0xf7, 0xf1];
+
+fn run_in_ring3(program: &'static [u8]) -> JoinHandle {
+    let mut address_space = AddressSpace::new().expect("out of frames");
+    address_space.map_lazy(VirtAddr::new(USER_STACK), USER_STACK_SIZE,
+                           PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("stack region rejected");
+    thread::spawn_in(address_space, move || unsafe {
+        userspace::map_user_range(VirtAddr::new(USER_CODE), 4096, PageTableFlags::WRITABLE)
+            .expect("mapping user code failed");
+        core::ptr::copy_nonoverlapping(program.as_ptr(), USER_CODE as *mut u8, program.len());
+        userspace::enter_user_mode(VirtAddr::new(USER_CODE),
+                                   VirtAddr::new(USER_STACK + USER_STACK_SIZE))
+    })
+}
+
+#[test_case]
+fn breakpoint_continues() {
+    serial_print!("breakpoint continues ... ");
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_faults_kill_thread() {
+    serial_print!("user faults kill thread ... ");
+    for program in [INVALID_OPCODE, GENERAL_PROTECTION, DIVIDE_BY_ZERO] {
+        assert_eq!(run_in_ring3(program).join(), ExitStatus::Killed);
+    }
+    // the rest of the system keeps running
+    assert_eq!(run_in_ring3(EXIT).join(), ExitStatus::Exited);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustOS::memory::BitmapFrameAllocator;
+use spin::Mutex;
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(frame_allocator_test_main);
+
+fn frame_allocator_test_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    rustOS::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::{serial_print, serial_println};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB,
+};
+
+#[test_case]
+fn allocate_and_reuse() {
+    serial_print!("allocate and reuse ... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+
+    let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.stats().free_frames, before.free_frames - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.stats(), before);
+
+    let again: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(again, frame);
+    unsafe { allocator.deallocate_frame(again) };
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    serial_print!("contiguous allocation ... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+
+    let range = allocator.allocate_contiguous(16).unwrap();
+    assert_eq!(range.count(), 16);
+    assert_eq!(allocator.stats().used_frames, before.used_frames + 16);
+    unsafe { allocator.deallocate_contiguous(range) };
+    assert_eq!(allocator.stats(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    serial_print!("huge frame allocation ... ");
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(allocator.stats().used_frames, before.used_frames + 512);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.stats(), before);
+    serial_println!("[ok]");
+}
@@ -12,28 +12,20 @@ use core::panic::PanicInfo;
 entry_point!(heap_allocator_test_main);
 
 fn heap_allocator_test_main(boot_info: &'static BootInfo) -> ! {
-    use rustOS::allocator;
-    use rustOS::memory::{self, BootInfoFrameAllocator};
-    use x86_64::{VirtAddr, structures::paging::Page};
-
-    rustOS::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe{memory::init(phys_mem_offset)};
-    let mut frame_allocator = unsafe { 
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
-     };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    rustOS::test_boot(boot_info);
 
     test_main();
     loop {}
 }
 
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
 
-use rustOS::{serial_print, serial_println};
-use alloc::boxed::Box;
+use rustOS::{allocator, serial_print, serial_println};
+use alloc::{boxed::Box, vec};
 
 #[test_case]
 fn simple_allocation() {
@@ -65,4 +57,45 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+#[test_case]
+fn heap_grows_on_demand() {
+    serial_print!("heap grows on demand ... ");
+    let size = 4 * allocator::HEAP_INITIAL_SIZE;
+    let v = vec![1u8; size];
+    assert_eq!(v.iter().map(|&b| b as usize).sum::<usize>(), size);
+    assert!(allocator::heap_stats().size > allocator::HEAP_INITIAL_SIZE);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn heap_stats_track_usage() {
+    serial_print!("heap stats track usage ... ");
+    let before = allocator::heap_stats();
+    let v: Vec<u8> = Vec::with_capacity(4096);
+    let during = allocator::heap_stats();
+    assert!(during.used >= before.used + 4096);
+    assert!(during.high_water_mark >= during.used);
+    drop(v);
+    let after = allocator::heap_stats();
+    #[cfg(not(feature = "bump-allocator"))]
+    assert_eq!(after.used, before.used);
+    // a bump allocator only reclaims memory once everything has been freed
+    #[cfg(feature = "bump-allocator")]
+    assert!(after.used <= during.used);
+    serial_println!("[ok]");
+}
+
+// a bump allocator only reclaims memory once everything has been freed
+#[cfg(not(feature = "bump-allocator"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    serial_print!("many boxes long lived ... ");
+    let long_lived = Box::new(1);
+    for i in 0..10_000 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use rustOS::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(kernel_exception_test_main);
+
+fn kernel_exception_test_main(boot_info: &'static BootInfo) -> ! {
+    use rustOS::memory;
+    use x86_64::VirtAddr;
+
+    serial_print!("kernel_exception::invalid_opcode...\t");
+    rustOS::init();
+    // the crash report only follows frame pointers into mapped memory,
+    // which it can tell once `memory` knows the physical memory offset
+    let _ = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+
+    unsafe { core::arch::asm!("ud2") };
+
+    panic!("Execution continued after invalid opcode");
+}
+
+/// Keeps the start of the panic message.
+struct Message {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buf: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+
+    if message == "EXCEPTION: INVALID OPCODE" {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("{}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rustOS::hlt_loop();
+}
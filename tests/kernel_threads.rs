@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_threads_test_main);
+
+fn kernel_threads_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use rustOS::{serial_print, serial_println, thread, time};
+
+#[test_case]
+fn spawn_and_join() {
+    serial_print!("spawn and join ... ");
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let handles = [
+        thread::spawn(|| { COUNTER.fetch_add(1, Ordering::SeqCst); }),
+        thread::spawn(|| { COUNTER.fetch_add(1, Ordering::SeqCst); }),
+        thread::spawn(|| { COUNTER.fetch_add(1, Ordering::SeqCst); }),
+    ];
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn yield_interleaves() {
+    serial_print!("yield interleaves ... ");
+    static TURNS: AtomicUsize = AtomicUsize::new(0);
+    let handle = thread::spawn(|| {
+        for _ in 0..10 {
+            TURNS.fetch_add(1, Ordering::SeqCst);
+            thread::yield_now();
+        }
+    });
+    for _ in 0..10 {
+        thread::yield_now();
+    }
+    handle.join();
+    assert_eq!(TURNS.load(Ordering::SeqCst), 10);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    serial_print!("busy thread is preempted ... ");
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+    // the busy thread never yields, so we only get back here via the timer
+    thread::yield_now();
+    STOP.store(true, Ordering::SeqCst);
+    handle.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep_waits() {
+    serial_print!("sleep waits ... ");
+    let start = time::uptime();
+    thread::sleep(Duration::from_millis(50));
+    assert!(time::uptime() - start >= Duration::from_millis(50));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn try_spawn_fails_when_table_is_full() {
+    serial_print!("try_spawn fails when table is full ... ");
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+    let mut handles = Vec::new();
+    let rejected = loop {
+        match thread::try_spawn(|| {
+            while !RELEASE.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+        }) {
+            Ok(handle) => handles.push(handle),
+            Err(error) => break error,
+        }
+        assert!(handles.len() < thread::MAX_THREADS, "thread table never filled up");
+    };
+    assert_eq!(rejected, thread::TooManyThreads(()));
+
+    RELEASE.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join();
+    }
+    // the slots are free again
+    thread::spawn(|| {}).join();
+    serial_println!("[ok]");
+}
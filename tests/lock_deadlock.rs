@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use rustOS::sync::IrqSafeSpinlock;
+use rustOS::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static FIRST: IrqSafeSpinlock<()> = IrqSafeSpinlock::new(());
+static SECOND: IrqSafeSpinlock<()> = IrqSafeSpinlock::new(());
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rustOS::init();
+
+    serial_print!("lock_deadlock::print_in_exception_handler...\t");
+    // the breakpoint handler prints, which needs the lock held right here
+    let writer = rustOS::vga_buffer::WRITER.lock();
+    x86_64::instructions::interrupts::int3();
+    drop(writer);
+    serial_println!("[ok]");
+
+    // lock order is only checked in debug builds
+    if cfg!(not(debug_assertions)) {
+        exit_qemu(QemuExitCode::Success);
+        rustOS::hlt_loop();
+    }
+
+    serial_print!("lock_deadlock::lock_order_inversion...\t");
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    // one CPU cannot deadlock on its own, but two doing this could
+    let _second = SECOND.lock();
+    let _first = FIRST.lock();
+
+    panic!("Locks taken in reverse order were not reported");
+}
+
+/// Keeps the start of the panic message.
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buf: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+
+    if message.starts_with("deadlock: ") && message.contains("reverse") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("{}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rustOS::hlt_loop();
+}
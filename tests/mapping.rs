@@ -0,0 +1,164 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(mapping_test_main);
+
+fn mapping_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use rustOS::memory::{self, Backing, MapError};
+use rustOS::{serial_print, serial_println};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Unused kernel addresses, 1 GiB apart so that the tests do not share page
+/// tables.
+const SMALL: u64 = 0x_5555_0000_0000;
+const HUGE: u64 = 0x_5555_4000_0000;
+const DEVICE: u64 = 0x_5555_8000_0000;
+
+const DATA: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+fn used_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.stats().used_frames)
+}
+
+fn map(virt: u64, backing: Backing, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::map_range(mapper, frame_allocator, VirtAddr::new(virt), backing, len, flags)
+    }).unwrap()
+}
+
+fn unmap(virt: u64, len: u64) -> Result<(), MapError> {
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::unmap_range(mapper, frame_allocator, VirtAddr::new(virt), len)
+    }).unwrap()
+}
+
+fn translate(virt: u64) -> TranslateResult {
+    memory::with_kernel_mapper(|mapper| mapper.translate(VirtAddr::new(virt)))
+}
+
+#[test_case]
+fn map_and_unmap() {
+    serial_print!("map and unmap ... ");
+    // the first mapping in this area also allocates page tables, which
+    // stay around after unmapping
+    map(SMALL, Backing::Allocate, 4096, DATA).unwrap();
+    unmap(SMALL, 4096).unwrap();
+
+    let before = used_frames();
+    map(SMALL, Backing::AllocateZeroed, 3 * 4096, DATA).unwrap();
+    assert_eq!(used_frames() - before, 3);
+    let ptr = (SMALL + 2 * 4096) as *mut u64;
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    unmap(SMALL, 3 * 4096).unwrap();
+    assert_eq!(used_frames(), before);
+    assert!(matches!(translate(SMALL), TranslateResult::NotMapped));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn failed_map_rolls_back() {
+    serial_print!("failed map rolls back ... ");
+    map(SMALL + 2 * 4096, Backing::Allocate, 4096, DATA).unwrap();
+    let before = used_frames();
+    assert_eq!(map(SMALL, Backing::Allocate, 3 * 4096, DATA),
+               Err(MapError::AlreadyMapped(VirtAddr::new(SMALL + 2 * 4096))));
+    assert_eq!(used_frames(), before);
+    assert!(matches!(translate(SMALL), TranslateResult::NotMapped));
+    assert_eq!(unmap(SMALL, 3 * 4096), Err(MapError::NotMapped(VirtAddr::new(SMALL))));
+    unmap(SMALL + 2 * 4096, 4096).unwrap();
+
+    assert_eq!(map(SMALL + 1, Backing::Allocate, 4096, DATA), Err(MapError::Unaligned));
+    assert_eq!(map(SMALL, Backing::Allocate, 100, DATA), Err(MapError::Unaligned));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn huge_pages_when_aligned() {
+    serial_print!("huge pages when aligned ... ");
+    let huge = 2 * 1024 * 1024;
+    // set up the page tables for the trailing 4 KiB page beforehand
+    map(HUGE + huge, Backing::Allocate, 4096, DATA).unwrap();
+    unmap(HUGE + huge, 4096).unwrap();
+
+    let before = used_frames();
+    map(HUGE, Backing::AllocateZeroed, huge + 4096, DATA).unwrap();
+    assert!(matches!(translate(HUGE + 4096),
+                     TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. }));
+    assert!(matches!(translate(HUGE + huge),
+                     TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. }));
+    unsafe { ((HUGE + huge - 8) as *mut u64).write_volatile(7) };
+
+    assert_eq!(unmap(HUGE + 4096, 4096), Err(MapError::PartialHugePage(VirtAddr::new(HUGE + 4096))));
+    unmap(HUGE, huge + 4096).unwrap();
+    assert_eq!(used_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn physical_backing() {
+    serial_print!("physical backing ... ");
+    let vga = PhysAddr::new(0xb8000);
+    map(DEVICE, Backing::Allocate, 4096, DATA).unwrap();
+    unmap(DEVICE, 4096).unwrap();
+
+    let before = used_frames();
+    map(DEVICE, Backing::Physical(vga), 4096, DATA).unwrap();
+    match translate(DEVICE + 8) {
+        TranslateResult::Mapped { frame, offset, .. } => {
+            assert_eq!(frame.start_address() + offset, vga + 8u64);
+        }
+        other => panic!("not mapped: {:?}", other),
+    }
+    unmap(DEVICE, 4096).unwrap();
+    // the VGA frame is not the frame allocator's, so nothing was freed
+    assert_eq!(used_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn protect_and_shootdown() {
+    serial_print!("protect and shootdown ... ");
+    static SHOT_DOWN: AtomicU64 = AtomicU64::new(0);
+    fn hook(start: VirtAddr, len: u64) {
+        assert_eq!(start.as_u64(), SMALL);
+        SHOT_DOWN.fetch_add(len, Ordering::SeqCst);
+    }
+    memory::mapping::set_tlb_shootdown_hook(hook);
+
+    map(SMALL, Backing::Allocate, 2 * 4096, DATA).unwrap();
+    memory::try_with_kernel_memory(|mapper, _| {
+        memory::protect(mapper, VirtAddr::new(SMALL), 2 * 4096, PageTableFlags::NO_EXECUTE)
+    }).unwrap().unwrap();
+    match translate(SMALL + 4096) {
+        TranslateResult::Mapped { flags, .. } => assert!(!flags.contains(PageTableFlags::WRITABLE)),
+        other => panic!("not mapped: {:?}", other),
+    }
+    assert_eq!(SHOT_DOWN.load(Ordering::SeqCst), 2 * 4096);
+    unmap(SMALL, 2 * 4096).unwrap();
+    assert_eq!(SHOT_DOWN.load(Ordering::SeqCst), 4 * 4096);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(page_walk_test_main);
+
+fn page_walk_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::memory::{self, Backing, MappedRange};
+use rustOS::{serial_print, serial_println};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::registers::control::Cr3;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Unused kernel addresses, 1 GiB apart.
+const HUGE: u64 = 0x_6666_0000_0000;
+const DEVICE: u64 = 0x_6666_4000_0000;
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+const DATA: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+fn map(virt: u64, backing: Backing, len: u64) {
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::map_range(mapper, frame_allocator, VirtAddr::new(virt), backing, len, DATA)
+    }).unwrap().unwrap();
+}
+
+fn unmap(virt: u64, len: u64) {
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::unmap_range(mapper, frame_allocator, VirtAddr::new(virt), len)
+    }).unwrap().unwrap();
+}
+
+fn mapped_ranges_in(start: u64, end: u64) -> ([Option<MappedRange>; 4], usize) {
+    let offset = memory::with_kernel_mapper(|mapper| mapper.phys_offset());
+    let (p4_frame, _): (PhysFrame, _) = Cr3::read();
+    let mut ranges = [None; 4];
+    let mut count = 0;
+    unsafe {
+        memory::walk::for_each_mapped_range(p4_frame, offset, |range| {
+            let range_start = range.start.as_u64();
+            if range_start >= start && range_start < end {
+                ranges[count] = Some(range);
+                count += 1;
+            }
+        });
+    }
+    (ranges, count)
+}
+
+#[test_case]
+fn translate_huge_page() {
+    serial_print!("translate huge page ... ");
+    map(HUGE, Backing::AllocateZeroed, HUGE_PAGE_SIZE);
+    let addr = VirtAddr::new(HUGE + 0x12_3456);
+
+    let walk = memory::walk(addr);
+    assert_eq!(walk.page_size(), Some(HUGE_PAGE_SIZE));
+    let last = walk.steps().last().unwrap();
+    assert_eq!(last.level, 2);
+    assert!(last.flags.contains(PageTableFlags::HUGE_PAGE));
+    assert_eq!(walk.phys(), Some(last.addr + 0x12_3456u64));
+
+    let offset = memory::with_kernel_mapper(|mapper| mapper.phys_offset());
+    assert_eq!(unsafe { memory::translate_addr(addr, offset) }, walk.phys());
+    unmap(HUGE, HUGE_PAGE_SIZE);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn walk_stops_at_missing_entry() {
+    serial_print!("walk stops at missing entry ... ");
+    let walk = memory::walk(VirtAddr::new(0x_7777_0000_0000));
+    assert_eq!(walk.phys(), None);
+    assert_eq!(walk.page_size(), None);
+    let last = walk.steps().last().unwrap();
+    assert!(!last.flags.contains(PageTableFlags::PRESENT));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn walk_of_kernel_code() {
+    serial_print!("walk of kernel code ... ");
+    let addr = VirtAddr::new(rustOS::hlt_loop as *const () as u64);
+    let walk = memory::walk(addr);
+    // the bootloader maps the kernel with 4 KiB pages
+    assert_eq!(walk.steps().count(), 4);
+    let offset = memory::with_kernel_mapper(|mapper| mapper.phys_offset());
+    assert_eq!(unsafe { memory::translate_addr(addr, offset) }, walk.phys());
+    assert!(walk.phys().is_some());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn ranges_are_merged() {
+    serial_print!("ranges are merged ... ");
+    let vga = PhysAddr::new(0xb8000);
+    map(DEVICE, Backing::Physical(vga), 3 * 4096);
+
+    let (ranges, count) = mapped_ranges_in(DEVICE, DEVICE + HUGE_PAGE_SIZE);
+    assert_eq!(count, 1);
+    let range = ranges[0].unwrap();
+    assert_eq!(range.start, VirtAddr::new(DEVICE));
+    assert_eq!(range.phys, vga);
+    assert_eq!(range.len, 3 * 4096);
+    assert_eq!(range.flags & DATA, DATA);
+
+    // a read-only page in the middle splits the range
+    memory::with_kernel_mapper(|mapper| {
+        memory::protect(mapper, VirtAddr::new(DEVICE + 4096), 4096, PageTableFlags::NO_EXECUTE)
+    }).unwrap();
+    let (ranges, count) = mapped_ranges_in(DEVICE, DEVICE + HUGE_PAGE_SIZE);
+    assert_eq!(count, 3);
+    assert!(!ranges[1].unwrap().flags.contains(PageTableFlags::WRITABLE));
+    assert!(ranges[2].unwrap().flags.contains(PageTableFlags::WRITABLE));
+
+    unmap(DEVICE, 3 * 4096);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(serial_input_test_main);
+
+fn serial_input_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use core::time::Duration;
+use futures_util::future::{self, Either};
+use futures_util::stream::StreamExt;
+use rustOS::serial::{ByteStream, SERIAL1};
+use rustOS::task::block_on;
+use rustOS::{serial_print, serial_println, time};
+use x86_64::instructions::port::Port;
+
+const MODEM_CONTROL: u16 = 0x3F8 + 4;
+/// DTR, RTS and OUT2, as `SerialPort::init` leaves them.
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x10;
+
+/// Sends `bytes` to ourselves by putting the UART in loopback mode for a
+/// moment; they come back through the receive interrupt.
+fn receive_looped_back(bytes: &[u8]) {
+    let mut port = SERIAL1.lock();
+    let mut modem_control = Port::<u8>::new(MODEM_CONTROL);
+    unsafe { modem_control.write(MODEM_CONTROL_NORMAL | MODEM_CONTROL_LOOPBACK) };
+    for &byte in bytes {
+        port.send_raw(byte);
+    }
+    unsafe { modem_control.write(MODEM_CONTROL_NORMAL) };
+}
+
+#[test_case]
+fn bytes_arrive_through_interrupt() {
+    serial_print!("bytes arrive through interrupt ... ");
+    let mut bytes = ByteStream::new();
+    let sent = b"uptime\x1b[D\r";
+    receive_looped_back(sent);
+
+    let received = block_on(async {
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            let timeout = time::sleep(Duration::from_secs(1));
+            match future::select(bytes.next(), timeout).await {
+                Either::Left((Some(byte), _)) => received.push(byte),
+                Either::Left((None, _)) => panic!("serial input ended"),
+                Either::Right(_) => panic!("timed out after {:?}", received),
+            }
+        }
+        received
+    });
+    assert_eq!(received, sent);
+    serial_println!("[ok]");
+}
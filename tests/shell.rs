@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(shell_test_main);
+
+fn shell_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use rustOS::shell::{self, Console, Edit, Key, KeyDecoder, LineEditor};
+use rustOS::{serial_print, serial_println};
+
+/// Collects the output; `show_line` leaves the last prompt line in `line`.
+#[derive(Default)]
+struct BufferConsole {
+    output: String,
+    line: String,
+    cleared: bool,
+}
+
+impl fmt::Write for BufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.output.push_str(s);
+        Ok(())
+    }
+}
+
+impl Console for BufferConsole {
+    fn show_line(&mut self, prompt: &str, line: &str, cursor: usize) {
+        self.line = alloc::format!("{}{}|{}", prompt, &line[..cursor], &line[cursor..]);
+    }
+
+    fn clear(&mut self) {
+        self.cleared = true;
+    }
+
+    fn width(&self) -> usize {
+        80
+    }
+}
+
+fn run(line: &str) -> BufferConsole {
+    let mut console = BufferConsole::default();
+    shell::execute(line, &mut console);
+    console
+}
+
+fn type_str(editor: &mut LineEditor, s: &str) {
+    for c in s.chars() {
+        editor.handle_key(Key::Char(c));
+    }
+}
+
+#[test_case]
+fn line_editing() {
+    serial_print!("line editing ... ");
+    let mut editor = LineEditor::new(10);
+    type_str(&mut editor, "helo");
+    editor.handle_key(Key::Left);
+    type_str(&mut editor, "l");
+    assert_eq!((editor.line(), editor.cursor()), ("hello", 4));
+
+    editor.handle_key(Key::Home);
+    assert_eq!(editor.handle_key(Key::Backspace), Edit::Unchanged);
+    editor.handle_key(Key::Delete);
+    editor.handle_key(Key::End);
+    type_str(&mut editor, " world!");
+    assert_eq!(editor.line(), "ello world");
+    assert_eq!(editor.handle_key(Key::Char('\u{7}')), Edit::Unchanged);
+    assert_eq!(editor.handle_key(Key::Enter), Edit::Submitted("ello world".into()));
+    assert_eq!((editor.line(), editor.cursor()), ("", 0));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn line_history() {
+    serial_print!("line history ... ");
+    let mut editor = LineEditor::new(80);
+    for line in ["first", "second", "second", "  "] {
+        type_str(&mut editor, line);
+        editor.handle_key(Key::Enter);
+    }
+    assert!(editor.history().eq(["second", "first"]));
+
+    type_str(&mut editor, "draft");
+    editor.handle_key(Key::Up);
+    assert_eq!(editor.line(), "second");
+    editor.handle_key(Key::Up);
+    assert_eq!(editor.line(), "first");
+    assert_eq!(editor.handle_key(Key::Up), Edit::Unchanged);
+    editor.handle_key(Key::Down);
+    editor.handle_key(Key::Down);
+    assert_eq!((editor.line(), editor.cursor()), ("draft", 5));
+    assert_eq!(editor.handle_key(Key::Down), Edit::Unchanged);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn help_lists_commands() {
+    serial_print!("help lists commands ... ");
+    let output = run("help").output;
+    for name in ["help", "clear", "mem", "uptime", "tasks", "translate", "reboot", "panic"] {
+        assert!(output.lines().any(|line| line.starts_with(name)), "{} missing", name);
+    }
+    assert!(run("frobnicate now").output.starts_with("unknown command `frobnicate`"));
+    assert!(run("   ").output.is_empty());
+    assert!(run("clear").cleared);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn status_commands() {
+    serial_print!("status commands ... ");
+    let mem = run("mem").output;
+    assert!(mem.starts_with("heap: ") && mem.contains("\nframes: "));
+    assert!(run("uptime").output.starts_with("up 0:00:"));
+
+    let tasks = run("tasks").output;
+    let running: Vec<&str> = tasks.lines().filter(|line| line.contains("Running")).collect();
+    assert_eq!(running.len(), 1);
+    assert!(tasks.contains("0 async tasks:"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn translate_command() {
+    serial_print!("translate command ... ");
+    static MAPPED: u8 = 0;
+    let addr = &MAPPED as *const u8 as u64;
+    let output = run(&alloc::format!("translate {:#x}", addr)).output;
+    assert!(output.contains("KiB page)"), "{}", output);
+
+    assert!(run("translate 0x_1000_0000_0000").output.contains("not mapped"));
+    assert!(run("translate 0x8000_0000_0000").output.contains("not a canonical"));
+    assert!(run("translate").output.starts_with("usage: "));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shell_runs_typed_lines() {
+    serial_print!("shell runs typed lines ... ");
+    let mut keys: Vec<Key> = "uptime".chars().map(Key::Char).collect();
+    keys.extend([Key::Enter, Key::Up, Key::Left, Key::Backspace]);
+    let mut console = BufferConsole::default();
+    rustOS::task::block_on(shell::run(futures_util::stream::iter(keys), &mut console));
+
+    assert!(console.output.contains("\nup 0:00:"));
+    assert_eq!(console.line, "> upti|e");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn terminal_key_decoding() {
+    serial_print!("terminal key decoding ... ");
+    let mut decoder = KeyDecoder::new();
+    let keys: Vec<Key> = b"a \x7f\x1b[D\x1bOC\x1b[3~\x1b[1~\x1b[F\x1b[1;5A\x1b[5~\x1bx\r\n\n"
+        .iter()
+        .filter_map(|&byte| decoder.feed(byte))
+        .collect();
+    assert_eq!(keys, [
+        Key::Char('a'), Key::Char(' '), Key::Backspace, Key::Left, Key::Right, Key::Delete,
+        Key::Home, Key::End, Key::Up, Key::Enter, Key::Enter,
+    ]);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(smp_test_main);
+
+fn smp_test_main(boot_info: &'static BootInfo) -> ! {
+    use rustOS::{interrupt, smp};
+
+    rustOS::test_boot(boot_info);
+    assert!(interrupt::init_apic());
+    // the test runs with `-smp 4`
+    assert_eq!(smp::init(), 4);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use rustOS::memory::{self, Backing};
+use rustOS::sync::IrqSafeSpinlock;
+use rustOS::{cpu, serial_print, serial_println, thread, time};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+#[test_case]
+fn cpus_have_their_own_data() {
+    serial_print!("cpus have their own data ... ");
+    assert_eq!(cpu::count(), 4);
+    assert!(x86_64::instructions::interrupts::without_interrupts(cpu::is_boot_cpu));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn threads_run_on_every_cpu() {
+    serial_print!("threads run on every cpu ... ");
+    static SEEN : AtomicU64 = AtomicU64::new(0);
+    let handles = (0..8).map(|_| thread::spawn(|| {
+        let deadline = time::ticks() + 20;
+        while time::ticks() < deadline {
+            let id = x86_64::instructions::interrupts::without_interrupts(cpu::id);
+            SEEN.fetch_or(1 << id, Ordering::SeqCst);
+        }
+    })).collect::<Vec<_>>();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(SEEN.load(Ordering::SeqCst), 0b1111);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn threads_finish_across_cpus() {
+    serial_print!("threads finish across cpus ... ");
+    static COUNTER : AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..10 {
+        let handles = (0..16).map(|_| thread::spawn(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            thread::yield_now();
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        })).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join();
+        }
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 10 * 16 * 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn time_keeps_its_rate() {
+    serial_print!("time keeps its rate ... ");
+    let start = time::uptime();
+    thread::sleep(Duration::from_millis(100));
+    let slept = time::uptime() - start;
+    assert!(slept < Duration::from_millis(300), "slept {:?}", slept);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shootdown_waits_for_cpus_spinning_on_a_lock() {
+    serial_print!("shootdown waits for cpus spinning on a lock ... ");
+    static LOCK : IrqSafeSpinlock<()> = IrqSafeSpinlock::new(());
+    static STARTED : AtomicUsize = AtomicUsize::new(0);
+    let page = VirtAddr::new(0x_5555_0000_0000);
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::map_range(mapper, frame_allocator, page, Backing::AllocateZeroed, 4096,
+                          PageTableFlags::WRITABLE)
+    }).unwrap().unwrap();
+
+    // the other CPUs wait for the lock with interrupts disabled, so the
+    // shootdown IPI cannot reach them
+    let guard = LOCK.lock();
+    let handles = (0..3).map(|_| thread::spawn(|| {
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        drop(LOCK.lock());
+    })).collect::<Vec<_>>();
+    while STARTED.load(Ordering::SeqCst) < 3 {
+        core::hint::spin_loop();
+    }
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::unmap_range(mapper, frame_allocator, page, 4096)
+    }).unwrap().unwrap();
+    drop(guard);
+
+    for handle in handles {
+        handle.join();
+    }
+    assert!(!memory::is_mapped(page));
+    serial_println!("[ok]");
+}
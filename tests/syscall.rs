@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(syscall_test_main);
+
+fn syscall_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use rustOS::syscall::SyscallError;
+use rustOS::{serial_print, serial_println, thread, userspace};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const USER_CODE: u64 = userspace::USER_SPACE_START;
+const USER_STACK: u64 = userspace::USER_SPACE_START + 0x10_0000;
+
+/// Stores the results of a few syscalls right after the code, then exits:
+///
+/// ```text
+///     mov eax, 0xffff             ; no such syscall
+///     syscall
+///     mov [rip + results], rax
+///     xor eax, eax                ; write(2, 0x1000, 4), not a user pointer
+///     mov edi, 2
+///     mov esi, 0x1000
+///     mov edx, 4
+///     syscall
+///     mov [rip + results + 8], rax
+///     xor eax, eax                ; write(2, msg, 18)
+///     mov edi, 2
+///     lea rsi, [rip + msg]
+///     mov edx, msg_end - msg
+///     syscall
+///     mov [rip + results + 16], rax
+///     mov eax, 4                  ; getpid()
+///     syscall
+///     mov [rip + results + 24], rax
+///     mov eax, 1                  ; exit(0)
+///     xor edi, edi
+///     syscall
+///     ud2
+/// msg:
+///     .ascii "hello from ring 3\n"
+/// ```
+const PROGRAM: &[u8] = &[
+    0xb8, 0xff, 0xff, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x89, 0x05, 0x62, 0x00,
+    0x00, 0x00, 0x31, 0xc0, 0xbf, 0x02, 0x00, 0x00, 0x00, 0xbe, 0x00, 0x10,
+    0x00, 0x00, 0xba, 0x04, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x89, 0x05,
+    0x50, 0x00, 0x00, 0x00, 0x31, 0xc0, 0xbf, 0x02, 0x00, 0x00, 0x00, 0x48,
+    0x8d, 0x35, 0x27, 0x00, 0x00, 0x00, 0xba, 0x12, 0x00, 0x00, 0x00, 0x0f,
+    0x05, 0x48, 0x89, 0x05, 0x3c, 0x00, 0x00, 0x00, 0xb8, 0x04, 0x00, 0x00,
+    0x00, 0x0f, 0x05, 0x48, 0x89, 0x05, 0x36, 0x00, 0x00, 0x00, 0xb8, 0x01,
+    0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05, 0x0f, 0x0b, 0x68, 0x65, 0x6c,
+    0x6c, 0x6f, 0x20, 0x66, 0x72, 0x6f, 0x6d, 0x20, 0x72, 0x69, 0x6e, 0x67,
+    0x20, 0x33, 0x0a, 0x90,
+];
+const RESULTS_OFFSET: u64 = 0x70;
+
+#[test_case]
+fn syscalls_from_ring3() {
+    serial_print!("syscalls from ring 3 ... ");
+
+    userspace::map_user_range(VirtAddr::new(USER_CODE), 4096, PageTableFlags::WRITABLE)
+        .expect("mapping user code failed");
+    userspace::map_user_range(VirtAddr::new(USER_STACK), 4096, PageTableFlags::WRITABLE)
+        .expect("mapping user stack failed");
+    unsafe {
+        core::ptr::copy_nonoverlapping(PROGRAM.as_ptr(), USER_CODE as *mut u8, PROGRAM.len());
+    }
+
+    let handle = thread::spawn(|| unsafe {
+        userspace::enter_user_mode(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK + 4096))
+    });
+    let tid = handle.id().as_u64();
+    handle.join();
+
+    let results = unsafe { &*((USER_CODE + RESULTS_OFFSET) as *const [i64; 4]) };
+    assert_eq!(results[0], -(SyscallError::NoSuchSyscall as i64));
+    assert_eq!(results[1], -(SyscallError::BadAddress as i64));
+    assert_eq!(results[2], 18);
+    assert_eq!(results[3], tid as i64);
+    serial_println!("[ok]");
+}
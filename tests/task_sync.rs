@@ -0,0 +1,212 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(task_sync_test_main);
+
+fn task_sync_test_main(boot_info: &'static BootInfo) -> ! {
+    rustOS::test_boot(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::future::join;
+use rustOS::task::block_on;
+use rustOS::task::sync::{mpsc, Mutex, Notify, Semaphore, WaitQueue};
+use rustOS::{serial_print, serial_println, thread, time};
+use x86_64::instructions::interrupts;
+
+/// Returns `Pending` once, so that joined futures take turns.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+#[test_case]
+fn mutex_held_across_await() {
+    serial_print!("mutex held across await ... ");
+    let mutex = Mutex::new(Vec::new());
+    let worker = |id: usize| {
+        let mutex = &mutex;
+        async move {
+            for _ in 0..3 {
+                let mut log = mutex.lock().await;
+                log.push(id);
+                yield_now().await;
+                log.push(id);
+            }
+        }
+    };
+    block_on(join(worker(1), worker(2)));
+
+    let log = mutex.into_inner();
+    assert_eq!(log.len(), 12);
+    // nobody got in between the two pushes of a holder
+    for pair in log.chunks(2) {
+        assert_eq!(pair[0], pair[1]);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn channel_applies_backpressure() {
+    serial_print!("channel applies backpressure ... ");
+    let (sender, mut receiver) = mpsc::channel(2);
+    let producer = async move {
+        for i in 0..10 {
+            sender.send(i).await.expect("receiver gone");
+        }
+    };
+    let consumer = async {
+        let mut received = Vec::new();
+        while let Some(value) = receiver.recv().await {
+            assert!(receiver.len() <= 2);
+            received.push(value);
+        }
+        received
+    };
+    let ((), received) = block_on(join(producer, consumer));
+    assert_eq!(received, (0..10).collect::<Vec<_>>());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn channel_try_send_and_close() {
+    serial_print!("channel try_send and close ... ");
+    let (sender, mut receiver) = mpsc::channel(1);
+    assert!(sender.try_send(1).is_ok());
+    assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Empty));
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(block_on(sender.send(3)), Err(mpsc::SendError(3)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_limits_holders() {
+    serial_print!("semaphore limits holders ... ");
+    let semaphore = Semaphore::new(2);
+    let holders = AtomicUsize::new(0);
+    let worker = || async {
+        let _permit = semaphore.acquire().await;
+        let now = holders.fetch_add(1, Ordering::SeqCst) + 1;
+        assert!(now <= 2);
+        yield_now().await;
+        holders.fetch_sub(1, Ordering::SeqCst);
+    };
+    block_on(join(join(worker(), worker()), join(worker(), worker())));
+    assert_eq!(semaphore.available_permits(), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn notify_stores_permit() {
+    serial_print!("notify stores permit ... ");
+    let notify = Notify::new();
+    notify.notify_one();
+    block_on(notify.notified());
+
+    // without a waiter, notify_waiters is lost
+    notify.notify_waiters();
+    let sent = AtomicBool::new(false);
+    let waiter = async {
+        notify.notified().await;
+        assert!(sent.load(Ordering::SeqCst));
+    };
+    let notifier = async {
+        time::sleep(Duration::from_millis(30)).await;
+        sent.store(true, Ordering::SeqCst);
+        notify.notify_one();
+    };
+    block_on(join(waiter, notifier));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wait_queue_signaled_with_interrupts_disabled() {
+    serial_print!("wait queue signaled with interrupts disabled ... ");
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+
+    // the signalling thread does what an interrupt handler would
+    let signaller = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(20));
+        interrupts::without_interrupts(|| {
+            READY.store(true, Ordering::SeqCst);
+            QUEUE.wake_all();
+        });
+    });
+    block_on(QUEUE.wait_until(|| READY.load(Ordering::SeqCst).then_some(())));
+    assert!(QUEUE.is_empty());
+    signaller.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn dropped_waiter_passes_wakeup_on() {
+    serial_print!("dropped waiter passes wakeup on ... ");
+    let queue = WaitQueue::new();
+    let woken = AtomicUsize::new(0);
+    let first = async {
+        queue.wait().await;
+        woken.fetch_add(1, Ordering::SeqCst);
+    };
+    let second = async {
+        queue.wait().await;
+        woken.fetch_add(1, Ordering::SeqCst);
+    };
+    let signal = async {
+        yield_now().await;
+        queue.wake_all();
+    };
+    block_on(join(join(first, second), signal));
+    assert_eq!(woken.load(Ordering::SeqCst), 2);
+
+    // a woken future dropped before it ran hands the wakeup to the next
+    let mut cancelled = Box::pin(queue.wait());
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    assert!(cancelled.as_mut().poll(&mut context).is_pending());
+    let mut waiting = core::pin::pin!(queue.wait());
+    assert!(waiting.as_mut().poll(&mut context).is_pending());
+    queue.wake_one();
+    drop(cancelled);
+    assert!(queue.is_empty());
+    assert!(waiting.as_mut().poll(&mut context).is_ready());
+    serial_println!("[ok]");
+}
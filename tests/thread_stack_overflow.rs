@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use rustOS::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(thread_stack_overflow_test_main);
+
+fn thread_stack_overflow_test_main(boot_info: &'static BootInfo) -> ! {
+    use rustOS::thread;
+
+    serial_print!("thread_stack_overflow::thread_stack_overflow...\t");
+    rustOS::test_boot(boot_info);
+
+    thread::spawn(|| stack_overflow(0)).join();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow(depth: u64) {
+    stack_overflow(depth + 1);
+    volatile::Volatile::new(depth).read(); // prevent tail recursion optimizations
+}
+
+/// Keeps the start of the panic message.
+struct Message {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buf: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+
+    if message.contains("stack overflow in thread stack") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("{}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rustOS::hlt_loop();
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PrivilegeLevel, VirtAddr};
+use rustOS::{exit_qemu, memory, userspace, QemuExitCode, serial_print, serial_println};
+
+const USER_CODE : u64 = userspace::USER_SPACE_START;
+const USER_STACK : u64 = userspace::USER_SPACE_START + 0x10_0000;
+
+extern "x86-interrupt" fn test_general_protection_handler(
+    stack_frame: InterruptStackFrame, _error_code: u64) {
+    // `hlt` is privileged, so it only faults if we really are in ring 3
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+        && stack_frame.instruction_pointer.as_u64() == USER_CODE
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{:#?}", stack_frame);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rustOS::hlt_loop();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.general_protection_fault.set_handler_fn(test_general_protection_handler);
+        idt
+    };
+}
+
+entry_point!(user_mode_test_main);
+
+fn user_mode_test_main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode::enter_ring3...\t");
+
+    rustOS::init();
+    // the test IDT has no IRQ handlers
+    x86_64::instructions::interrupts::disable();
+    unsafe { rustOS::interrupt::PICS.lock().write_masks(0xff, 0xff) };
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install_kernel_mapper(mapper);
+    memory::install_frame_allocator(frame_allocator);
+
+    userspace::map_user_range(VirtAddr::new(USER_CODE), 4096, PageTableFlags::WRITABLE)
+        .expect("mapping user code failed");
+    userspace::map_user_range(VirtAddr::new(USER_STACK), 4096, PageTableFlags::WRITABLE)
+        .expect("mapping user stack failed");
+    unsafe { (USER_CODE as *mut u8).write(0xf4) }; // hlt
+
+    unsafe {
+        userspace::enter_user_mode(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK + 4096))
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustOS::test_panic_handler(info)
+}
@@ -0,0 +1,56 @@
+#!/bin/sh
+# Writes the function symbols of a kernel ELF into its `.kernel_symbols`
+# section, where `backtrace::symbolize` looks them up. The section keeps its
+# size and place, so nothing else in the image moves.
+#
+# usage: tools/embed-symbols.sh <kernel>
+#
+# Uses nm and readelf from binutils; set NM and READELF to use others, e.g.
+# llvm-nm and llvm-readelf from rustup's llvm-tools. Everything else is
+# POSIX.
+set -e
+
+kernel="$1"
+nm="${NM:-nm}"
+readelf="${READELF:-readelf}"
+
+for tool in "$nm" "$readelf" awk sed dd head; do
+    if ! command -v "$tool" > /dev/null 2>&1; then
+        echo "embed-symbols: $tool not found; install binutils or set NM and READELF," >&2
+        echo "e.g. NM=llvm-nm READELF=llvm-readelf after \`rustup component add llvm-tools\`" >&2
+        exit 1
+    fi
+done
+
+# the section headers start with "[Nr]" or "[ Nr]", so the fields are
+# counted from the name: type, address, offset, size
+set -- $("$readelf" -S -W "$kernel" \
+    | awk '{ for (i = 1; i < NF; i++) if ($i == ".kernel_symbols") print $(i + 4), $(i + 3) }')
+if [ $# -ne 2 ]; then
+    echo "embed-symbols: $kernel has no .kernel_symbols section" >&2
+    exit 1
+fi
+size=$(printf '%d' "0x$1")
+offset=$(printf '%d' "0x$2")
+
+table=$(mktemp)
+trap 'rm -f "$table" "$table.cut"' EXIT
+
+# functions only, without the hash suffix of legacy Rust mangling
+"$nm" --numeric-sort --defined-only --demangle "$kernel" \
+    | sed -n 's/^\([0-9a-f]\{16\}\) [tTwW] \(.*\)$/\1 \2/p' \
+    | sed 's/::h[0-9a-f]\{16\}$//' > "$table"
+
+# at least one zero byte has to stay to end the table
+if [ "$(wc -c < "$table")" -ge "$size" ]; then
+    echo "embed-symbols: symbol table does not fit, dropping the highest addresses" >&2
+    head -c "$((size - 1))" "$table" | sed '$d' > "$table.cut"
+    mv "$table.cut" "$table"
+fi
+
+# zeros up to the size of the section, so nothing of an older table is left
+head -c "$((size - $(wc -c < "$table")))" /dev/zero >> "$table"
+
+# seek counts whole blocks, so with a block as big as the offset, one block
+# lands right on the section
+dd if="$table" of="$kernel" bs="$offset" seek=1 conv=notrunc 2> /dev/null
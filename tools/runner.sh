@@ -0,0 +1,7 @@
+#!/bin/sh
+# Cargo runner: embeds the symbol table into the kernel, then boots it with
+# `bootimage runner` as before.
+set -e
+
+"$(dirname "$0")/embed-symbols.sh" "$1"
+exec bootimage runner "$@"
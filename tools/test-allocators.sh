@@ -0,0 +1,10 @@
+#!/bin/sh
+# Runs the heap tests against every heap design, not just the default one.
+#
+# usage: tools/test-allocators.sh [more cargo test arguments]
+set -e
+
+for design in bump linked-list fixed-size-block; do
+    echo "heap tests with the $design allocator"
+    cargo test --test heap_allocation --no-default-features --features "$design-allocator" "$@"
+done
@@ -0,0 +1,47 @@
+# Test program for the ELF loader. Rebuild with:
+#   as --64 -o /tmp/hello.o user/hello.s
+#   ld -static -nostdlib -z max-page-size=4096 -Ttext=0x100000400000 -o user/hello.elf /tmp/hello.o
+#
+# Writes a greeting to stderr, bumps a counter in .data and one in .bss and
+# exits if their sum is 42, i.e. if both were set up correctly. Otherwise it
+# faults with `ud2`.
+
+    .set SYS_WRITE, 0
+    .set SYS_EXIT, 1
+    .set STDERR, 2
+
+    .text
+    .globl _start
+_start:
+    mov $SYS_WRITE, %eax
+    mov $STDERR, %edi
+    lea msg(%rip), %rsi
+    mov $msg_end - msg, %edx
+    syscall
+
+    incq counter(%rip)
+    addq $1, zeroed(%rip)
+    mov counter(%rip), %rdi
+    add zeroed(%rip), %rdi
+    # the user stack must be writable too
+    push %rdi
+    pop %rdi
+    cmp $42, %rdi
+    jne 1f
+    mov $SYS_EXIT, %eax
+    syscall
+1:
+    ud2
+
+    .section .rodata
+msg:
+    .ascii "hello from an ELF\n"
+msg_end:
+
+    .data
+counter:
+    .quad 40
+
+    .bss
+zeroed:
+    .quad 0